@@ -0,0 +1,112 @@
+// Secondary Client (SC): si registra presso il PC, ne riceve la chiave del
+// flusso SC -> SS e la usa come PSK per parlare direttamente col SS
+
+use std::net::{SocketAddr, TcpStream};
+use std::io;
+
+use hpke_proto::config;
+use hpke_proto::ciphersuite::{AEADtype, HpkeMode, KDFtype, KEMtype};
+use hpke_proto::data_packets_manager::{DataType, PacketDecoder, read_packet, unexpected_packet};
+use hpke_proto::error::{HpkeProtoError, exit_on_error};
+use hpke_proto::handshake;
+use hpke_proto::kri::{EpochSender, RekeyPolicy};
+use hpke_proto::psk::PskStore;
+use hpke_proto::secondary::{self, SealedMessage};
+use hpke_proto::trust::ServerKeyPolicy;
+
+const INFO_STR: &[u8] = b"PDMv2 secondary session";
+
+
+// Handshake col SS, apertura della sessione e invio dei messaggi letti da stdin
+fn exchange_with_ss(
+    mut stream: TcpStream,
+    remote: SocketAddr,
+    psks: &PskStore,
+    rekey: RekeyPolicy,
+    associated_data: &[u8]
+) -> Result<(), HpkeProtoError> {
+    let mut decoder = PacketDecoder::new();
+
+    // Handshake col SS in modo Psk, con la chiave del flusso come PSK
+    let mut server_pk = vec![];
+    let negotiated = handshake::handle_server(
+        remote,
+        &mut stream,
+        &mut decoder,
+        &mut server_pk,
+        &KEMtype::to_vect(),
+        &KDFtype::to_vect(),
+        &AEADtype::to_vect(),
+        &[HpkeMode::Psk],
+        psks,
+        &[],
+        // Il SS è autenticato dalla chiave del flusso usata come PSK
+        &ServerKeyPolicy::AcceptAny,
+        None
+    )?;
+    println!("Ciphersuite negoziata col SS: {}", negotiated.suite);
+
+    let session = secondary::open_sender_session(&stream, &negotiated, psks, &server_pk, INFO_STR)?;
+    let mut sender = EpochSender::new(session, negotiated.suite.aead, rekey)?;
+
+    loop {
+        println!("\nInserisci testo");
+        let mut input = String::new();
+        // Fine dell'input (Ctrl-D): la sessione termina senza errori
+        if io::stdin().read_line(&mut input)? == 0 {
+            return Ok(());
+        }
+
+        let (header, ciphertext, tag) = sender.seal(input.as_bytes(), associated_data)?;
+        secondary::send_sealed(&stream, SealedMessage {
+            kri: Some(header),
+            ciphertext,
+            associated_data: associated_data.to_vec(),
+            tag
+        }, false)?;
+
+        let pack = match read_packet(&mut stream, &mut decoder)? {
+            Some(pack) => pack,
+            None => return Ok(()),
+        };
+        if pack.data_type != DataType::Plaintext {
+            return Err(unexpected_packet(pack.data_type));
+        }
+        println!("Il SS ha inviato: {}", String::from_utf8_lossy(&pack.payload));
+    }
+}
+
+
+fn main() {
+
+    let associated_data = b"associated data";
+
+    // Topologia e PSK di registrazione dalla sezione [registration] di client.toml,
+    // rotazione della chiave da [rekey]
+    let args: Vec<String> = std::env::args().collect();
+    let loaded = config::config_path(&args, config::DEFAULT_CLIENT_CONFIG)
+        .and_then(|(path, required)| config::load_client(&path, required));
+    let (registration, rekey) = match loaded {
+        Ok(config) => (config.registration, config.rekey),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Registrazione presso il PC
+    let material = exit_on_error(
+        secondary::register(&registration, &registration.sc_id),
+        "registrazione presso il PC fallita"
+    );
+    let psks = exit_on_error(material.to_psk_store(), "materiale di chiave non valido");
+    println!("Chiave del flusso ricevuta (PSK_ID {})", String::from_utf8_lossy(&material.psk_id));
+
+    let remote = registration.ss_addr;
+    let stream = exit_on_error(TcpStream::connect(remote), "Fallimento nel connettersi al SS");
+
+    // Un errore del SS chiude la connessione senza panic
+    if let Err(e) = exchange_with_ss(stream, remote, &psks, rekey, associated_data) {
+        println!("Connessione chiusa: {}", e);
+    }
+}
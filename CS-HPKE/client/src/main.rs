@@ -1,415 +1,250 @@
-use std::net:: { TcpStream, SocketAddr };
-use std::{str, clone};
-use std::io::{self, BufRead, BufReader, Write, Read, Error};
-use std::time::Duration;
-
-use hpke::{
-    aead::{AeadTag, AesGcm128, AesGcm256, ChaCha20Poly1305, ExportOnlyAead},
-    kdf::{HkdfSha256, HkdfSha384, HkdfSha512},
-    kem::{X25519HkdfSha256, DhP256HkdfSha256},
-    Deserializable, Kem as KemTrait, OpModeR, OpModeS, Serializable,
-};
+use std::net:: { TcpListener, TcpStream };
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
 
 use rand::{rngs::StdRng, SeedableRng};
 
-mod data_packets_manager;
-mod ciphersuite_client;
-
-const INFO_STR: &[u8] = b"example session";
-
-
-// These are the only algorithms we're gonna use for this example
-type Kem = X25519HkdfSha256;
-type Aead = ChaCha20Poly1305;
-type Kdf = HkdfSha384;
-
-
-fn client_init() -> (<Kem as KemTrait>::PrivateKey, <Kem as KemTrait>::PublicKey) {
-    let mut csprng = StdRng::from_entropy();
-    Kem::gen_keypair(&mut csprng)
-}
-
-
-// Cripta il messaggio
-fn client_encrypt_msg(msg: &[u8], associated_data: &[u8], server_pk: &<Kem as KemTrait>::PublicKey) 
-    -> (<Kem as KemTrait>::EncappedKey, Vec<u8>, AeadTag<Aead>) {
+use hpke_proto::{config, data_packets_manager, exchange, handshake, keystore};
+use hpke_proto::agility::{self, AgileKeypair, AgileOpModeS, AgilePublicKey};
+use hpke_proto::ciphersuite::{Ciphersuite, HpkeMode, KEMtype};
+use hpke_proto::config::ClientConfig;
+use hpke_proto::data_packets_manager::{DataType, PacketDecoder, display_pack};
+use hpke_proto::handshake::{Negotiated, send_packet};
+use hpke_proto::error::{HpkeProtoError, exit_on_error};
+use hpke_proto::exchange::MessageSender;
+use hpke_proto::kri::RekeyPolicy;
+use hpke_proto::psk::PskStore;
+use hpke_proto::secondary::{self, FlowKeyMaterial, Registration};
+use hpke_proto::session::SenderSession;
+use hpke_proto::trust::Fingerprint;
+
+// Chiavi statiche del client, una per ogni KEM disponibile (usate nei modi Auth).
+// Con keys.key_file vengono lette da disco, altrimenti sono nuove a ogni avvio
+fn client_init(kems: &[KEMtype], key_file: Option<&Path>, passphrase: Option<&[u8]>) -> Result<Vec<AgileKeypair>, HpkeProtoError> {
+    if let Some(path) = key_file {
+        return keystore::load_or_create(path, kems, passphrase);
+    }
     let mut csprng = StdRng::from_entropy();
-
-    // Inside setup_sender(), encap() is made
-    let (encapped_key, mut sender_ctx) =
-        hpke::setup_sender::<Aead, Kdf, Kem, _>(
-            &OpModeS::Base,
-            server_pk,
-            INFO_STR, 
-            &mut csprng
-        ).expect("invalid server pubkey!");
-
-    let mut msg_copy = msg.to_vec();
-    let tag = 
-        sender_ctx.seal_in_place_detached(
-            &mut msg_copy,
-            associated_data
-        ).expect("encryption failed!");
-
-    let ciphertext = msg_copy;
-
-    (encapped_key, ciphertext, tag)
+    Ok(kems.iter()
+        .filter_map(|kem| agility::agile_gen_keypair(*kem, &mut csprng).ok())
+        .collect())
 }
 
 
-fn send_packet(stream:&mut TcpStream, pack: &[u8], received_mex: &mut [u8], what: String) {
-    stream.write(pack).unwrap();
-    println!("{} inviata", what);
-
-    match stream.read(received_mex) {
-
-        Ok(_) => {
-            if received_mex == [0] { println!("Il server ha ricevuto {}", what) }
-        },
-        
+// Configurazione da --config PATH, altrimenti da client.toml se presente.
+// Una configurazione non valida ferma il client prima di connettersi
+fn load_config() -> ClientConfig {
+    let args: Vec<String> = std::env::args().collect();
+    let loaded = config::config_path(&args, config::DEFAULT_CLIENT_CONFIG)
+        .and_then(|(path, required)| config::load_client(&path, required));
+    match loaded {
+        Ok(config) => config,
         Err(e) => {
-            println!("Fallimento nel ricevere dati: {}", e);
-            return;
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
     }
 }
 
 
-// Gestisce l'arrivo del pacchetto memorizzandolo nel corretto vettore
-fn handle_data(mut stream: &TcpStream, vec: &mut Vec<u8>, buf: &[u8], mex: &[u8]) -> Result<(), Error> {
-    let id = buf[0];                                                        
-    let dtype = data_packets_manager::int_to_datatype_display(id);      
-    println!("Arrivato {}", dtype);
-    display_buf(&buf);
-    let bytes_written = stream.write(mex)?;
-    if bytes_written == 0 {return Ok(());}
-    fill_vec_from_buf(vec, &buf);
-    Ok(display_vec(&vec))
+// Costruisce il modo HPKE del mittente a partire dalla negoziazione
+fn client_op_mode(negotiated: &Negotiated, psks: &PskStore, client_keys: &[AgileKeypair]) -> Result<AgileOpModeS, HpkeProtoError> {
+    let bundle = negotiated.psk_bundle(psks)?;
+    let keypair = client_keys.iter().find(|k| k.kem() == negotiated.suite.kem).cloned();
+    let missing = || HpkeProtoError::negotiation("negoziazione incompleta per il modo scelto");
+    Ok(match negotiated.mode {
+        HpkeMode::Base => AgileOpModeS::Base,
+        HpkeMode::Psk => AgileOpModeS::Psk(bundle.ok_or_else(missing)?),
+        HpkeMode::Auth => AgileOpModeS::Auth(keypair.ok_or_else(missing)?),
+        HpkeMode::AuthPsk => AgileOpModeS::AuthPsk(keypair.ok_or_else(missing)?, bundle.ok_or_else(missing)?),
+    })
 }
 
 
-// Rimpie il vettore con i dati dentro al buffer
-fn fill_vec_from_buf(vec: &mut Vec<u8>, buf: &[u8]) {
-    let mut ii:usize = 2;
-    let pack_len = buf[1];
-
-    loop {
-        while ii <= ((pack_len + 1)).into() {
-            vec.push(buf[ii]);
-            ii += 1;
-        }
-        break;
-    }
-}
-
-
-fn display_buf(buf: &[u8]) {
-    print!("data: ");
-    for i in buf { print!("{} ", i); }
-    print!("\n");
-}
-
-
-fn display_vec(vec: &Vec<u8>) {
-    print!("vettore: ");
-    for i in vec { print!("{} ", i); }
-    print!("\n\n");
+// Crea il pacchetto, lo invia e lo stampa
+fn send_data(stream: &mut TcpStream, data_type: DataType, payload: Vec<u8>, what: &str) -> Result<(), HpkeProtoError> {
+    let pack = data_packets_manager::create_packet(data_type, payload).group()?;
+    send_packet(stream, &pack, what)?;
+    display_pack(&pack);
+    Ok(())
 }
 
 
-// Printa il pacchetto completo
-fn display_pack(pack: &[u8]) {
-    let id = pack[0];
-    let dtype = data_packets_manager::int_to_datatype_display(id);
-    print!("{}: ", dtype);
-    let mut count:i8 = 0;
-    for i in pack {
-        count+=1;
-        print!("{} ",i);
-    }
-    println!("\nlen: {}\n", count);
-}
-
+// Con single_shot ogni messaggio viaggia in una busta autocontenuta,
+// altrimenti è cifrato con la chiave dell'epoca corrente della sessione
+// info è già legato al transcript della negoziazione
+#[allow(clippy::too_many_arguments)]
+fn server_exchange_mex(
+    stream: &mut TcpStream,
+    decoder: &mut PacketDecoder,
+    suite: &Ciphersuite,
+    mode: &AgileOpModeS,
+    associated_data: &[u8],
+    server_pk: &AgilePublicKey,
+    info: &[u8],
+    registration: Option<(&Mutex<TcpListener>, &Registration)>,
+    rekey: RekeyPolicy,
+    single_shot: bool
+) -> Result<(), HpkeProtoError> {
+    let mut csprng = StdRng::from_entropy();
 
-fn handle_server(
-    remote: SocketAddr,
-    stream: &mut TcpStream, 
-    server_pk: &mut Vec<u8>,
-    kem: &mut String, 
-    kdf: &mut String, 
-    aead: &mut String,
-    available_kem_cps: &Vec<String>,
-    available_kdf_cps: &Vec<String>,
-    available_aead_cps: &Vec<String>,
-) -> Result<(), Error> {
-
-    let mut received = [1 as u8];
-    let finish_cps = [8 as u8];
-    let finish_cps2 = [9 as u8];
-    let mut data = [0 as u8; 100]; 
-
-    println!("\nConnessione al server avviata alla porta {}", remote);
-
-
-    // ##### INVIO DELLA CIPHERSUITE DISPONIBILE #####
-
-    println!("\nInvio ciphersuite e richiesta chiave pubblica al server\n");  
-
-    // => KEM
-    for i in available_kem_cps {
-        let clone = i.clone();
-        let kem_cps_pack = data_packets_manager::create_packet(
-            data_packets_manager::DataType::Enc_ctx_KEM, 
-            clone.into_bytes()
-        );
-        let kem_cps_data_pack = kem_cps_pack.group();
-        let kem_cps_data_pack_bytes = kem_cps_data_pack.as_slice();
-        send_packet(stream, kem_cps_data_pack_bytes, &mut received, String::from("KEM cps"));
-        display_pack(kem_cps_data_pack_bytes);
-    }
-    // => KDF
-    for j in available_kdf_cps {
-        let clone = j.clone();
-        let kdf_cps_pack = data_packets_manager::create_packet(
-            data_packets_manager::DataType::Enc_ctx_KDF, 
-            clone.into_bytes()
-        );
-        let kdf_cps_data_pack = kdf_cps_pack.group();
-        let kdf_cps_data_pack_bytes = kdf_cps_data_pack.as_slice();
-        send_packet(stream, kdf_cps_data_pack_bytes, &mut received, String::from("KDF cps"));
-        display_pack(kdf_cps_data_pack_bytes);
+    // Un solo encap() per tutta la sessione: le chiavi dei messaggi
+    // si derivano dal contesto, epoca per epoca
+    let (session, encapped_key) = SenderSession::new(
+        suite,
+        mode,
+        server_pk,
+        info,
+        &mut csprng
+    )?;
+
+    // => EncappedKey, inviata una sola volta all'apertura della sessione
+    send_data(stream, DataType::EncappedKey, encapped_key.bytes, "EncappedKey")?;
+
+    // PC consegna a SC la chiave del flusso SC -> SS derivata dalla sessione
+    if let Some((listener, registration)) = registration {
+        let material = FlowKeyMaterial::derive(&session, registration)?;
+        secondary::serve_registration(listener, registration, &registration.sc_id, &material)?;
     }
-    // => AEAD
-    for k in available_aead_cps {
-        let clone = k.clone();
-        let aead_cps_pack = data_packets_manager::create_packet(
-            data_packets_manager::DataType::Enc_ctx_AEAD, 
-            clone.into_bytes()
-        );
-        let aead_cps_data_pack = aead_cps_pack.group();
-        let aead_cps_data_pack_bytes = aead_cps_data_pack.as_slice();
-        send_packet(stream, aead_cps_data_pack_bytes, &mut received, String::from("AEAD cps"));
-        display_pack(aead_cps_data_pack_bytes);
-    }
-
-    stream.write(&finish_cps).unwrap(); // segnala a S che è stato inviato tutto il ciphersuite
-
-    println!("\nCiphersuite e richiesta chiave pubblica inviati");
-
-    let ok_mex = [0 as u8]; // segnala con un write al server la ricezione del pacchetto
-
-    // vect per memorizzare gli algoritmi scelti
-    let mut server_pubkey: Vec<u8> = vec![];
-    let mut choosen_kem: Vec<u8> = vec![];
-    let mut choosen_kdf: Vec<u8> = vec![];
-    let mut choosen_aead: Vec<u8> = vec![];
-
-    // flag per segnalare che esiste almeno un algoritmo in comune di quel tipo
-    let mut pk_pass = false;
-    let mut kem_pass = false;
-    let mut kdf_pass = false;
-    let mut aead_pass = false;
-            
-
-    // ##### RICEZIONE DELLA CIPHERSUITE DEL SERVER #####
 
-    println!("Aspetto la ciphersuite scellta dal server...");
-
-    loop {
-               
-        let bytes_read = stream.read(&mut data)?;
-        if bytes_read == 0 {return Ok(());}
-
-        // => Server's public key
-        if data[0] == 0 {
-            handle_data(stream, &mut server_pubkey, &data, &ok_mex)?;
-            pk_pass = true;
-        }
-        // => KEM
-        if data[0] == 5 {
-            handle_data(stream, &mut choosen_kem, &data, &ok_mex)?;
-            kem_pass = true;
-        }
-        // => KDF
-        if data[0] == 6 {
-            handle_data(stream, &mut choosen_kdf, &data, &ok_mex)?;
-            kdf_pass = true;
-        }
-        // => AEAD
-        if data[0] == 7 {
-            handle_data(stream, &mut choosen_aead, &data, &ok_mex)?;
-            aead_pass = true;
-        }
-        if pk_pass && kem_pass && kdf_pass && aead_pass {
-            println!("Client ha ricevuto la ciphersuite del server");
-            break;   
+    let mut sender = MessageSender::new(
+        session,
+        suite,
+        mode,
+        server_pk,
+        info,
+        associated_data,
+        rekey,
+        single_shot
+    )?;
+
+    // Testo che deve essere mandato criptato, fino alla fine dell'input (Ctrl-D)
+    exchange::send_messages(stream, decoder, &mut sender, || {
+        println!("\nInserisci testo");
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input)? == 0 {
+            return Ok(None);
         }
+        Ok(Some(input.into_bytes()))
+    })
+}
 
-    }
-    
-    // #### OUTPUT DEI RISULTATI ####
-    *server_pk = server_pubkey;
-    *kem = String::from_utf8(choosen_kem).unwrap();
-    *kdf = String::from_utf8(choosen_kdf).unwrap();
-    *aead = String::from_utf8(choosen_aead).unwrap();
-    
-    //println!("kem {}", kem);
-    //println!("kdf {}", kdf);
-    //println!("aead {}", aead);
-
-    stream.write(&finish_cps2).unwrap();
-    
-    Ok(())
+fn main() {
 
-}
+    let config = load_config();
+    data_packets_manager::set_packet_dump(config.packet_dump);
 
+    let remote = config.connect;
 
-fn server_exchange_mex(stream: &mut TcpStream, associated_data: &[u8], server_pk: &<Kem as KemTrait>::PublicKey) -> Result<(), Error> {
-    
-    let mut received = [0 as u8; 1];
+    // Algoritmi offerti dal client, in ordine di preferenza
+    let kem_cps_av = config.kems;
+    let kdf_cps_av = config.kdfs;
+    let aead_cps_av = config.aeads;
+    let modes_av = config.modes;
 
-    loop {
-        // Testo che deve essere mandato criptato
-        println!("\nInserisci testo");
-        let mut input = String::new();
-        io::stdin().read_line(&mut input).expect("Failed to read");
-
-        // Let the client send a message to the server using the server's pubkey
-        let (encapped_key, ciphertext, tag) =   
-            client_encrypt_msg(
-                input.as_bytes(), 
-                associated_data, 
-                &server_pk
-            );
-        
-
-        // ##### CREAZIONE DEI PACCHETTI EncappedKey, Ciphertext, AssociatedData, TagBytes #####
-
-        // => EncappedKey
-        let ek = encapped_key.to_bytes().to_vec();
-        let ek_pack = data_packets_manager::create_packet(
-            data_packets_manager::DataType::EncappedKey, 
-            ek
-        );
-        let ek_data_pack = ek_pack.group();
-        let ek_data_pack_bytes = ek_data_pack.as_slice();
-
-        // => ChiperText
-        let ct_clone = ciphertext.clone();
-        let ct_pack = data_packets_manager::create_packet(
-            data_packets_manager::DataType::Ciphertext, 
-            ct_clone
-        );
-        let ct_data_pack = ct_pack.group();
-        let ct_data_pack_bytes = ct_data_pack.as_slice();
-
-        // => AssociatedData
-        let ad = associated_data.to_vec();
-        let ad_pack = data_packets_manager::create_packet(
-            data_packets_manager::DataType::AssociatedData, 
-            ad
-        );
-        let ad_data_pack = ad_pack.group();
-        let ad_data_pack_bytes = ad_data_pack.as_slice();
-
-        // => TagBytes
-        let tb = tag.to_bytes().to_vec();
-        let tb_pack = data_packets_manager::create_packet(
-            data_packets_manager::DataType::TagBytes, 
-            tb
-        );
-        let tb_data_pack = tb_pack.group();
-        let tb_data_pack_bytes = tb_data_pack.as_slice();
-
-
-        // ##### INVIO DEI PACCHETTI EncappedKey, Ciphertext, AssociatedData, TagBytes #####
-
-        println!("\nInvio pacchetti al server...\n");
-
-        // => EncappedKey
-        send_packet(stream, ek_data_pack_bytes, &mut received, String::from("EncappedKey"));
-        display_pack(ek_data_pack_bytes);
-
-        // => ChiperText
-        send_packet(stream, ct_data_pack_bytes, &mut received, String::from("Ciphertext"));
-        display_pack(ct_data_pack_bytes);
-
-        // => AssociatedData             
-        send_packet(stream, ad_data_pack_bytes, &mut received, String::from("AssociatedData"));
-        display_pack(ad_data_pack_bytes);
-
-        // => TagBytes              
-        send_packet(stream, tb_data_pack_bytes, &mut received, String::from("Tag"));
-        display_pack(tb_data_pack_bytes);
-
-        // ##### RICEZIONE CONTENUTO MANDATO #####
-        let mut buf = [0 as u8; 100];
-        let bytes_read = stream.read(&mut buf);
-        let mut msg = buf.to_vec();
-        let msg = str::from_utf8(&msg).unwrap();
-        println!("Il server ha inviato: {}", msg);
+    // Archivio delle PSK condivise col server
+    let psks = config.psks;
 
+    //Generazione delle chiavi statiche del client, una per ogni KEM disponibile
+    let client_keys = exit_on_error(
+        client_init(&kem_cps_av, config.key_file.as_deref(), config.passphrase.as_deref()),
+        "chiavi statiche del client"
+    );
+    // Nei modi Auth il server accetta solo le impronte in authorized_clients
+    for keypair in &client_keys {
+        println!("Impronta della chiave del client per {}: {}", keypair.kem(), Fingerprint::of(keypair.public_key()));
     }
-    Ok(())
-}
 
+    // Criterio di fiducia nella chiave pubblica del server
+    let server_keys = config.server_keys;
 
-fn main() {
+    // Con server_key.verify_key l'annuncio della chiave deve essere firmato dal server
+    let verify_key = config.verify_key;
 
-    //Generazione di chiave pubblica e privata del client
-    let (client_prikey, client_pubkey) = client_init();
-    
-    let remote: SocketAddr = "127.0.0.1:8888".parse().unwrap();
+    let mut server_pubkey:Vec<u8> = vec![];
 
-    let kem_cps_av = ciphersuite_client::KEMtypeS::to_vect();
-    let kdf_cps_av = ciphersuite_client::KDFtypeS::to_vect();
-    let aead_cps_av = ciphersuite_client::AEADtypeS::to_vect();
+    let associated_data = &config.associated_data[..];
 
-    let mut server_pubkey:Vec<u8> = vec![];
+    // Con secondary il PC accetta la registrazione di un SC
+    let registration = if config.secondary {
+        Some(exit_on_error(secondary::bind_registration(&config.registration), "Could not bind"))
+    } else {
+        None
+    };
+
+    // Con single_shot ogni messaggio viene inviato come busta single-shot
+    let single_shot = config.single_shot;
 
-    let mut kem_str:String = String::from("");
-    let mut kdf_str:String = String::from("");
-    let mut aead_str:String = String::from("");
+    let connected = match config.timeouts.connect {
+        Some(timeout) => TcpStream::connect_timeout(&remote, timeout),
+        None => TcpStream::connect(remote),
+    };
 
-    let associated_data = b"associated data"; 
-       
-    match TcpStream::connect(remote) {
+    match connected {
 
         Ok(mut stream) => {
 
-            /*Primary client initiates a request to the primary server. 
-              The request contains a list of available ciphersuites for KEM, KDF, and AEAD. */
-            handle_server(
-                remote,
-                &mut stream, 
-                &mut server_pubkey, 
-                &mut kem_str, 
-                &mut kdf_str, 
-                &mut aead_str,
-                &kem_cps_av,
-                &kdf_cps_av,
-                &aead_cps_av
-            ).unwrap();
+            let mut decoder = PacketDecoder::new();
+
+            // Qualsiasi errore del server chiude la connessione senza panic
+            let mut session = || -> Result<(), HpkeProtoError> {
+
+                stream.set_read_timeout(config.timeouts.read)?;
+                stream.set_write_timeout(config.timeouts.write)?;
+
+                /*Primary client initiates a request to the primary server. 
+                  The request contains a list of available ciphersuites for KEM, KDF, and AEAD. */
+                let negotiated = handshake::handle_server(
+                    remote,
+                    &mut stream, 
+                    &mut decoder,
+                    &mut server_pubkey, 
+                    &kem_cps_av,
+                    &kdf_cps_av,
+                    &aead_cps_av,
+                    &modes_av,
+                    &psks,
+                    &client_keys,
+                    &server_keys,
+                    verify_key.as_ref()
+                )?;
+                let suite = negotiated.suite;
             
-            println!("kem scelto: {}", kem_str);
-            println!("kdf scelto: {}", kdf_str);
-            println!("aead scelto: {}", aead_str);
-    
-            // Recupera la serve public key
-            let server_pubkey = <Kem as KemTrait>::PublicKey::from_bytes(
-                &mut server_pubkey.as_slice()
-            ).expect("could not deserialize the encapsulated pubkey!");
-
-            server_exchange_mex(
-                &mut stream, 
-                associated_data, 
-                &server_pubkey
-            ).unwrap();
+                println!("kem scelto: {:?} ({})", suite.kem, suite.kem);
+                println!("kdf scelto: {:?} ({})", suite.kdf, suite.kdf);
+                println!("aead scelto: {:?} ({})", suite.aead, suite.aead);
+                println!("modo scelto: {:?} ({})", negotiated.mode, negotiated.mode);
+
+                let mode = client_op_mode(&negotiated, &psks, &client_keys)?;
+
+                // Recupera la serve public key
+                let server_pubkey = agility::agile_pubkey_from_bytes(
+                    suite.kem,
+                    &server_pubkey
+                )?;
+
+                server_exchange_mex(
+                    &mut stream, 
+                    &mut decoder,
+                    &suite,
+                    &mode,
+                    associated_data, 
+                    &server_pubkey,
+                    &negotiated.session_info(&config.info),
+                    registration.as_ref().map(|listener| (listener, &config.registration)),
+                    config.rekey,
+                    single_shot
+                )
+            };
+
+            if let Err(e) = session() {
+                println!("Connessione chiusa: {}", e);
+            }
        },
 
         Err(e) => {
             println!("Fallimento nel connettersi: {}", e);
-            return;
         },
     }
 
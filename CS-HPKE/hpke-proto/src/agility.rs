@@ -0,0 +1,636 @@
+// Livello HPKE a dispatch dinamico: KEM, KDF e AEAD vengono scelti a runtime
+// a partire dalla ciphersuite negoziata, sullo stile dell'esempio agility.rs
+// della libreria (https://github.com/rozbb/rust-hpke/blob/master/examples/agility.rs)
+
+use std::fmt;
+
+use hpke::{
+    aead::{Aead, AeadCtxR, AeadCtxS, AeadTag, AesGcm128, AesGcm256, ChaCha20Poly1305, ExportOnlyAead},
+    kdf::{HkdfSha256, HkdfSha384, HkdfSha512, Kdf as KdfTrait},
+    kem::{DhP256HkdfSha256, X25519HkdfSha256},
+    Deserializable, HpkeError, Kem as KemTrait, OpModeR, OpModeS, PskBundle, Serializable,
+};
+use rand::{CryptoRng, RngCore};
+
+use crate::ciphersuite::{AEADtype, Ciphersuite, HpkeMode, KDFtype, KEMtype};
+use crate::replay::ReplayError;
+
+
+#[derive(Debug)]
+pub enum AgileHpkeError {
+    // La chiave è per un KEM diverso da quello della ciphersuite
+    AlgMismatch(KEMtype, KEMtype),
+    // KEM presente nel registro ma non implementato dalla libreria
+    UnsupportedKem(KEMtype),
+    // Sequenza dei nonce del contesto esaurita: serve un nuovo setup (RFC 9180, 5.2)
+    MessageLimitReached,
+    // Parametri non validi passati al livello HPKE
+    InvalidInput(&'static str),
+    // Messaggio cifrato con la chiave di un'epoca fuori dalla finestra ammessa
+    StaleEpoch(u32),
+    // Messaggio o encapped key già ricevuti
+    Replay(ReplayError),
+    // Errore interno della libreria HPKE
+    HpkeError(HpkeError),
+}
+
+impl fmt::Display for AgileHpkeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AgileHpkeError::AlgMismatch(expected, found) =>
+                write!(f, "chiave per {:?}, ma la ciphersuite usa {:?}", found, expected),
+            AgileHpkeError::UnsupportedKem(kem) =>
+                write!(f, "KEM {:?} ({}) non supportato dalla libreria HPKE", kem, kem),
+            AgileHpkeError::MessageLimitReached =>
+                write!(f, "numero massimo di messaggi raggiunto per il contesto HPKE"),
+            AgileHpkeError::InvalidInput(what) => write!(f, "input non valido: {}", what),
+            AgileHpkeError::StaleEpoch(kri) => write!(f, "epoca {} non più accettata", kri),
+            AgileHpkeError::Replay(e) => write!(f, "{}", e),
+            AgileHpkeError::HpkeError(e) => write!(f, "errore HPKE: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AgileHpkeError {}
+
+impl From<HpkeError> for AgileHpkeError {
+    fn from(e: HpkeError) -> AgileHpkeError {
+        match e {
+            HpkeError::MessageLimitReached => AgileHpkeError::MessageLimitReached,
+            e => AgileHpkeError::HpkeError(e),
+        }
+    }
+}
+
+impl From<ReplayError> for AgileHpkeError {
+    fn from(e: ReplayError) -> AgileHpkeError {
+        AgileHpkeError::Replay(e)
+    }
+}
+
+
+// Chiavi e encapped key serializzate, etichettate con il KEM a cui appartengono
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgilePublicKey {
+    pub kem: KEMtype,
+    pub bytes: Vec<u8>
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct AgilePrivateKey {
+    pub kem: KEMtype,
+    pub bytes: Vec<u8>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgileEncappedKey {
+    pub kem: KEMtype,
+    pub bytes: Vec<u8>
+}
+
+#[derive(Clone)]
+pub struct AgileKeypair(pub AgilePrivateKey, pub AgilePublicKey);
+
+impl AgileKeypair {
+    pub fn kem(&self) -> KEMtype {
+        self.1.kem
+    }
+
+    pub fn private_key(&self) -> &AgilePrivateKey {
+        &self.0
+    }
+
+    pub fn public_key(&self) -> &AgilePublicKey {
+        &self.1
+    }
+}
+
+
+// PSK e relativo PSK_ID. La PSK resta locale: sul canale viaggia solo il PSK_ID
+#[derive(Clone)]
+pub struct AgilePskBundle {
+    pub psk: Vec<u8>,
+    pub psk_id: Vec<u8>
+}
+
+impl AgilePskBundle {
+    fn as_bundle(&self) -> PskBundle<'_> {
+        PskBundle { psk: &self.psk, psk_id: &self.psk_id }
+    }
+}
+
+// Modo HPKE lato mittente: nei modi Auth serve la chiave statica del mittente
+#[derive(Clone)]
+pub enum AgileOpModeS {
+    Base,
+    Psk(AgilePskBundle),
+    Auth(AgileKeypair),
+    AuthPsk(AgileKeypair, AgilePskBundle)
+}
+
+// Modo HPKE lato destinatario: nei modi Auth serve la chiave pubblica del mittente
+#[derive(Clone)]
+pub enum AgileOpModeR {
+    Base,
+    Psk(AgilePskBundle),
+    Auth(AgilePublicKey),
+    AuthPsk(AgilePublicKey, AgilePskBundle)
+}
+
+impl AgileOpModeS {
+    pub fn mode(&self) -> HpkeMode {
+        match self {
+            AgileOpModeS::Base => HpkeMode::Base,
+            AgileOpModeS::Psk(_) => HpkeMode::Psk,
+            AgileOpModeS::Auth(_) => HpkeMode::Auth,
+            AgileOpModeS::AuthPsk(_, _) => HpkeMode::AuthPsk,
+        }
+    }
+
+    // Converte nel modo tipizzato della libreria per il KEM scelto
+    fn try_lift<Kem: KemTrait>(&self, kem: KEMtype) -> Result<OpModeS<'_, Kem>, AgileHpkeError> {
+        Ok(match self {
+            AgileOpModeS::Base => OpModeS::Base,
+            AgileOpModeS::Psk(bundle) => OpModeS::Psk(bundle.as_bundle()),
+            AgileOpModeS::Auth(keypair) => OpModeS::Auth(lift_keypair::<Kem>(kem, keypair)?),
+            AgileOpModeS::AuthPsk(keypair, bundle) =>
+                OpModeS::AuthPsk(lift_keypair::<Kem>(kem, keypair)?, bundle.as_bundle()),
+        })
+    }
+}
+
+impl AgileOpModeR {
+    pub fn mode(&self) -> HpkeMode {
+        match self {
+            AgileOpModeR::Base => HpkeMode::Base,
+            AgileOpModeR::Psk(_) => HpkeMode::Psk,
+            AgileOpModeR::Auth(_) => HpkeMode::Auth,
+            AgileOpModeR::AuthPsk(_, _) => HpkeMode::AuthPsk,
+        }
+    }
+
+    // Converte nel modo tipizzato della libreria per il KEM scelto
+    fn try_lift<Kem: KemTrait>(&self, kem: KEMtype) -> Result<OpModeR<'_, Kem>, AgileHpkeError> {
+        Ok(match self {
+            AgileOpModeR::Base => OpModeR::Base,
+            AgileOpModeR::Psk(bundle) => OpModeR::Psk(bundle.as_bundle()),
+            AgileOpModeR::Auth(pk) => OpModeR::Auth(lift_pubkey::<Kem>(kem, pk)?),
+            AgileOpModeR::AuthPsk(pk, bundle) =>
+                OpModeR::AuthPsk(lift_pubkey::<Kem>(kem, pk)?, bundle.as_bundle()),
+        })
+    }
+}
+
+fn lift_pubkey<Kem: KemTrait>(kem: KEMtype, pk: &AgilePublicKey) -> Result<Kem::PublicKey, AgileHpkeError> {
+    if pk.kem != kem {
+        return Err(AgileHpkeError::AlgMismatch(kem, pk.kem));
+    }
+    Ok(Kem::PublicKey::from_bytes(&pk.bytes)?)
+}
+
+fn lift_keypair<Kem: KemTrait>(kem: KEMtype, keypair: &AgileKeypair) -> Result<(Kem::PrivateKey, Kem::PublicKey), AgileHpkeError> {
+    if keypair.kem() != kem {
+        return Err(AgileHpkeError::AlgMismatch(kem, keypair.kem()));
+    }
+    let sk = Kem::PrivateKey::from_bytes(&keypair.private_key().bytes)?;
+    let pk = Kem::PublicKey::from_bytes(&keypair.public_key().bytes)?;
+    Ok((sk, pk))
+}
+
+
+// Contesti di cifratura e decifratura con gli algoritmi nascosti dietro un trait object
+pub trait AgileAeadCtxS {
+    // Cifra il messaggio in place e rende il tag serializzato
+    fn seal_in_place_detached(&mut self, msg: &mut [u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError>;
+    // Esporta un segreto di out_buf.len() byte legato al contesto (RFC 9180, 5.3)
+    fn export(&self, exporter_ctx: &[u8], out_buf: &mut [u8]) -> Result<(), AgileHpkeError>;
+}
+
+pub trait AgileAeadCtxR {
+    // Decifra il messaggio in place verificando il tag
+    fn open_in_place_detached(&mut self, ciphertext: &mut [u8], aad: &[u8], tag: &[u8]) -> Result<(), AgileHpkeError>;
+    // Esporta un segreto di out_buf.len() byte legato al contesto (RFC 9180, 5.3)
+    fn export(&self, exporter_ctx: &[u8], out_buf: &mut [u8]) -> Result<(), AgileHpkeError>;
+}
+
+// La libreria va in panic se si cifra con ExportOnlyAead: qui diventa un errore
+fn check_encrypts<A: Aead>() -> Result<(), AgileHpkeError> {
+    if A::AEAD_ID == ExportOnlyAead::AEAD_ID {
+        return Err(AgileHpkeError::InvalidInput("AEAD export-only: nessuna cifratura possibile"));
+    }
+    Ok(())
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AgileAeadCtxS for AeadCtxS<A, Kdf, Kem> {
+    fn seal_in_place_detached(&mut self, msg: &mut [u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
+        check_encrypts::<A>()?;
+        let tag = AeadCtxS::seal_in_place_detached(self, msg, aad)?;
+        Ok(tag.to_bytes().to_vec())
+    }
+
+    fn export(&self, exporter_ctx: &[u8], out_buf: &mut [u8]) -> Result<(), AgileHpkeError> {
+        AeadCtxS::export(self, exporter_ctx, out_buf)?;
+        Ok(())
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AgileAeadCtxR for AeadCtxR<A, Kdf, Kem> {
+    fn open_in_place_detached(&mut self, ciphertext: &mut [u8], aad: &[u8], tag: &[u8]) -> Result<(), AgileHpkeError> {
+        check_encrypts::<A>()?;
+        let tag = AeadTag::<A>::from_bytes(tag)?;
+        AeadCtxR::open_in_place_detached(self, ciphertext, aad, &tag)?;
+        Ok(())
+    }
+
+    fn export(&self, exporter_ctx: &[u8], out_buf: &mut [u8]) -> Result<(), AgileHpkeError> {
+        AeadCtxR::export(self, exporter_ctx, out_buf)?;
+        Ok(())
+    }
+}
+
+
+// Istanzia la funzione generica $f con gli algoritmi della ciphersuite.
+// Copre tutte le combinazioni KEM/KDF/AEAD presenti nel registro
+macro_rules! dispatch {
+    ($suite:expr, $f:ident, ($($args:expr),*)) => {
+        match $suite.kem {
+            KEMtype::X25519HkdfSha256 => dispatch!(@kdf $suite, X25519HkdfSha256, $f, ($($args),*)),
+            KEMtype::DhP256HkdfSha256 => dispatch!(@kdf $suite, DhP256HkdfSha256, $f, ($($args),*)),
+            unsupported => Err(AgileHpkeError::UnsupportedKem(unsupported)),
+        }
+    };
+    (@kdf $suite:expr, $kem:ty, $f:ident, ($($args:expr),*)) => {
+        match $suite.kdf {
+            KDFtype::HkdfSha256 => dispatch!(@aead $suite, $kem, HkdfSha256, $f, ($($args),*)),
+            KDFtype::HkdfSha384 => dispatch!(@aead $suite, $kem, HkdfSha384, $f, ($($args),*)),
+            KDFtype::HkdfSha512 => dispatch!(@aead $suite, $kem, HkdfSha512, $f, ($($args),*)),
+        }
+    };
+    (@aead $suite:expr, $kem:ty, $kdf:ty, $f:ident, ($($args:expr),*)) => {
+        match $suite.aead {
+            AEADtype::AesGcm128 => $f::<AesGcm128, $kdf, $kem>($($args),*),
+            AEADtype::AesGcm256 => $f::<AesGcm256, $kdf, $kem>($($args),*),
+            AEADtype::ChaCha20Poly1305 => $f::<ChaCha20Poly1305, $kdf, $kem>($($args),*),
+            AEADtype::ExportOnlyAead => $f::<ExportOnlyAead, $kdf, $kem>($($args),*),
+        }
+    };
+}
+
+
+// Genera una coppia di chiavi per il KEM indicato
+pub fn agile_gen_keypair<R: CryptoRng + RngCore>(kem: KEMtype, csprng: &mut R) -> Result<AgileKeypair, AgileHpkeError> {
+    match kem {
+        KEMtype::X25519HkdfSha256 => Ok(gen_keypair::<X25519HkdfSha256, R>(kem, csprng)),
+        KEMtype::DhP256HkdfSha256 => Ok(gen_keypair::<DhP256HkdfSha256, R>(kem, csprng)),
+        unsupported => Err(AgileHpkeError::UnsupportedKem(unsupported)),
+    }
+}
+
+fn gen_keypair<Kem: KemTrait, R: CryptoRng + RngCore>(kem: KEMtype, csprng: &mut R) -> AgileKeypair {
+    let (sk, pk) = Kem::gen_keypair(csprng);
+    let sk = AgilePrivateKey { kem, bytes: sk.to_bytes().to_vec() };
+    let pk = AgilePublicKey { kem, bytes: pk.to_bytes().to_vec() };
+    AgileKeypair(sk, pk)
+}
+
+
+// Deserializza e valida una chiave pubblica ricevuta dal peer
+pub fn agile_pubkey_from_bytes(kem: KEMtype, bytes: &[u8]) -> Result<AgilePublicKey, AgileHpkeError> {
+    match kem {
+        KEMtype::X25519HkdfSha256 => pubkey_from_bytes::<X25519HkdfSha256>(kem, bytes),
+        KEMtype::DhP256HkdfSha256 => pubkey_from_bytes::<DhP256HkdfSha256>(kem, bytes),
+        unsupported => Err(AgileHpkeError::UnsupportedKem(unsupported)),
+    }
+}
+
+fn pubkey_from_bytes<Kem: KemTrait>(kem: KEMtype, bytes: &[u8]) -> Result<AgilePublicKey, AgileHpkeError> {
+    let pk = Kem::PublicKey::from_bytes(bytes)?;
+    Ok(AgilePublicKey { kem, bytes: pk.to_bytes().to_vec() })
+}
+
+
+// Ricostruisce una coppia di chiavi salvata su disco, validando entrambe le metà
+pub fn agile_keypair_from_bytes(kem: KEMtype, sk: &[u8], pk: &[u8]) -> Result<AgileKeypair, AgileHpkeError> {
+    match kem {
+        KEMtype::X25519HkdfSha256 => keypair_from_bytes::<X25519HkdfSha256>(kem, sk, pk),
+        KEMtype::DhP256HkdfSha256 => keypair_from_bytes::<DhP256HkdfSha256>(kem, sk, pk),
+        unsupported => Err(AgileHpkeError::UnsupportedKem(unsupported)),
+    }
+}
+
+fn keypair_from_bytes<Kem: KemTrait>(kem: KEMtype, sk: &[u8], pk: &[u8]) -> Result<AgileKeypair, AgileHpkeError> {
+    let sk = Kem::PrivateKey::from_bytes(sk)?;
+    let pk = Kem::PublicKey::from_bytes(pk)?;
+    Ok(AgileKeypair(
+        AgilePrivateKey { kem, bytes: sk.to_bytes().to_vec() },
+        AgilePublicKey { kem, bytes: pk.to_bytes().to_vec() }
+    ))
+}
+
+
+// Lato mittente: encap() verso la chiave pubblica del destinatario e creazione del contesto
+pub fn agile_setup_sender<R: CryptoRng + RngCore>(
+    suite: &Ciphersuite,
+    mode: &AgileOpModeS,
+    pk_recip: &AgilePublicKey,
+    info: &[u8],
+    csprng: &mut R,
+) -> Result<(AgileEncappedKey, Box<dyn AgileAeadCtxS>), AgileHpkeError> {
+    if pk_recip.kem != suite.kem {
+        return Err(AgileHpkeError::AlgMismatch(suite.kem, pk_recip.kem));
+    }
+    dispatch!(suite, setup_sender, (suite.kem, mode, pk_recip, info, csprng))
+}
+
+fn setup_sender<A, Kdf, Kem>(
+    kem: KEMtype,
+    mode: &AgileOpModeS,
+    pk_recip: &AgilePublicKey,
+    info: &[u8],
+    csprng: &mut (impl CryptoRng + RngCore),
+) -> Result<(AgileEncappedKey, Box<dyn AgileAeadCtxS>), AgileHpkeError>
+where
+    A: Aead + 'static,
+    Kdf: KdfTrait + 'static,
+    Kem: KemTrait + 'static,
+{
+    let mode = mode.try_lift::<Kem>(kem)?;
+    let pk_recip = Kem::PublicKey::from_bytes(&pk_recip.bytes)?;
+    let (encapped_key, ctx) =
+        hpke::setup_sender::<A, Kdf, Kem, _>(&mode, &pk_recip, info, csprng)?;
+    let encapped_key = AgileEncappedKey { kem, bytes: encapped_key.to_bytes().to_vec() };
+    Ok((encapped_key, Box::new(ctx)))
+}
+
+
+// Lato destinatario: decap() della encapped key e creazione del contesto
+pub fn agile_setup_receiver(
+    suite: &Ciphersuite,
+    mode: &AgileOpModeR,
+    sk_recip: &AgilePrivateKey,
+    encapped_key: &AgileEncappedKey,
+    info: &[u8],
+) -> Result<Box<dyn AgileAeadCtxR>, AgileHpkeError> {
+    if sk_recip.kem != suite.kem {
+        return Err(AgileHpkeError::AlgMismatch(suite.kem, sk_recip.kem));
+    }
+    if encapped_key.kem != suite.kem {
+        return Err(AgileHpkeError::AlgMismatch(suite.kem, encapped_key.kem));
+    }
+    dispatch!(suite, setup_receiver, (suite.kem, mode, sk_recip, encapped_key, info))
+}
+
+fn setup_receiver<A, Kdf, Kem>(
+    kem: KEMtype,
+    mode: &AgileOpModeR,
+    sk_recip: &AgilePrivateKey,
+    encapped_key: &AgileEncappedKey,
+    info: &[u8],
+) -> Result<Box<dyn AgileAeadCtxR>, AgileHpkeError>
+where
+    A: Aead + 'static,
+    Kdf: KdfTrait + 'static,
+    Kem: KemTrait + 'static,
+{
+    let mode = mode.try_lift::<Kem>(kem)?;
+    let sk_recip = Kem::PrivateKey::from_bytes(&sk_recip.bytes)?;
+    let encapped_key = Kem::EncappedKey::from_bytes(&encapped_key.bytes)?;
+    let ctx = hpke::setup_receiver::<A, Kdf, Kem>(&mode, &sk_recip, &encapped_key, info)?;
+    Ok(Box::new(ctx))
+}
+
+
+// Single-shot (RFC 9180, sezione 6): setup e una sola cifratura, rende (enc, ciphertext, tag)
+pub fn agile_single_shot_seal<R: CryptoRng + RngCore>(
+    suite: &Ciphersuite,
+    mode: &AgileOpModeS,
+    pk_recip: &AgilePublicKey,
+    info: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+    csprng: &mut R,
+) -> Result<(AgileEncappedKey, Vec<u8>, Vec<u8>), AgileHpkeError> {
+    if pk_recip.kem != suite.kem {
+        return Err(AgileHpkeError::AlgMismatch(suite.kem, pk_recip.kem));
+    }
+    dispatch!(suite, single_shot_seal, (suite.kem, mode, pk_recip, info, plaintext, aad, csprng))
+}
+
+fn single_shot_seal<A, Kdf, Kem>(
+    kem: KEMtype,
+    mode: &AgileOpModeS,
+    pk_recip: &AgilePublicKey,
+    info: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+    csprng: &mut (impl CryptoRng + RngCore),
+) -> Result<(AgileEncappedKey, Vec<u8>, Vec<u8>), AgileHpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    check_encrypts::<A>()?;
+    let mode = mode.try_lift::<Kem>(kem)?;
+    let pk_recip = Kem::PublicKey::from_bytes(&pk_recip.bytes)?;
+    let mut ciphertext = plaintext.to_vec();
+    let (encapped_key, tag) = hpke::single_shot_seal_in_place_detached::<A, Kdf, Kem, _>(
+        &mode,
+        &pk_recip,
+        info,
+        &mut ciphertext,
+        aad,
+        csprng
+    )?;
+    let encapped_key = AgileEncappedKey { kem, bytes: encapped_key.to_bytes().to_vec() };
+    Ok((encapped_key, ciphertext, tag.to_bytes().to_vec()))
+}
+
+
+// Single-shot lato destinatario: decap e apertura dell'unico messaggio
+#[allow(clippy::too_many_arguments)]
+pub fn agile_single_shot_open(
+    suite: &Ciphersuite,
+    mode: &AgileOpModeR,
+    sk_recip: &AgilePrivateKey,
+    encapped_key: &AgileEncappedKey,
+    info: &[u8],
+    ciphertext: &[u8],
+    aad: &[u8],
+    tag: &[u8],
+) -> Result<Vec<u8>, AgileHpkeError> {
+    if sk_recip.kem != suite.kem {
+        return Err(AgileHpkeError::AlgMismatch(suite.kem, sk_recip.kem));
+    }
+    if encapped_key.kem != suite.kem {
+        return Err(AgileHpkeError::AlgMismatch(suite.kem, encapped_key.kem));
+    }
+    dispatch!(suite, single_shot_open, (suite.kem, mode, sk_recip, encapped_key, info, ciphertext, aad, tag))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn single_shot_open<A, Kdf, Kem>(
+    kem: KEMtype,
+    mode: &AgileOpModeR,
+    sk_recip: &AgilePrivateKey,
+    encapped_key: &AgileEncappedKey,
+    info: &[u8],
+    ciphertext: &[u8],
+    aad: &[u8],
+    tag: &[u8],
+) -> Result<Vec<u8>, AgileHpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    check_encrypts::<A>()?;
+    let mode = mode.try_lift::<Kem>(kem)?;
+    let sk_recip = Kem::PrivateKey::from_bytes(&sk_recip.bytes)?;
+    let encapped_key = Kem::EncappedKey::from_bytes(&encapped_key.bytes)?;
+    let tag = AeadTag::<A>::from_bytes(tag)?;
+    let mut plaintext = ciphertext.to_vec();
+    hpke::single_shot_open_in_place_detached::<A, Kdf, Kem>(
+        &mode,
+        &sk_recip,
+        &encapped_key,
+        info,
+        &mut plaintext,
+        aad,
+        &tag
+    )?;
+    Ok(plaintext)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{rngs::StdRng, SeedableRng};
+    use strum::IntoEnumIterator;
+
+    const INFO: &[u8] = b"agility test";
+    const AAD: &[u8] = b"associated data";
+
+    // Tutte le combinazioni KEM/KDF/AEAD con un KEM implementato
+    fn supported_suites() -> Vec<Ciphersuite> {
+        let mut suites = vec![];
+        for kem in KEMtype::iter().filter(KEMtype::is_supported) {
+            for kdf in KDFtype::iter() {
+                for aead in AEADtype::iter() {
+                    suites.push(Ciphersuite { kem, kdf, aead });
+                }
+            }
+        }
+        suites
+    }
+
+    fn psk() -> AgilePskBundle {
+        AgilePskBundle { psk: vec![0x5a; 32], psk_id: b"client-1".to_vec() }
+    }
+
+    #[test]
+    fn every_suite_round_trip() {
+        let mut csprng = StdRng::from_entropy();
+        for suite in supported_suites() {
+            let keypair = agile_gen_keypair(suite.kem, &mut csprng).unwrap();
+            let (encapped_key, mut sender) =
+                agile_setup_sender(&suite, &AgileOpModeS::Base, keypair.public_key(), INFO, &mut csprng).unwrap();
+            let mut receiver =
+                agile_setup_receiver(&suite, &AgileOpModeR::Base, keypair.private_key(), &encapped_key, INFO).unwrap();
+
+            let (mut sent, mut received) = ([0u8; 32], [0u8; 32]);
+            sender.export(b"contesto", &mut sent).unwrap();
+            receiver.export(b"contesto", &mut received).unwrap();
+            assert_eq!(sent, received, "{}", suite);
+
+            let single_shot = agile_single_shot_seal(&suite, &AgileOpModeS::Base, keypair.public_key(), INFO, b"messaggio", AAD, &mut csprng);
+            if suite.aead == AEADtype::ExportOnlyAead {
+                // Export-only: il contesto esporta segreti ma non cifra
+                assert!(sender.seal_in_place_detached(&mut b"messaggio".to_vec(), AAD).is_err(), "{}", suite);
+                assert!(receiver.open_in_place_detached(&mut b"messaggio".to_vec(), AAD, &[]).is_err(), "{}", suite);
+                assert!(single_shot.is_err(), "{}", suite);
+                continue;
+            }
+
+            let mut msg = b"messaggio".to_vec();
+            let tag = sender.seal_in_place_detached(&mut msg, AAD).unwrap();
+            receiver.open_in_place_detached(&mut msg, AAD, &tag).unwrap();
+            assert_eq!(msg, b"messaggio", "{}", suite);
+
+            let (encapped_key, ciphertext, tag) = single_shot.unwrap();
+            let plaintext = agile_single_shot_open(
+                &suite, &AgileOpModeR::Base, keypair.private_key(), &encapped_key, INFO, &ciphertext, AAD, &tag
+            ).unwrap();
+            assert_eq!(plaintext, b"messaggio", "{}", suite);
+        }
+    }
+
+    #[test]
+    fn every_mode_round_trip() {
+        let mut csprng = StdRng::from_entropy();
+        for kem in KEMtype::iter().filter(KEMtype::is_supported) {
+            let suite = Ciphersuite { kem, kdf: KDFtype::HkdfSha384, aead: AEADtype::ChaCha20Poly1305 };
+            let recipient = agile_gen_keypair(kem, &mut csprng).unwrap();
+            let sender_keys = agile_gen_keypair(kem, &mut csprng).unwrap();
+            let modes = [
+                (AgileOpModeS::Base, AgileOpModeR::Base),
+                (AgileOpModeS::Psk(psk()), AgileOpModeR::Psk(psk())),
+                (AgileOpModeS::Auth(sender_keys.clone()), AgileOpModeR::Auth(sender_keys.public_key().clone())),
+                (
+                    AgileOpModeS::AuthPsk(sender_keys.clone(), psk()),
+                    AgileOpModeR::AuthPsk(sender_keys.public_key().clone(), psk())
+                ),
+            ];
+            for (mode_s, mode_r) in modes {
+                assert_eq!(mode_s.mode(), mode_r.mode());
+                let (encapped_key, ciphertext, tag) =
+                    agile_single_shot_seal(&suite, &mode_s, recipient.public_key(), INFO, b"messaggio", AAD, &mut csprng).unwrap();
+                let plaintext = agile_single_shot_open(
+                    &suite, &mode_r, recipient.private_key(), &encapped_key, INFO, &ciphertext, AAD, &tag
+                ).unwrap();
+                assert_eq!(plaintext, b"messaggio", "{:?}", mode_s.mode());
+
+                // Il modo del destinatario deve coincidere con quello del mittente
+                let open_base = agile_single_shot_open(
+                    &suite, &AgileOpModeR::Base, recipient.private_key(), &encapped_key, INFO, &ciphertext, AAD, &tag
+                );
+                assert_eq!(open_base.is_ok(), mode_s.mode() == HpkeMode::Base);
+            }
+        }
+    }
+
+    #[test]
+    fn unregistered_kems_are_unsupported() {
+        let mut csprng = StdRng::from_entropy();
+        for kem in KEMtype::iter().filter(|kem| !kem.is_supported()) {
+            let unsupported = |result: Result<(), AgileHpkeError>| {
+                assert!(matches!(result, Err(AgileHpkeError::UnsupportedKem(k)) if k == kem), "{:?}", kem);
+            };
+            unsupported(agile_gen_keypair(kem, &mut csprng).map(|_| ()));
+            unsupported(agile_pubkey_from_bytes(kem, &[4u8; 65]).map(|_| ()));
+            unsupported(agile_keypair_from_bytes(kem, &[1u8; 32], &[4u8; 65]).map(|_| ()));
+
+            let suite = Ciphersuite { kem, kdf: KDFtype::HkdfSha256, aead: AEADtype::AesGcm128 };
+            let pk = AgilePublicKey { kem, bytes: vec![4u8; 65] };
+            unsupported(agile_setup_sender(&suite, &AgileOpModeS::Base, &pk, INFO, &mut csprng).map(|_| ()));
+            unsupported(agile_single_shot_seal(&suite, &AgileOpModeS::Base, &pk, INFO, b"", AAD, &mut csprng).map(|_| ()));
+
+            let sk = AgilePrivateKey { kem, bytes: vec![1u8; 32] };
+            let encapped_key = AgileEncappedKey { kem, bytes: vec![4u8; 65] };
+            unsupported(agile_setup_receiver(&suite, &AgileOpModeR::Base, &sk, &encapped_key, INFO).map(|_| ()));
+        }
+    }
+
+    #[test]
+    fn key_for_another_kem_is_rejected() {
+        let mut csprng = StdRng::from_entropy();
+        let keypair = agile_gen_keypair(KEMtype::X25519HkdfSha256, &mut csprng).unwrap();
+        let suite = Ciphersuite { kem: KEMtype::DhP256HkdfSha256, kdf: KDFtype::HkdfSha256, aead: AEADtype::AesGcm128 };
+        assert!(matches!(
+            agile_setup_sender(&suite, &AgileOpModeS::Base, keypair.public_key(), INFO, &mut csprng),
+            Err(AgileHpkeError::AlgMismatch(KEMtype::DhP256HkdfSha256, KEMtype::X25519HkdfSha256))
+        ));
+    }
+}
@@ -0,0 +1,539 @@
+use std::fmt;
+use strum::IntoEnumIterator;
+use strum_macros::EnumIter;
+
+use crate::error::HpkeProtoError;
+
+
+// Errore: l'identificativo ricevuto non corrisponde a nessun algoritmo conosciuto
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownAlgorithm {
+    pub category: &'static str,
+    pub id: u16
+}
+
+impl fmt::Display for UnknownAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} sconosciuto: {:#06X}", self.category, self.id)
+    }
+}
+
+impl std::error::Error for UnknownAlgorithm {}
+
+// Un algoritmo sconosciuto scelto dal peer rende impossibile l'accordo
+impl From<UnknownAlgorithm> for HpkeProtoError {
+    fn from(e: UnknownAlgorithm) -> HpkeProtoError {
+        HpkeProtoError::Negotiation(e.to_string())
+    }
+}
+
+
+// Comportamento comune a KEM, KDF e AEAD: sul canale viaggiano gli
+// identificativi dell'RFC 9180 come u16 big endian (2 byte)
+pub trait Algorithm: Sized + Copy + PartialEq + fmt::Display + TryFrom<u16, Error = UnknownAlgorithm> {
+    // Identificativo dell'algoritmo secondo l'RFC 9180, sezione 7
+    fn id(&self) -> u16;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.id().to_be_bytes().to_vec()
+    }
+
+    // Legge un identificativo da un payload di esattamente 2 byte
+    fn from_bytes(payload: &[u8]) -> Result<Self, HpkeProtoError> {
+        let id = read_id(payload)?;
+        Ok(Self::try_from(id)?)
+    }
+}
+
+// Legge un u16 big endian da un payload di esattamente 2 byte
+pub fn read_id(payload: &[u8]) -> Result<u16, HpkeProtoError> {
+    match payload {
+        [hi, lo] => Ok(u16::from_be_bytes([*hi, *lo])),
+        _ => Err(HpkeProtoError::framing(
+            format!("identificativo di algoritmo di {} byte invece di 2", payload.len())
+        )),
+    }
+}
+
+
+//######################### KEM ###############################
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter)]
+pub enum KEMtype {
+    // DHKEM dell'RFC 9180, sezione 7.1
+    X25519HkdfSha256,
+    DhP256HkdfSha256,
+    DhP384HkdfSha384,
+    DhP521HkdfSha512,
+    X448HkdfSha512
+}
+impl Algorithm for KEMtype {
+    fn id(&self) -> u16 {
+        match self {
+            KEMtype::DhP256HkdfSha256 => 0x0010,
+            KEMtype::DhP384HkdfSha384 => 0x0011,
+            KEMtype::DhP521HkdfSha512 => 0x0012,
+            KEMtype::X25519HkdfSha256 => 0x0020,
+            KEMtype::X448HkdfSha512 => 0x0021,
+        }
+    }
+}
+impl TryFrom<u16> for KEMtype {
+    type Error = UnknownAlgorithm;
+
+    fn try_from(id: u16) -> Result<KEMtype, UnknownAlgorithm> {
+        KEMtype::iter()
+            .find(|kem| kem.id() == id)
+            .ok_or(UnknownAlgorithm { category: "KEM", id })
+    }
+}
+// implementazione di Display per stampare gli id degli algoritmi
+impl fmt::Display for KEMtype {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#06X}", self.id())
+    }
+}
+// racchiude in un unico vettore gli algoritmi disponibili
+impl KEMtype {
+    pub fn to_vect() -> Vec<KEMtype> {
+        KEMtype::iter().filter(|kem| kem.is_supported()).collect()
+    }
+
+    // La libreria hpke 0.9 implementa solo X25519 e P-256: gli altri KEM sono
+    // registrati per riconoscerne gli ID, ma non possono essere negoziati
+    pub fn is_supported(&self) -> bool {
+        match self {
+            KEMtype::X25519HkdfSha256 | KEMtype::DhP256HkdfSha256 => true,
+            KEMtype::DhP384HkdfSha384 | KEMtype::DhP521HkdfSha512 | KEMtype::X448HkdfSha512 => false,
+        }
+    }
+}
+
+//######################### KDF ###############################
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter)]
+pub enum KDFtype {
+    // Algoritmi per KDF disponibili
+    HkdfSha256,
+    HkdfSha384,
+    HkdfSha512
+}
+impl Algorithm for KDFtype {
+    fn id(&self) -> u16 {
+        match self {
+            KDFtype::HkdfSha256 => 0x0001,
+            KDFtype::HkdfSha384 => 0x0002,
+            KDFtype::HkdfSha512 => 0x0003,
+        }
+    }
+}
+impl TryFrom<u16> for KDFtype {
+    type Error = UnknownAlgorithm;
+
+    fn try_from(id: u16) -> Result<KDFtype, UnknownAlgorithm> {
+        KDFtype::iter()
+            .find(|kdf| kdf.id() == id)
+            .ok_or(UnknownAlgorithm { category: "KDF", id })
+    }
+}
+// implementazione di Display per stampare gli id degli algoritmi
+impl fmt::Display for KDFtype {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#06X}", self.id())
+    }
+}
+// racchiude in un unico vettore gli algoritmi disponibili
+impl KDFtype {
+    pub fn to_vect() -> Vec<KDFtype> {
+        KDFtype::iter().collect()
+    }
+}
+
+//######################### AEAD ##############################
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter)]
+pub enum AEADtype {
+    // Algoritmi per AEAD disponibili
+    AesGcm128,
+    AesGcm256,
+    ChaCha20Poly1305,
+    ExportOnlyAead
+}
+impl Algorithm for AEADtype {
+    fn id(&self) -> u16 {
+        match self {
+            AEADtype::AesGcm128 => 0x0001,
+            AEADtype::AesGcm256 => 0x0002,
+            AEADtype::ChaCha20Poly1305 => 0x0003,
+            AEADtype::ExportOnlyAead => 0xFFFF,
+        }
+    }
+}
+impl TryFrom<u16> for AEADtype {
+    type Error = UnknownAlgorithm;
+
+    fn try_from(id: u16) -> Result<AEADtype, UnknownAlgorithm> {
+        AEADtype::iter()
+            .find(|aead| aead.id() == id)
+            .ok_or(UnknownAlgorithm { category: "AEAD", id })
+    }
+}
+// implementazione di Display per stampare gli id degli algoritmi
+impl fmt::Display for AEADtype {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#06X}", self.id())
+    }
+}
+// racchiude in un unico vettore gli algoritmi disponibili
+impl AEADtype {
+    pub fn to_vect() -> Vec<AEADtype> {
+        AEADtype::iter().collect()
+    }
+}
+
+
+//######################### MODE ##############################
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter)]
+pub enum HpkeMode {
+    // Modi dell'RFC 9180, sezione 5
+    Base,
+    Psk,
+    Auth,
+    AuthPsk
+}
+impl Algorithm for HpkeMode {
+    fn id(&self) -> u16 {
+        match self {
+            HpkeMode::Base => 0x0000,
+            HpkeMode::Psk => 0x0001,
+            HpkeMode::Auth => 0x0002,
+            HpkeMode::AuthPsk => 0x0003,
+        }
+    }
+}
+impl TryFrom<u16> for HpkeMode {
+    type Error = UnknownAlgorithm;
+
+    fn try_from(id: u16) -> Result<HpkeMode, UnknownAlgorithm> {
+        HpkeMode::iter()
+            .find(|mode| mode.id() == id)
+            .ok_or(UnknownAlgorithm { category: "MODE", id })
+    }
+}
+impl fmt::Display for HpkeMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#06X}", self.id())
+    }
+}
+impl HpkeMode {
+    pub fn to_vect() -> Vec<HpkeMode> {
+        HpkeMode::iter().collect()
+    }
+
+    // Il modo richiede una PSK condivisa
+    pub fn uses_psk(&self) -> bool {
+        matches!(self, HpkeMode::Psk | HpkeMode::AuthPsk)
+    }
+
+    // Il modo autentica il mittente con la sua chiave statica
+    pub fn uses_auth(&self) -> bool {
+        matches!(self, HpkeMode::Auth | HpkeMode::AuthPsk)
+    }
+}
+
+
+//######################### CIPHERSUITE #######################
+// Terna di algoritmi scelta al termine della negoziazione
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ciphersuite {
+    pub kem: KEMtype,
+    pub kdf: KDFtype,
+    pub aead: AEADtype
+}
+
+impl fmt::Display for Ciphersuite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KEM {:?} ({}), KDF {:?} ({}), AEAD {:?} ({})",
+            self.kem, self.kem, self.kdf, self.kdf, self.aead, self.aead)
+    }
+}
+
+
+//######################### CATEGORIE #########################
+// Categorie negoziate dall'handshake. L'alert NegotiationFailed contiene
+// un byte per ogni categoria in cui client e server non hanno nulla in comune
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlgorithmCategory {
+    Kem,
+    Kdf,
+    Aead,
+    Mode,
+    Psk     // modo con PSK possibile, ma nessun PSK_ID in comune
+}
+
+impl AlgorithmCategory {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            AlgorithmCategory::Kem => 0,
+            AlgorithmCategory::Kdf => 1,
+            AlgorithmCategory::Aead => 2,
+            AlgorithmCategory::Mode => 3,
+            AlgorithmCategory::Psk => 4,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Result<AlgorithmCategory, HpkeProtoError> {
+        match byte {
+            0 => Ok(AlgorithmCategory::Kem),
+            1 => Ok(AlgorithmCategory::Kdf),
+            2 => Ok(AlgorithmCategory::Aead),
+            3 => Ok(AlgorithmCategory::Mode),
+            4 => Ok(AlgorithmCategory::Psk),
+            _ => Err(HpkeProtoError::framing(format!("categoria di algoritmo sconosciuta: {}", byte))),
+        }
+    }
+}
+
+impl fmt::Display for AlgorithmCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AlgorithmCategory::Kem => write!(f, "KEM"),
+            AlgorithmCategory::Kdf => write!(f, "KDF"),
+            AlgorithmCategory::Aead => write!(f, "AEAD"),
+            AlgorithmCategory::Mode => write!(f, "MODE"),
+            AlgorithmCategory::Psk => write!(f, "PSK"),
+        }
+    }
+}
+
+
+//######################### POLITICA DI SELEZIONE #############
+// Di chi è l'ordine di preferenza che decide l'algoritmo scelto
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreferenceMode {
+    ServerPreference,   // vince il primo algoritmo della lista del server supportato dal client
+    ClientPreference    // vince il primo algoritmo offerto dal client supportato dal server
+}
+
+// Liste di preferenza del server per ciascuna categoria.
+// Solo gli algoritmi presenti in lista possono essere scelti
+#[derive(Debug, Clone)]
+pub struct SelectionPolicy {
+    pub mode: PreferenceMode,
+    pub kems: Vec<KEMtype>,
+    pub kdfs: Vec<KDFtype>,
+    pub aeads: Vec<AEADtype>,
+    pub modes: Vec<HpkeMode>
+}
+
+impl Default for SelectionPolicy {
+    // Di default ChaCha20Poly1305 e AES-256-GCM vincono sempre su AES-128-GCM.
+    // ExportOnlyAead non è in lista: con esso non si possono cifrare messaggi
+    fn default() -> SelectionPolicy {
+        SelectionPolicy {
+            mode: PreferenceMode::ServerPreference,
+            kems: vec![
+                KEMtype::X25519HkdfSha256,
+                KEMtype::X448HkdfSha512,
+                KEMtype::DhP256HkdfSha256,
+                KEMtype::DhP384HkdfSha384,
+                KEMtype::DhP521HkdfSha512
+            ],
+            kdfs: vec![KDFtype::HkdfSha512, KDFtype::HkdfSha384, KDFtype::HkdfSha256],
+            aeads: vec![AEADtype::ChaCha20Poly1305, AEADtype::AesGcm256, AEADtype::AesGcm128],
+            modes: vec![HpkeMode::AuthPsk, HpkeMode::Auth, HpkeMode::Psk, HpkeMode::Base]
+        }
+    }
+}
+
+impl SelectionPolicy {
+    // Sceglie la ciphersuite a partire dall'offerta del client.
+    // Rende None per ogni categoria senza algoritmi in comune
+    pub fn select(
+        &self,
+        client_kems: &[KEMtype],
+        client_kdfs: &[KDFtype],
+        client_aeads: &[AEADtype]
+    ) -> (Option<KEMtype>, Option<KDFtype>, Option<AEADtype>) {
+        // I KEM non implementati dalla libreria vengono scartati prima della scelta
+        let supported_kems: Vec<KEMtype> = self.kems.iter()
+            .copied()
+            .filter(|kem| {
+                if !kem.is_supported() {
+                    println!("KEM {:?} ({}) non supportato dalla libreria HPKE: escluso", kem, kem);
+                }
+                kem.is_supported()
+            })
+            .collect();
+
+        (
+            select_algorithm("KEM", self.mode, &supported_kems, client_kems),
+            select_algorithm("KDF", self.mode, &self.kdfs, client_kdfs),
+            select_algorithm("AEAD", self.mode, &self.aeads, client_aeads)
+        )
+    }
+
+    // Sceglie il modo HPKE; i modi con PSK sono possibili solo se il server
+    // conosce uno dei PSK_ID offerti dal client, i modi Auth solo se il
+    // server ha dei client autorizzati
+    pub fn select_mode(&self, client_modes: &[HpkeMode], psk_available: bool, auth_available: bool) -> Option<HpkeMode> {
+        let modes: Vec<HpkeMode> = self.modes.iter()
+            .copied()
+            .filter(|mode| {
+                if mode.uses_psk() && !psk_available {
+                    println!("MODE {:?}: nessuna PSK in comune col client, escluso", mode);
+                    return false;
+                }
+                if mode.uses_auth() && !auth_available {
+                    println!("MODE {:?}: nessun client autorizzato, escluso", mode);
+                    return false;
+                }
+                true
+            })
+            .collect();
+        select_algorithm("MODE", self.mode, &modes, client_modes)
+    }
+}
+
+// Sceglie il miglior algoritmo supportato da entrambi secondo la modalità
+// indicata, e stampa il motivo della scelta
+pub fn select_algorithm<T: Algorithm + fmt::Debug>(
+    category: &str,
+    mode: PreferenceMode,
+    server_pref: &[T],
+    client_offer: &[T]
+) -> Option<T> {
+    let (winner, rank, list) = match mode {
+        PreferenceMode::ServerPreference => (
+            server_pref.iter().enumerate().find(|(_, alg)| client_offer.contains(alg)),
+            "del server",
+            server_pref
+        ),
+        PreferenceMode::ClientPreference => (
+            client_offer.iter().enumerate().find(|(_, alg)| server_pref.contains(alg)),
+            "del client",
+            client_offer
+        ),
+    };
+
+    match winner {
+        Some((pos, alg)) => {
+            let skipped: Vec<&T> = list[..pos].iter().collect();
+            println!(
+                "{} scelto: {:?} ({}), posizione {} nella lista di preferenza {}; scartati perché non comuni: {:?}",
+                category, alg, alg, pos + 1, rank, skipped
+            );
+            Some(*alg)
+        }
+        None => {
+            println!(
+                "{}: nessun algoritmo in comune (server {:?}, client {:?})",
+                category, server_pref, client_offer
+            );
+            None
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KDFS: [KDFtype; 3] = [KDFtype::HkdfSha512, KDFtype::HkdfSha384, KDFtype::HkdfSha256];
+
+    // Ogni algoritmo registrato torna uguale da u16 e dai 2 byte sul canale
+    fn round_trip_all<T: Algorithm + IntoEnumIterator + fmt::Debug>() {
+        for alg in T::iter() {
+            assert_eq!(T::try_from(alg.id()), Ok(alg));
+            assert_eq!(T::from_bytes(&alg.to_bytes()).unwrap(), alg);
+            assert_eq!(alg.to_bytes(), alg.id().to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn registered_ids_round_trip() {
+        round_trip_all::<KEMtype>();
+        round_trip_all::<KDFtype>();
+        round_trip_all::<AEADtype>();
+        round_trip_all::<HpkeMode>();
+        // Valori dell'RFC 9180, sezione 7
+        assert_eq!(KEMtype::try_from(0x0020), Ok(KEMtype::X25519HkdfSha256));
+        assert_eq!(KDFtype::try_from(0x0003), Ok(KDFtype::HkdfSha512));
+        assert_eq!(AEADtype::try_from(0xFFFF), Ok(AEADtype::ExportOnlyAead));
+    }
+
+    #[test]
+    fn unknown_ids() {
+        assert_eq!(KEMtype::try_from(0x0000), Err(UnknownAlgorithm { category: "KEM", id: 0x0000 }));
+        assert_eq!(KEMtype::try_from(0x0013), Err(UnknownAlgorithm { category: "KEM", id: 0x0013 }));
+        assert_eq!(KDFtype::try_from(0x0004), Err(UnknownAlgorithm { category: "KDF", id: 0x0004 }));
+        assert_eq!(AEADtype::try_from(0x0004), Err(UnknownAlgorithm { category: "AEAD", id: 0x0004 }));
+        assert_eq!(HpkeMode::try_from(0x0004), Err(UnknownAlgorithm { category: "MODE", id: 0x0004 }));
+
+        // Sul canale un id sconosciuto rende impossibile l'accordo
+        assert!(matches!(KEMtype::from_bytes(&[0x00, 0x13]), Err(HpkeProtoError::Negotiation(_))));
+    }
+
+    #[test]
+    fn ids_must_be_two_bytes() {
+        assert_eq!(read_id(&[0x00, 0x20]).unwrap(), 0x0020);
+        for payload in [&[][..], &[0x00], &[0x00, 0x20, 0x00]] {
+            assert!(matches!(read_id(payload), Err(HpkeProtoError::Framing(_))), "{:?}", payload);
+            assert!(matches!(KEMtype::from_bytes(payload), Err(HpkeProtoError::Framing(_))), "{:?}", payload);
+        }
+    }
+
+    #[test]
+    fn server_preference_picks_the_first_server_choice() {
+        let client = [KDFtype::HkdfSha256, KDFtype::HkdfSha384];
+        assert_eq!(select_algorithm("KDF", PreferenceMode::ServerPreference, &KDFS, &client), Some(KDFtype::HkdfSha384));
+    }
+
+    #[test]
+    fn client_preference_picks_the_first_client_choice() {
+        let client = [KDFtype::HkdfSha256, KDFtype::HkdfSha384];
+        assert_eq!(select_algorithm("KDF", PreferenceMode::ClientPreference, &KDFS, &client), Some(KDFtype::HkdfSha256));
+        // Un algoritmo offerto ma non nella lista del server non viene mai scelto
+        let server = [KDFtype::HkdfSha384];
+        assert_eq!(select_algorithm("KDF", PreferenceMode::ClientPreference, &server, &client), Some(KDFtype::HkdfSha384));
+    }
+
+    #[test]
+    fn no_common_algorithm() {
+        for mode in [PreferenceMode::ServerPreference, PreferenceMode::ClientPreference] {
+            let server = [AEADtype::ChaCha20Poly1305];
+            assert_eq!(select_algorithm("AEAD", mode, &server, &[AEADtype::AesGcm128]), None);
+            assert_eq!(select_algorithm("AEAD", mode, &server, &[]), None);
+            assert_eq!(select_algorithm::<AEADtype>("AEAD", mode, &[], &[AEADtype::AesGcm128]), None);
+        }
+    }
+
+    #[test]
+    fn select_skips_unsupported_kems() {
+        let policy = SelectionPolicy::default();
+        // X448 è prima di P-256 nella lista del server, ma la libreria non lo implementa
+        let (kem, kdf, aead) = policy.select(
+            &[KEMtype::X448HkdfSha512, KEMtype::DhP256HkdfSha256],
+            &[KDFtype::HkdfSha256],
+            &[AEADtype::AesGcm128, AEADtype::AesGcm256]
+        );
+        assert_eq!(kem, Some(KEMtype::DhP256HkdfSha256));
+        assert_eq!(kdf, Some(KDFtype::HkdfSha256));
+        assert_eq!(aead, Some(AEADtype::AesGcm256));
+
+        let (kem, _, aead) = policy.select(&[KEMtype::X448HkdfSha512], &KDFS, &[AEADtype::ExportOnlyAead]);
+        assert_eq!((kem, aead), (None, None));
+    }
+
+    #[test]
+    fn select_mode_needs_psk_and_authorized_clients() {
+        let policy = SelectionPolicy::default();
+        let all = HpkeMode::to_vect();
+        assert_eq!(policy.select_mode(&all, true, true), Some(HpkeMode::AuthPsk));
+        assert_eq!(policy.select_mode(&all, false, true), Some(HpkeMode::Auth));
+        assert_eq!(policy.select_mode(&all, true, false), Some(HpkeMode::Psk));
+        assert_eq!(policy.select_mode(&all, false, false), Some(HpkeMode::Base));
+        assert_eq!(policy.select_mode(&[HpkeMode::Psk], false, true), None);
+
+        let policy = SelectionPolicy { mode: PreferenceMode::ClientPreference, ..SelectionPolicy::default() };
+        assert_eq!(policy.select_mode(&[HpkeMode::Base, HpkeMode::AuthPsk], true, true), Some(HpkeMode::Base));
+    }
+}
@@ -0,0 +1,876 @@
+// Configurazione di server e client da file TOML.
+// Ogni chiave è facoltativa: quelle mancanti prendono il valore di default,
+// uguale al comportamento senza file. Le chiavi sconosciute sono un errore,
+// così un refuso non passa inosservato. Gli algoritmi si scrivono col nome
+// della variante (es. "X25519HkdfSha256") e sono elencati in ordine di
+// preferenza. Gli esempi completi sono server.toml e client.toml.
+//
+// Il file viene prima letto così com'è (ServerFile, ClientFile) e poi
+// validato in ServerConfig e ClientConfig: ogni errore riporta la chiave
+// a cui si riferisce.
+
+use std::fmt::Debug;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use strum::IntoEnumIterator;
+
+use crate::ciphersuite::{AEADtype, Algorithm, HpkeMode, KDFtype, KEMtype, PreferenceMode, SelectionPolicy};
+use crate::connections;
+use crate::error::HpkeProtoError;
+use crate::kri::RekeyPolicy;
+use crate::psk::PskStore;
+use crate::secondary::Registration;
+use crate::signing::{self, VerifyingKey};
+use crate::trust::{self, AuthorizedClients, Fingerprint, ServerKeyPolicy};
+
+pub const DEFAULT_SERVER_CONFIG: &str = "server.toml";
+pub const DEFAULT_CLIENT_CONFIG: &str = "client.toml";
+
+// Porte su cui i primari accettano le registrazioni dei secondari
+pub const PS_REGISTRATION_ADDR: &str = "127.0.0.1:8889";
+pub const PC_REGISTRATION_ADDR: &str = "127.0.0.1:8890";
+
+
+// ##### FILE TOML #####
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerFile {
+    pub listen: String,
+    pub info: String,
+    pub max_connections: usize,
+    // Il PS accetta la registrazione di un SS per ogni sessione
+    pub secondary: bool,
+    pub algorithms: AlgorithmsFile,
+    pub keys: ServerKeysFile,
+    pub authorized_clients: AuthorizedClientsFile,
+    pub psks: Vec<PskFile>,
+    pub registration: RegistrationFile,
+    pub rekey: RekeyFile,
+    pub timeouts: TimeoutsFile,
+    pub logging: LoggingFile
+}
+
+impl Default for ServerFile {
+    fn default() -> ServerFile {
+        ServerFile {
+            listen: "0.0.0.0:8888".to_string(),
+            info: "example session".to_string(),
+            max_connections: connections::DEFAULT_MAX_CONNECTIONS,
+            secondary: false,
+            algorithms: AlgorithmsFile::default(),
+            keys: ServerKeysFile::default(),
+            authorized_clients: AuthorizedClientsFile::default(),
+            psks: vec![],
+            registration: RegistrationFile::default(),
+            rekey: RekeyFile::default(),
+            timeouts: TimeoutsFile::default(),
+            logging: LoggingFile::default()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientFile {
+    pub connect: String,
+    pub info: String,
+    pub associated_data: String,
+    // Ogni messaggio viaggia in una busta single-shot
+    pub single_shot: bool,
+    // Il PC accetta la registrazione di un SC
+    pub secondary: bool,
+    pub algorithms: AlgorithmsFile,
+    pub keys: ClientKeysFile,
+    pub server_key: ServerKeyFile,
+    pub psks: Vec<PskFile>,
+    pub registration: RegistrationFile,
+    pub rekey: RekeyFile,
+    pub timeouts: TimeoutsFile,
+    pub logging: LoggingFile
+}
+
+impl Default for ClientFile {
+    fn default() -> ClientFile {
+        ClientFile {
+            connect: "127.0.0.1:8888".to_string(),
+            info: "example session".to_string(),
+            associated_data: "associated data".to_string(),
+            single_shot: false,
+            secondary: false,
+            algorithms: AlgorithmsFile::default(),
+            keys: ClientKeysFile::default(),
+            server_key: ServerKeyFile::default(),
+            psks: vec![],
+            registration: RegistrationFile::default(),
+            rekey: RekeyFile::default(),
+            timeouts: TimeoutsFile::default(),
+            logging: LoggingFile::default()
+        }
+    }
+}
+
+// [algorithms]: algoritmi abilitati, in ordine di preferenza.
+// Una lista assente prende il default del server (SelectionPolicy::default)
+// o del client (tutti gli algoritmi implementati)
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlgorithmsFile {
+    // "server" o "client": solo lato server
+    pub preference: Option<String>,
+    pub kems: Option<Vec<String>>,
+    pub kdfs: Option<Vec<String>>,
+    pub aeads: Option<Vec<String>>,
+    pub modes: Option<Vec<String>>
+}
+
+// [keys] del server
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerKeysFile {
+    // Archivio delle chiavi d'identità, una per KEM
+    pub key_file: PathBuf,
+    // Variabile d'ambiente con la passphrase dell'archivio; se non è
+    // impostata le chiavi sono salvate in chiaro
+    pub passphrase_env: String,
+    // Chiave Ed25519 con cui il server firma l'annuncio della chiave pubblica
+    pub signing_key: PathBuf
+}
+
+impl Default for ServerKeysFile {
+    fn default() -> ServerKeysFile {
+        ServerKeysFile {
+            key_file: PathBuf::from("server_keys.hpks"),
+            passphrase_env: "HPKE_KEYSTORE_PASSPHRASE".to_string(),
+            signing_key: PathBuf::from("server_signing.key")
+        }
+    }
+}
+
+// [authorized_clients]: chiavi statiche dei client ammesse nei modi Auth
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthorizedClientsFile {
+    // Una impronta per riga, riletto a ogni connessione
+    pub file: Option<PathBuf>,
+    pub pins: Vec<String>
+}
+
+impl Default for AuthorizedClientsFile {
+    fn default() -> AuthorizedClientsFile {
+        AuthorizedClientsFile { file: Some(PathBuf::from("authorized_clients")), pins: vec![] }
+    }
+}
+
+// [keys] del client: senza key_file le chiavi statiche sono nuove a ogni avvio
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientKeysFile {
+    pub key_file: Option<PathBuf>,
+    pub passphrase_env: String
+}
+
+impl Default for ClientKeysFile {
+    fn default() -> ClientKeysFile {
+        ClientKeysFile { key_file: None, passphrase_env: "HPKE_KEYSTORE_PASSPHRASE".to_string() }
+    }
+}
+
+// [server_key]: come il client verifica la chiave del server
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerKeyFile {
+    // "tofu", "strict", "pinned" o "none"
+    pub trust: String,
+    pub known_hosts: PathBuf,
+    pub pins: Vec<String>,
+    // Chiave di verifica Ed25519 del server, in esadecimale
+    pub verify_key: Option<String>
+}
+
+impl Default for ServerKeyFile {
+    fn default() -> ServerKeyFile {
+        ServerKeyFile {
+            trust: "tofu".to_string(),
+            known_hosts: PathBuf::from("known_servers"),
+            pins: vec![],
+            verify_key: None
+        }
+    }
+}
+
+// [[psks]]: la PSK si scrive come testo (key) o in esadecimale (key_hex).
+// Nessuna PSK di default: vanno configurate in modo esplicito
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PskFile {
+    pub id: String,
+    pub key: Option<String>,
+    pub key_hex: Option<String>
+}
+
+// [registration]: entità secondarie (PDMv2). In server.toml la leggono PS e SS,
+// in client.toml PC e SC; la PSK deve coincidere tra primario e secondario
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistrationFile {
+    // Indirizzo di registrazione del primario; di default 127.0.0.1:8889
+    // per il PS e 127.0.0.1:8890 per il PC
+    pub primary: Option<String>,
+    // Attesa massima del secondario, in secondi; 0 disattiva il timeout
+    pub timeout_secs: u64,
+    pub sc_id: String,
+    pub ss_id: String,
+    pub sc_addr: String,
+    pub ss_addr: String,
+    // Nessuna PSK di default, come per [[psks]]
+    pub psk: Option<PskFile>
+}
+
+impl Default for RegistrationFile {
+    fn default() -> RegistrationFile {
+        RegistrationFile {
+            primary: None,
+            timeout_secs: 30,
+            sc_id: "secondary-client-1".to_string(),
+            ss_id: "secondary-server-1".to_string(),
+            sc_addr: "127.0.0.1:9000".to_string(),
+            ss_addr: "127.0.0.1:9001".to_string(),
+            psk: None
+        }
+    }
+}
+
+// [rekey]: rotazione della chiave dei messaggi (KRI). Il mittente (client, SC)
+// ruota dopo max_messages messaggi o max_age_secs secondi; il destinatario
+// (server, SS) accetta ancora le ultime `window` epoche
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RekeyFile {
+    pub max_messages: u64,
+    pub max_age_secs: u64,
+    pub window: u32
+}
+
+impl Default for RekeyFile {
+    fn default() -> RekeyFile {
+        let policy = RekeyPolicy::default();
+        RekeyFile {
+            max_messages: policy.max_messages,
+            max_age_secs: policy.max_age.as_secs(),
+            window: policy.window
+        }
+    }
+}
+
+// Epoche vecchie accettate al massimo: ognuna tiene in memoria una chiave
+const MAX_REKEY_WINDOW: u32 = 64;
+
+// [timeouts], in secondi; 0 disattiva il timeout. connect_secs vale solo per il client.
+// read_secs limita anche l'attesa del messaggio successivo: un peer fermo
+// non occupa per sempre uno dei posti di max_connections
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsFile {
+    pub connect_secs: u64,
+    pub read_secs: u64,
+    pub write_secs: u64
+}
+
+impl Default for TimeoutsFile {
+    fn default() -> TimeoutsFile {
+        TimeoutsFile { connect_secs: 10, read_secs: 300, write_secs: 30 }
+    }
+}
+
+// [logging]
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingFile {
+    // Stampa il contenuto di ogni pacchetto inviato e ricevuto
+    pub packet_dump: bool
+}
+
+impl Default for LoggingFile {
+    fn default() -> LoggingFile {
+        LoggingFile { packet_dump: true }
+    }
+}
+
+
+// ##### CONFIGURAZIONE VALIDATA #####
+
+pub struct ServerConfig {
+    pub listen: SocketAddr,
+    pub info: Vec<u8>,
+    pub max_connections: usize,
+    pub secondary: bool,
+    pub policy: SelectionPolicy,
+    pub key_file: PathBuf,
+    pub passphrase: Option<Vec<u8>>,
+    pub signing_key: PathBuf,
+    pub authorized_clients: AuthorizedClients,
+    pub psks: PskStore,
+    pub registration: Registration,
+    pub rekey: RekeyPolicy,
+    pub timeouts: Timeouts,
+    pub packet_dump: bool
+}
+
+pub struct ClientConfig {
+    pub connect: SocketAddr,
+    pub info: Vec<u8>,
+    pub associated_data: Vec<u8>,
+    pub single_shot: bool,
+    pub secondary: bool,
+    pub kems: Vec<KEMtype>,
+    pub kdfs: Vec<KDFtype>,
+    pub aeads: Vec<AEADtype>,
+    pub modes: Vec<HpkeMode>,
+    pub key_file: Option<PathBuf>,
+    pub passphrase: Option<Vec<u8>>,
+    pub server_keys: ServerKeyPolicy,
+    pub verify_key: Option<VerifyingKey>,
+    pub psks: PskStore,
+    pub registration: Registration,
+    pub rekey: RekeyPolicy,
+    pub timeouts: Timeouts,
+    pub packet_dump: bool
+}
+
+// None = nessun timeout
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    pub read: Option<Duration>,
+    pub write: Option<Duration>
+}
+
+
+// File di configurazione da --config PATH nella riga di comando, altrimenti
+// default. Rende anche se il file è obbligatorio: lo è solo se indicato
+pub fn config_path(args: &[String], default: &str) -> Result<(PathBuf, bool), HpkeProtoError> {
+    match args.iter().position(|arg| arg == "--config") {
+        Some(i) => match args.get(i + 1) {
+            Some(path) if !path.starts_with("--") => Ok((PathBuf::from(path), true)),
+            _ => Err(HpkeProtoError::config("--config richiede il percorso del file")),
+        },
+        None => Ok((PathBuf::from(default), false)),
+    }
+}
+
+// Legge la configurazione del server. Se required è false e il file
+// non esiste si usano i valori di default
+pub fn load_server(path: &Path, required: bool) -> Result<ServerConfig, HpkeProtoError> {
+    read_file::<ServerFile>(path, required)?.validate()
+}
+
+// Legge la configurazione del client, come load_server
+pub fn load_client(path: &Path, required: bool) -> Result<ClientConfig, HpkeProtoError> {
+    read_file::<ClientFile>(path, required)?.validate()
+}
+
+fn read_file<T: DeserializeOwned + Default>(path: &Path, required: bool) -> Result<T, HpkeProtoError> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound && !required => {
+            println!("{} non trovato: uso la configurazione di default", path.display());
+            return Ok(T::default());
+        }
+        Err(e) => return Err(HpkeProtoError::config(format!("{}: {}", path.display(), e))),
+    };
+    // L'errore di toml indica già riga, colonna e chiave
+    let file = toml::from_str(&text).map_err(|e| HpkeProtoError::config(format!("{}: {}", path.display(), e)))?;
+    println!("Configurazione letta da {}", path.display());
+    Ok(file)
+}
+
+
+impl ServerFile {
+    pub fn validate(self) -> Result<ServerConfig, HpkeProtoError> {
+        if self.max_connections == 0 {
+            return Err(HpkeProtoError::config("max_connections: deve essere almeno 1"));
+        }
+        let mode = match self.algorithms.preference.as_deref() {
+            None | Some("server") => PreferenceMode::ServerPreference,
+            Some("client") => PreferenceMode::ClientPreference,
+            Some(other) => return Err(HpkeProtoError::config(format!(
+                "algorithms.preference: \"{}\" non valido (validi: server, client)", other
+            ))),
+        };
+        let psks = parse_psks(&self.psks)?;
+        let defaults = SelectionPolicy::default();
+        let algorithms = &self.algorithms;
+        let modes = parse_modes(algorithms.modes.as_deref(), defaults.modes, &psks)?;
+
+        Ok(ServerConfig {
+            listen: parse_addr("listen", &self.listen)?,
+            info: self.info.into_bytes(),
+            max_connections: self.max_connections,
+            secondary: self.secondary,
+            policy: SelectionPolicy {
+                mode,
+                // Dal default restano fuori i KEM non implementati dalla libreria
+                kems: parse_kems(algorithms.kems.as_deref(), defaults.kems.into_iter().filter(KEMtype::is_supported).collect())?,
+                kdfs: parse_algorithms("algorithms.kdfs", algorithms.kdfs.as_deref(), defaults.kdfs)?,
+                aeads: parse_aeads(algorithms.aeads.as_deref(), defaults.aeads)?,
+                modes
+            },
+            key_file: self.keys.key_file,
+            passphrase: passphrase_from_env(&self.keys.passphrase_env),
+            signing_key: self.keys.signing_key,
+            authorized_clients: AuthorizedClients {
+                pins: parse_pins("authorized_clients.pins", &self.authorized_clients.pins)?,
+                file: self.authorized_clients.file
+            },
+            psks,
+            registration: self.registration.validate(PS_REGISTRATION_ADDR, self.secondary)?,
+            rekey: self.rekey.validate()?,
+            timeouts: self.timeouts.validate(),
+            packet_dump: self.logging.packet_dump
+        })
+    }
+}
+
+impl ClientFile {
+    pub fn validate(self) -> Result<ClientConfig, HpkeProtoError> {
+        if self.algorithms.preference.is_some() {
+            return Err(HpkeProtoError::config(
+                "algorithms.preference: vale solo per il server, il client offre gli algoritmi nell'ordine della lista"
+            ));
+        }
+        let psks = parse_psks(&self.psks)?;
+        let algorithms = &self.algorithms;
+        let modes = parse_modes(algorithms.modes.as_deref(), HpkeMode::to_vect(), &psks)?;
+        // ExportOnlyAead non cifra messaggi: di default non viene offerto
+        let default_aeads = AEADtype::to_vect().into_iter().filter(|aead| *aead != AEADtype::ExportOnlyAead).collect();
+
+        Ok(ClientConfig {
+            connect: parse_addr("connect", &self.connect)?,
+            info: self.info.into_bytes(),
+            associated_data: self.associated_data.into_bytes(),
+            single_shot: self.single_shot,
+            secondary: self.secondary,
+            kems: parse_kems(algorithms.kems.as_deref(), KEMtype::to_vect())?,
+            kdfs: parse_algorithms("algorithms.kdfs", algorithms.kdfs.as_deref(), KDFtype::to_vect())?,
+            aeads: parse_aeads(algorithms.aeads.as_deref(), default_aeads)?,
+            modes,
+            key_file: self.keys.key_file,
+            passphrase: passphrase_from_env(&self.keys.passphrase_env),
+            server_keys: self.server_key.validate()?,
+            verify_key: match &self.server_key.verify_key {
+                Some(hex) => Some(signing::parse_verifying_key(hex)
+                    .map_err(|_| HpkeProtoError::config("server_key.verify_key: attese 64 cifre esadecimali di una chiave Ed25519"))?),
+                None => None,
+            },
+            psks,
+            registration: self.registration.validate(PC_REGISTRATION_ADDR, self.secondary)?,
+            rekey: self.rekey.validate()?,
+            timeouts: self.timeouts.validate(),
+            packet_dump: self.logging.packet_dump
+        })
+    }
+}
+
+impl ServerKeyFile {
+    fn validate(&self) -> Result<ServerKeyPolicy, HpkeProtoError> {
+        if self.trust != "pinned" && !self.pins.is_empty() {
+            return Err(HpkeProtoError::config("server_key.pins: usato solo con trust = \"pinned\""));
+        }
+        match self.trust.as_str() {
+            "tofu" => Ok(ServerKeyPolicy::KnownHosts { path: self.known_hosts.clone(), tofu: true }),
+            "strict" => Ok(ServerKeyPolicy::KnownHosts { path: self.known_hosts.clone(), tofu: false }),
+            "pinned" => {
+                if self.pins.is_empty() {
+                    return Err(HpkeProtoError::config("server_key.pins: serve almeno un'impronta con trust = \"pinned\""));
+                }
+                Ok(ServerKeyPolicy::Pinned(parse_pins("server_key.pins", &self.pins)?))
+            }
+            "none" => Ok(ServerKeyPolicy::AcceptAny),
+            other => Err(HpkeProtoError::config(format!(
+                "server_key.trust: \"{}\" non valido (validi: tofu, strict, pinned, none)", other
+            ))),
+        }
+    }
+}
+
+impl RegistrationFile {
+    // Con secondary il primario deve avere la PSK di registrazione
+    fn validate(&self, default_primary: &str, secondary: bool) -> Result<Registration, HpkeProtoError> {
+        if self.sc_id.is_empty() || self.ss_id.is_empty() {
+            return Err(HpkeProtoError::config("registration: sc_id e ss_id non possono essere vuoti"));
+        }
+        let mut psks = PskStore::new();
+        match &self.psk {
+            Some(entry) => {
+                let key = format!("registration.psk ({})", entry.id);
+                psks.insert(entry.id.as_bytes(), &parse_psk(&key, entry)?)
+                    .map_err(|e| HpkeProtoError::config(format!("{}: {}", key, e)))?;
+            }
+            None if secondary => return Err(HpkeProtoError::config(
+                "registration.psk: con secondary = true serve la PSK di registrazione condivisa col secondario"
+            )),
+            None => {}
+        }
+        Ok(Registration {
+            primary: parse_addr("registration.primary", self.primary.as_deref().unwrap_or(default_primary))?,
+            timeout: secs(self.timeout_secs),
+            sc_id: self.sc_id.as_bytes().to_vec(),
+            ss_id: self.ss_id.as_bytes().to_vec(),
+            sc_addr: parse_addr("registration.sc_addr", &self.sc_addr)?,
+            ss_addr: parse_addr("registration.ss_addr", &self.ss_addr)?,
+            psks
+        })
+    }
+}
+
+impl RekeyFile {
+    fn validate(&self) -> Result<RekeyPolicy, HpkeProtoError> {
+        if self.max_messages == 0 {
+            return Err(HpkeProtoError::config("rekey.max_messages: deve essere almeno 1"));
+        }
+        if self.max_age_secs == 0 {
+            return Err(HpkeProtoError::config("rekey.max_age_secs: deve essere almeno 1"));
+        }
+        if self.window > MAX_REKEY_WINDOW {
+            return Err(HpkeProtoError::config(format!("rekey.window: al massimo {} epoche", MAX_REKEY_WINDOW)));
+        }
+        Ok(RekeyPolicy {
+            max_messages: self.max_messages,
+            max_age: Duration::from_secs(self.max_age_secs),
+            window: self.window
+        })
+    }
+}
+
+impl TimeoutsFile {
+    fn validate(&self) -> Timeouts {
+        Timeouts { connect: secs(self.connect_secs), read: secs(self.read_secs), write: secs(self.write_secs) }
+    }
+}
+
+// 0 secondi = nessun timeout
+fn secs(s: u64) -> Option<Duration> {
+    if s == 0 { None } else { Some(Duration::from_secs(s)) }
+}
+
+
+fn parse_addr(key: &str, addr: &str) -> Result<SocketAddr, HpkeProtoError> {
+    addr.parse().map_err(|_| HpkeProtoError::config(format!("{}: \"{}\" non è un indirizzo ip:porta", key, addr)))
+}
+
+fn parse_pins(key: &str, pins: &[String]) -> Result<Vec<Fingerprint>, HpkeProtoError> {
+    pins.iter()
+        .map(|pin| Fingerprint::parse(pin).map_err(|_| HpkeProtoError::config(
+            format!("{}: impronta \"{}\" non valida, atteso SHA256:<64 cifre esadecimali>", key, pin)
+        )))
+        .collect()
+}
+
+fn names<T: Debug>(algorithms: &[T]) -> Vec<String> {
+    algorithms.iter().map(|alg| format!("{:?}", alg)).collect()
+}
+
+// Lista di algoritmi per nome, non vuota e senza ripetizioni; se assente vale default
+fn parse_algorithms<T: Algorithm + IntoEnumIterator + Debug>(key: &str, list: Option<&[String]>, default: Vec<T>) -> Result<Vec<T>, HpkeProtoError> {
+    let Some(list) = list else {
+        return Ok(default);
+    };
+    if list.is_empty() {
+        return Err(HpkeProtoError::config(format!("{}: serve almeno un algoritmo", key)));
+    }
+    let mut algorithms: Vec<T> = vec![];
+    for name in list {
+        let alg = T::iter().find(|alg| format!("{:?}", alg) == *name).ok_or_else(|| HpkeProtoError::config(format!(
+            "{}: algoritmo \"{}\" sconosciuto (validi: {})", key, name, names(&T::iter().collect::<Vec<T>>()).join(", ")
+        )))?;
+        if algorithms.contains(&alg) {
+            return Err(HpkeProtoError::config(format!("{}: \"{}\" ripetuto", key, name)));
+        }
+        algorithms.push(alg);
+    }
+    Ok(algorithms)
+}
+
+fn parse_kems(list: Option<&[String]>, default: Vec<KEMtype>) -> Result<Vec<KEMtype>, HpkeProtoError> {
+    let kems = parse_algorithms("algorithms.kems", list, default)?;
+    if let Some(kem) = kems.iter().find(|kem| !kem.is_supported()) {
+        return Err(HpkeProtoError::config(format!(
+            "algorithms.kems: {:?} non è implementato dalla libreria (supportati: {})",
+            kem, names(&KEMtype::to_vect()).join(", ")
+        )));
+    }
+    Ok(kems)
+}
+
+fn parse_aeads(list: Option<&[String]>, default: Vec<AEADtype>) -> Result<Vec<AEADtype>, HpkeProtoError> {
+    let aeads = parse_algorithms("algorithms.aeads", list, default)?;
+    if aeads.contains(&AEADtype::ExportOnlyAead) {
+        return Err(HpkeProtoError::config("algorithms.aeads: ExportOnlyAead non può cifrare messaggi"));
+    }
+    Ok(aeads)
+}
+
+// I modi con PSK richiedono almeno una PSK configurata
+fn parse_modes(list: Option<&[String]>, default: Vec<HpkeMode>, psks: &PskStore) -> Result<Vec<HpkeMode>, HpkeProtoError> {
+    let modes = parse_algorithms("algorithms.modes", list, default)?;
+    if psks.is_empty() && modes.iter().all(|mode| mode.uses_psk()) {
+        return Err(HpkeProtoError::config("algorithms.modes: tutti i modi abilitati richiedono una PSK, ma [[psks]] è vuoto"));
+    }
+    Ok(modes)
+}
+
+fn parse_psks(list: &[PskFile]) -> Result<PskStore, HpkeProtoError> {
+    let mut psks = PskStore::new();
+    for (i, entry) in list.iter().enumerate() {
+        let key = format!("psks[{}] ({})", i, entry.id);
+        let psk = parse_psk(&key, entry)?;
+        if psks.contains(entry.id.as_bytes()) {
+            return Err(HpkeProtoError::config(format!("{}: id ripetuto", key)));
+        }
+        psks.insert(entry.id.as_bytes(), &psk).map_err(|e| HpkeProtoError::config(format!("{}: {}", key, e)))?;
+    }
+    Ok(psks)
+}
+
+// Chiave di una PSK, scritta come testo o in esadecimale
+fn parse_psk(key: &str, entry: &PskFile) -> Result<Vec<u8>, HpkeProtoError> {
+    let psk = match (&entry.key, &entry.key_hex) {
+        (Some(text), None) => text.as_bytes().to_vec(),
+        (None, Some(hex)) => {
+            let mut psk = vec![0u8; hex.len() / 2];
+            trust::parse_hex(hex, &mut psk)
+                .ok_or_else(|| HpkeProtoError::config(format!("{}: key_hex non è esadecimale", key)))?;
+            psk
+        }
+        _ => return Err(HpkeProtoError::config(format!("{}: serve esattamente uno tra key e key_hex", key))),
+    };
+    if weak_psk(&psk) {
+        return Err(HpkeProtoError::config(format!(
+            "{}: PSK di esempio o segnaposto, generarne una casuale (es. openssl rand -hex 32)", key
+        )));
+    }
+    Ok(psk)
+}
+
+// PSK pubblicate come esempio in questo repository
+const KNOWN_PSKS: &[&[u8]] = &[
+    b"0123456789abcdef0123456789abcdef",
+    b"registration-psk-0123456789abcdef"
+];
+
+// Segnaposto lasciati in un file di configurazione copiato da un esempio
+const PLACEHOLDERS: &[&str] = &["changeme", "change-me", "change_me", "placeholder", "example", "xxxxxxxx"];
+
+// PSK nota, segnaposto, ripetizione di un blocco più corto (es. 32 volte lo
+// stesso byte) o sequenza crescente (0x00 0x01 0x02 ...)
+fn weak_psk(psk: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(psk).to_lowercase();
+    let periodic = (1..=psk.len() / 2).any(|period| psk[period..].iter().zip(psk).all(|(a, b)| a == b));
+    let ascending = psk.windows(2).all(|pair| pair[1] == pair[0].wrapping_add(1));
+    KNOWN_PSKS.contains(&psk) || PLACEHOLDERS.iter().any(|word| text.contains(word)) || periodic || ascending
+}
+
+fn passphrase_from_env(var: &str) -> Option<Vec<u8>> {
+    std::env::var(var).ok().map(String::into_bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // PSK casuale di 32 byte in esadecimale
+    const PSK_HEX: &str = "3f1c9a7be2d45086a1f0c3b79e2d5a4c6b8e0f1d2a3c4b5e6f708192a3b4c5d6";
+
+    fn server(text: &str) -> Result<ServerConfig, HpkeProtoError> {
+        toml::from_str::<ServerFile>(text).map_err(|e| HpkeProtoError::config(e.to_string()))?.validate()
+    }
+
+    fn client(text: &str) -> Result<ClientConfig, HpkeProtoError> {
+        toml::from_str::<ClientFile>(text).map_err(|e| HpkeProtoError::config(e.to_string()))?.validate()
+    }
+
+    fn config_error<T>(result: Result<T, HpkeProtoError>) -> String {
+        match result {
+            Err(HpkeProtoError::Config(what)) => what,
+            Err(e) => panic!("errore inatteso: {}", e),
+            Ok(_) => panic!("configurazione accettata"),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let config = server("").unwrap();
+        assert_eq!(config.policy.kems, SelectionPolicy::default().kems.into_iter().filter(KEMtype::is_supported).collect::<Vec<_>>());
+        assert!(config.psks.is_empty());
+        assert_eq!(config.timeouts.read, Some(Duration::from_secs(300)));
+        assert_eq!(config.registration.primary, PS_REGISTRATION_ADDR.parse().unwrap());
+
+        let config = client("").unwrap();
+        assert!(!config.aeads.contains(&AEADtype::ExportOnlyAead));
+        assert_eq!(config.registration.primary, PC_REGISTRATION_ADDR.parse().unwrap());
+    }
+
+    #[test]
+    fn algorithms_in_order() {
+        let config = server(r#"
+            [algorithms]
+            preference = "client"
+            kems = ["DhP256HkdfSha256", "X25519HkdfSha256"]
+            aeads = ["ChaCha20Poly1305"]
+        "#).unwrap();
+        assert_eq!(config.policy.mode, PreferenceMode::ClientPreference);
+        assert_eq!(config.policy.kems, vec![KEMtype::DhP256HkdfSha256, KEMtype::X25519HkdfSha256]);
+        assert_eq!(config.policy.aeads, vec![AEADtype::ChaCha20Poly1305]);
+    }
+
+    #[test]
+    fn unknown_algorithm_names() {
+        let what = config_error(server("[algorithms]\nkems = [\"X25519\"]"));
+        assert!(what.contains("algorithms.kems") && what.contains("X25519"), "{}", what);
+
+        let what = config_error(client("[algorithms]\naeads = [\"aesgcm128\"]"));
+        assert!(what.contains("algorithms.aeads"), "{}", what);
+
+        let what = config_error(server("[algorithms]\nmodes = [\"Base\", \"Psk2\"]"));
+        assert!(what.contains("algorithms.modes"), "{}", what);
+
+        let what = config_error(server("[algorithms]\npreference = \"both\""));
+        assert!(what.contains("algorithms.preference"), "{}", what);
+
+        // Nomi validi ma non utilizzabili
+        assert!(config_error(server("[algorithms]\naeads = [\"ExportOnlyAead\"]")).contains("ExportOnlyAead"));
+        assert!(config_error(server("[algorithms]\nkdfs = [\"HkdfSha256\", \"HkdfSha256\"]")).contains("ripetuto"));
+    }
+
+    #[test]
+    fn empty_preference_lists() {
+        for list in ["kems", "kdfs", "aeads", "modes"] {
+            let what = config_error(server(&format!("[algorithms]\n{} = []", list)));
+            assert!(what.contains(&format!("algorithms.{}", list)), "{}", what);
+            config_error(client(&format!("[algorithms]\n{} = []", list)));
+        }
+    }
+
+    #[test]
+    fn psk_modes_need_a_psk() {
+        let what = config_error(server("[algorithms]\nmodes = [\"Psk\", \"AuthPsk\"]"));
+        assert!(what.contains("[[psks]]"), "{}", what);
+
+        let config = server(&format!(
+            "[algorithms]\nmodes = [\"Psk\"]\n[[psks]]\nid = \"client-1\"\nkey_hex = \"{}\"", PSK_HEX
+        )).unwrap();
+        assert!(config.psks.contains(b"client-1"));
+    }
+
+    #[test]
+    fn psk_length_and_format() {
+        // 31 byte: sotto il minimo dell'RFC 9180
+        let what = config_error(server(&format!("[[psks]]\nid = \"short\"\nkey_hex = \"{}\"", &PSK_HEX[..62])));
+        assert!(what.contains("psks[0]"), "{}", what);
+
+        // Numero dispari di cifre o caratteri non esadecimali
+        config_error(server(&format!("[[psks]]\nid = \"odd\"\nkey_hex = \"{}0\"", PSK_HEX)));
+        config_error(server(&format!("[[psks]]\nid = \"hex\"\nkey_hex = \"{}zz\"", &PSK_HEX[..62])));
+
+        // key e key_hex insieme, o nessuno dei due
+        config_error(server(&format!("[[psks]]\nid = \"both\"\nkey = \"{}\"\nkey_hex = \"{}\"", PSK_HEX, PSK_HEX)));
+        config_error(server("[[psks]]\nid = \"none\""));
+
+        // Id ripetuto
+        let what = config_error(server(&format!(
+            "[[psks]]\nid = \"a\"\nkey_hex = \"{0}\"\n[[psks]]\nid = \"a\"\nkey = \"{0}\"", PSK_HEX
+        )));
+        assert!(what.contains("ripetuto"), "{}", what);
+    }
+
+    #[test]
+    fn weak_psks_are_rejected() {
+        for key in ["0123456789abcdef0123456789abcdef", "changeme-changeme-changeme-changeme", &"a".repeat(32)] {
+            let what = config_error(server(&format!("[[psks]]\nid = \"weak\"\nkey = \"{}\"", key)));
+            assert!(what.contains("segnaposto"), "{}", what);
+        }
+        let ascending: String = (0..32u8).map(|b| format!("{:02x}", b)).collect();
+        config_error(server(&format!("[[psks]]\nid = \"weak\"\nkey_hex = \"{}\"", ascending)));
+    }
+
+    #[test]
+    fn registration_psk_required_with_secondary() {
+        let what = config_error(server("secondary = true"));
+        assert!(what.contains("registration.psk"), "{}", what);
+
+        let config = client(&format!(
+            "secondary = true\n[registration]\nprimary = \"127.0.0.1:7000\"\n[registration.psk]\nid = \"reg\"\nkey_hex = \"{}\"",
+            PSK_HEX
+        )).unwrap();
+        assert!(config.registration.psks.contains(b"reg"));
+        assert_eq!(config.registration.primary, "127.0.0.1:7000".parse().unwrap());
+    }
+
+    #[test]
+    fn unknown_keys_and_bad_values() {
+        // I refusi non passano inosservati
+        assert!(server("lisen = \"0.0.0.0:8888\"").is_err());
+        assert!(client("[timeouts]\nread = 10").is_err());
+
+        assert!(config_error(server("listen = \"localhost\"")).contains("listen"));
+        assert!(config_error(server("max_connections = 0")).contains("max_connections"));
+        assert!(config_error(client("[server_key]\ntrust = \"pinned\"")).contains("server_key.pins"));
+        assert!(config_error(client("[algorithms]\npreference = \"client\"")).contains("algorithms.preference"));
+    }
+
+    #[test]
+    fn rekey_policy() {
+        let config = server("").unwrap();
+        assert_eq!(config.rekey.max_messages, RekeyPolicy::default().max_messages);
+        assert_eq!(config.rekey.window, RekeyPolicy::default().window);
+
+        let config = client("[rekey]\nmax_messages = 100\nmax_age_secs = 60\nwindow = 0").unwrap();
+        assert_eq!(config.rekey.max_messages, 100);
+        assert_eq!(config.rekey.max_age, Duration::from_secs(60));
+        assert_eq!(config.rekey.window, 0);
+
+        assert!(config_error(client("[rekey]\nmax_messages = 0")).contains("rekey.max_messages"));
+        assert!(config_error(server("[rekey]\nmax_age_secs = 0")).contains("rekey.max_age_secs"));
+        assert!(config_error(server("[rekey]\nwindow = 65")).contains("rekey.window"));
+        assert!(server("[rekey]\nmax_age = 60").is_err());
+    }
+
+    #[test]
+    fn zero_timeout_disables_it() {
+        let config = client("[timeouts]\nconnect_secs = 0\nread_secs = 5").unwrap();
+        assert_eq!(config.timeouts.connect, None);
+        assert_eq!(config.timeouts.read, Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn missing_file() {
+        let path = std::env::temp_dir().join(format!("hpke-proto-{}-missing.toml", std::process::id()));
+        // Indicato con --config il file è obbligatorio
+        let what = config_error(load_server(&path, true));
+        assert!(what.contains(&path.display().to_string()), "{}", what);
+        config_error(load_client(&path, true));
+        // Il file di default può mancare
+        assert!(load_server(&path, false).is_ok());
+        assert!(load_client(&path, false).is_ok());
+    }
+
+    #[test]
+    fn config_path_from_args() {
+        let args = |list: &[&str]| list.iter().map(|arg| arg.to_string()).collect::<Vec<String>>();
+        assert_eq!(config_path(&args(&["server"]), "server.toml").unwrap(), (PathBuf::from("server.toml"), false));
+        assert_eq!(
+            config_path(&args(&["server", "--config", "other.toml"]), "server.toml").unwrap(),
+            (PathBuf::from("other.toml"), true)
+        );
+        config_error(config_path(&args(&["server", "--config"]), "server.toml"));
+        config_error(config_path(&args(&["server", "--config", "--verbose"]), "server.toml"));
+    }
+}
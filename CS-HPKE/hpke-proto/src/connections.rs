@@ -0,0 +1,255 @@
+// Gestione concorrente delle connessioni lato server.
+// - Un thread per connessione: un client lento non blocca gli altri.
+// - Gli errori di una connessione chiudono solo quella connessione.
+// - Limite sul numero di connessioni attive: oltre il limite la
+//   connessione viene accettata e chiusa subito.
+// - Chiusura ordinata: alla richiesta di shutdown il server smette di
+//   accettare, chiude in lettura le connessioni aperte e aspetta che
+//   i thread finiscano il messaggio in corso.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::{Shutdown as SocketShutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::error::HpkeProtoError;
+
+// Limite di default sulle connessioni contemporanee
+pub const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+// Intervallo con cui il ciclo di accept controlla la richiesta di shutdown
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
+
+// Flag di shutdown condiviso tra il server e chi lo ferma (es. il Ctrl-C)
+#[derive(Clone, Default)]
+pub struct Shutdown(Arc<AtomicBool>);
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    pub fn request(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+
+// Connessioni attive, indicizzate da un id progressivo. Si tiene un clone
+// dello stream solo per poterlo chiudere allo shutdown
+#[derive(Default)]
+struct ActiveConnections {
+    next_id: u64,
+    streams: HashMap<u64, TcpStream>
+}
+
+impl ActiveConnections {
+    // Registra la connessione se c'è posto, rende il suo id
+    fn try_insert(&mut self, stream: &TcpStream, max: usize) -> Result<Option<u64>, Error> {
+        if self.streams.len() >= max {
+            return Ok(None);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.streams.insert(id, stream.try_clone()?);
+        Ok(Some(id))
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.streams.remove(&id);
+    }
+
+    // Chiude in lettura tutte le connessioni: la read() successiva rende EOF
+    // e il thread esce dopo aver risposto al messaggio in corso
+    fn close_all(&self) {
+        for stream in self.streams.values() {
+            let _ = stream.shutdown(SocketShutdown::Read);
+        }
+    }
+}
+
+// Alla fine del thread la connessione libera il suo posto, anche in caso di panic
+struct ConnectionSlot {
+    id: u64,
+    active: Arc<Mutex<ActiveConnections>>
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.active.lock().unwrap_or_else(PoisonError::into_inner).remove(self.id);
+    }
+}
+
+
+// Accetta connessioni finché non viene richiesto lo shutdown e serve
+// ognuna in un thread dedicato con handler. Un errore dell'handler viene
+// stampato e chiude solo la connessione che l'ha causato
+pub fn serve<F>(listener: &TcpListener, max_connections: usize, shutdown: &Shutdown, handler: F) -> Result<(), HpkeProtoError>
+where
+    F: Fn(TcpStream, SocketAddr) -> Result<(), HpkeProtoError> + Send + Sync + 'static
+{
+    if max_connections == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "il limite di connessioni deve essere almeno 1").into());
+    }
+
+    // Listener non bloccante: l'accept non deve impedire di vedere lo shutdown
+    listener.set_nonblocking(true)?;
+
+    let handler = Arc::new(handler);
+    let active = Arc::new(Mutex::new(ActiveConnections::default()));
+    let mut workers: Vec<JoinHandle<()>> = vec![];
+
+    while !shutdown.is_requested() {
+        let (stream, peer) = match listener.accept() {
+            Ok(conn) => conn,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("failed: {}", e);
+                continue;
+            }
+        };
+        // Un errore su questa connessione non deve fermare il server
+        if let Err(e) = stream.set_nonblocking(false) {
+            eprintln!("Connessione da {} non configurata: {}", peer, e);
+            continue;
+        }
+
+        // Un thread andato in panic col lock non deve fermare il server
+        let id = match active.lock().unwrap_or_else(PoisonError::into_inner).try_insert(&stream, max_connections) {
+            Ok(Some(id)) => id,
+            Ok(None) => {
+                println!("Connessione da {} rifiutata: limite di {} connessioni raggiunto", peer, max_connections);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Connessione da {} non registrata: {}", peer, e);
+                continue;
+            }
+        };
+        println!("Connessione {} aperta da {}", id, peer);
+
+        let slot = ConnectionSlot { id, active: Arc::clone(&active) };
+        let handler = Arc::clone(&handler);
+        workers.push(thread::spawn(move || {
+            let _slot = slot;
+            match handler(stream, peer) {
+                Ok(()) => println!("Connessione {} chiusa", id),
+                Err(e) => println!("Connessione {} chiusa: {}", id, e),
+            }
+        }));
+
+        // I thread già terminati non vanno più aspettati
+        workers.retain(|w| !w.is_finished());
+    }
+
+    println!("Shutdown richiesto: attendo la chiusura delle connessioni aperte");
+    active.lock().unwrap_or_else(PoisonError::into_inner).close_all();
+    for worker in workers {
+        // Un panic nel thread riguarda solo la sua connessione
+        let _ = worker.join();
+    }
+    println!("Server fermato");
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Instant;
+
+    // Connessioni servite e terminate dall'handler
+    #[derive(Clone, Default)]
+    struct Counters {
+        served: Arc<AtomicUsize>,
+        finished: Arc<AtomicUsize>
+    }
+
+    // Server di prova: l'handler legge fino alla chiusura della connessione
+    fn start(max_connections: usize) -> (SocketAddr, Shutdown, Counters, JoinHandle<Result<(), HpkeProtoError>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let counters = Counters::default();
+
+        let (stop, seen) = (shutdown.clone(), counters.clone());
+        let server = thread::spawn(move || serve(&listener, max_connections, &stop, move |mut stream, _peer| {
+            seen.served.fetch_add(1, Ordering::SeqCst);
+            let mut rest = vec![];
+            stream.read_to_end(&mut rest)?;
+            seen.finished.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }));
+        (addr, shutdown, counters, server)
+    }
+
+    fn wait_for(what: &str, done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timeout: {}", what);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn count(counter: &AtomicUsize) -> usize {
+        counter.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn connections_over_the_limit_are_closed() {
+        let (addr, shutdown, counters, server) = start(1);
+
+        let mut first = TcpStream::connect(addr).unwrap();
+        first.write_all(b"x").unwrap();
+        wait_for("prima connessione servita", || count(&counters.served) == 1);
+
+        // Oltre il limite la connessione viene chiusa senza arrivare all'handler
+        let mut second = TcpStream::connect(addr).unwrap();
+        second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(second.read(&mut [0u8; 1]).unwrap(), 0);
+        assert_eq!(count(&counters.served), 1);
+
+        // Chiusa la prima, il posto si libera
+        drop(first);
+        wait_for("prima connessione terminata", || count(&counters.finished) == 1);
+        let _third = TcpStream::connect(addr).unwrap();
+        wait_for("terza connessione servita", || count(&counters.served) == 2);
+
+        shutdown.request();
+        server.join().unwrap().unwrap();
+        assert_eq!(count(&counters.finished), 2);
+    }
+
+    #[test]
+    fn shutdown_closes_open_connections() {
+        let (addr, shutdown, counters, server) = start(4);
+        let clients: Vec<TcpStream> = (0..2).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        wait_for("connessioni servite", || count(&counters.served) == 2);
+
+        // I client restano connessi: lo shutdown chiude le connessioni in lettura
+        shutdown.request();
+        server.join().unwrap().unwrap();
+        assert_eq!(count(&counters.finished), 2);
+        drop(clients);
+    }
+
+    #[test]
+    fn zero_connections_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(serve(&listener, 0, &Shutdown::new(), |_, _| Ok(())).is_err());
+    }
+}
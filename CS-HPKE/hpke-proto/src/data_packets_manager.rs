@@ -0,0 +1,419 @@
+use std::fmt;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::error::HpkeProtoError;
+
+// Tipi di dati che devono essere scambiati tra client e server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataType {
+    PublicKey,
+    EncappedKey,
+    Ciphertext,
+    AssociatedData,
+    TagBytes,
+    EncCtxKem,
+    EncCtxKdf,
+    EncCtxAead,
+    EndCiphersuite,     // il client ha inviato tutti i suoi algoritmi
+    CiphersuiteAck,     // il client ha ricevuto ciphersuite e chiave pubblica del server
+    Plaintext,          // messaggio decriptato rimandato al client
+    EncCtxMode,       // modo HPKE (Base, Psk, Auth, AuthPsk)
+    PskId,              // identificativo della PSK; la PSK non viene mai inviata
+    ClientPublicKey,    // chiave statica del client per i modi Auth e AuthPsk
+    SecondaryId,        // identificativo di SC/SS che si registra presso il primario
+    KeyRotationIndex,   // KRI e sequenza del messaggio che segue
+    Envelope,           // messaggio single-shot completo in un solo pacchetto
+    EndOfMessage,       // chiude un messaggio; il payload contiene i flag
+    MessageAck,         // ack finale del messaggio, solo se richiesto
+    NegotiationFailed,  // alert del server: categorie senza algoritmi in comune
+    ClientNonce,        // nonce del client da includere nella firma dell'annuncio
+    ServerKeySignature  // firma Ed25519 dell'annuncio della chiave del server
+}
+
+// Flag di EndOfMessage: il mittente vuole un MessageAck
+pub const EOM_ACK_REQUESTED: u8 = 0x01;
+
+// Ogni DataType viene riconosciuto tramite un numero intero (1° elemento nel pacchetto)
+pub fn datatype_to_int(data_ype: &DataType) -> u8 {
+    match data_ype {
+        DataType::PublicKey => 0,
+        DataType::EncappedKey => 1,
+        DataType::Ciphertext => 2,
+        DataType::AssociatedData => 3,
+        DataType::TagBytes => 4,
+        DataType::EncCtxKem => 5,
+        DataType::EncCtxKdf => 6,
+        DataType::EncCtxAead => 7,
+        DataType::EndCiphersuite => 8,
+        DataType::CiphersuiteAck => 9,
+        DataType::Plaintext => 10,
+        DataType::EncCtxMode => 11,
+        DataType::PskId => 12,
+        DataType::ClientPublicKey => 13,
+        DataType::SecondaryId => 14,
+        DataType::KeyRotationIndex => 15,
+        DataType::Envelope => 16,
+        DataType::EndOfMessage => 17,
+        DataType::MessageAck => 18,
+        DataType::NegotiationFailed => 19,
+        DataType::ClientNonce => 20,
+        DataType::ServerKeySignature => 21
+    }
+}
+
+// Errore di protocollo: il primo byte del pacchetto non corrisponde a nessun DataType
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownDataType(pub u8);
+
+impl fmt::Display for UnknownDataType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tipo di pacchetto sconosciuto: {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownDataType {}
+
+// Rende un Datatype da un numero intero; i valori sconosciuti vengono rifiutati
+impl TryFrom<u8> for DataType {
+    type Error = UnknownDataType;
+
+    fn try_from(i: u8) -> Result<DataType, UnknownDataType> {
+        match i {
+            0 => Ok(DataType::PublicKey),
+            1 => Ok(DataType::EncappedKey),
+            2 => Ok(DataType::Ciphertext),
+            3 => Ok(DataType::AssociatedData),
+            4 => Ok(DataType::TagBytes),
+            5 => Ok(DataType::EncCtxKem),
+            6 => Ok(DataType::EncCtxKdf),
+            7 => Ok(DataType::EncCtxAead),
+            8 => Ok(DataType::EndCiphersuite),
+            9 => Ok(DataType::CiphersuiteAck),
+            10 => Ok(DataType::Plaintext),
+            11 => Ok(DataType::EncCtxMode),
+            12 => Ok(DataType::PskId),
+            13 => Ok(DataType::ClientPublicKey),
+            14 => Ok(DataType::SecondaryId),
+            15 => Ok(DataType::KeyRotationIndex),
+            16 => Ok(DataType::Envelope),
+            17 => Ok(DataType::EndOfMessage),
+            18 => Ok(DataType::MessageAck),
+            19 => Ok(DataType::NegotiationFailed),
+            20 => Ok(DataType::ClientNonce),
+            21 => Ok(DataType::ServerKeySignature),
+            _ => Err(UnknownDataType(i))
+        }
+    }
+}
+
+// Rende un elemento di DataType printabile
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataType::PublicKey => write!(f, "PublicKey"),
+            DataType::EncappedKey => write!(f, "EncappedKey"),
+            DataType::Ciphertext => write!(f, "CipherText"),
+            DataType::AssociatedData => write!(f, "AssociatedData"),
+            DataType::TagBytes => write!(f, "TagBytes"),
+            DataType::EncCtxKem => write!(f, "EncCtxKem"),
+            DataType::EncCtxKdf => write!(f, "EncCtxKdf"),
+            DataType::EncCtxAead => write!(f, "EncCtxAead"),
+            DataType::EndCiphersuite => write!(f, "EndCiphersuite"),
+            DataType::CiphersuiteAck => write!(f, "CiphersuiteAck"),
+            DataType::Plaintext => write!(f, "Plaintext"),
+            DataType::EncCtxMode => write!(f, "EncCtxMode"),
+            DataType::PskId => write!(f, "PskId"),
+            DataType::ClientPublicKey => write!(f, "ClientPublicKey"),
+            DataType::SecondaryId => write!(f, "SecondaryId"),
+            DataType::KeyRotationIndex => write!(f, "KeyRotationIndex"),
+            DataType::Envelope => write!(f, "Envelope"),
+            DataType::EndOfMessage => write!(f, "EndOfMessage"),
+            DataType::MessageAck => write!(f, "MessageAck"),
+            DataType::NegotiationFailed => write!(f, "NegotiationFailed"),
+            DataType::ClientNonce => write!(f, "ClientNonce"),
+            DataType::ServerKeySignature => write!(f, "ServerKeySignature"),
+        }
+    }
+}
+
+// Errore per un pacchetto valido ma non atteso in questa fase del protocollo
+pub fn unexpected_packet(dt: DataType) -> HpkeProtoError {
+    HpkeProtoError::framing(format!("pacchetto {} inatteso", dt))
+}
+
+// Dimensione dell'header: 1 byte di DataType + 4 byte di lunghezza (u32 big endian)
+pub const HEADER_LEN: usize = 5;
+
+// Lunghezza massima accettata per un payload: evita che un peer
+// faccia allocare al decoder buffer arbitrariamente grandi
+pub const MAX_PAYLOAD_LEN: usize = 1 << 20;
+
+// Header del pacchetto: [DataType|Len]
+pub struct HeaderData {
+    data_type: DataType,
+    data_len: usize
+}
+
+// Pacchetto: [Header|Payload]
+pub struct DataPacket {
+    header: HeaderData,
+    payload: Vec<u8>
+}
+
+impl DataPacket {
+    // Organizza in un unico vettore l'intero pacchetto
+    pub fn group(&self) -> Result<Vec<u8>, HpkeProtoError> {
+        // Restituisce un vec<u8>: header|payload
+        frame(self.header.data_type, &self.payload[..self.header.data_len])
+    }
+}
+
+// Crea un pacchetto
+pub fn create_packet(dt: DataType, data: Vec<u8>) -> DataPacket {
+    let head = HeaderData {
+        data_type: dt,
+        data_len: data.len()
+    };
+    DataPacket {
+        header: head,
+        payload: data
+    }
+}
+
+// Serializza un pacchetto: [DataType|len (u32 BE)|payload].
+// Un payload oltre MAX_PAYLOAD_LEN verrebbe rifiutato dal peer: errore già qui
+pub fn frame(dt: DataType, payload: &[u8]) -> Result<Vec<u8>, HpkeProtoError> {
+    if payload.len() > MAX_PAYLOAD_LEN {
+        return Err(HpkeProtoError::framing(
+            format!("payload di {} byte oltre il limite di {}", payload.len(), MAX_PAYLOAD_LEN)
+        ));
+    }
+    let len = payload.len() as u32;
+
+    let mut pack = Vec::with_capacity(HEADER_LEN + payload.len());
+    pack.push(datatype_to_int(&dt));
+    pack.extend_from_slice(&len.to_be_bytes());
+    pack.extend_from_slice(payload);
+    Ok(pack)
+}
+
+
+// Pacchetto ricostruito dal decoder
+pub struct ReceivedPacket {
+    pub data_type: DataType,
+    pub payload: Vec<u8>
+}
+
+// Decoder incrementale: accumula i byte letti dallo stream e restituisce
+// i pacchetti man mano che sono completi. Gestisce sia le letture parziali
+// sia più pacchetti arrivati con una sola read()
+#[derive(Default)]
+pub struct PacketDecoder {
+    buf: Vec<u8>
+}
+
+impl PacketDecoder {
+    pub fn new() -> PacketDecoder {
+        PacketDecoder { buf: vec![] }
+    }
+
+    // Aggiunge al buffer interno i byte appena letti
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    // Restituisce il prossimo pacchetto completo, se c'è
+    pub fn next_packet(&mut self) -> Result<Option<ReceivedPacket>, HpkeProtoError> {
+        match parse_packet(&self.buf)? {
+            Some((pack, used)) => {
+                self.buf.drain(..used);
+                Ok(Some(pack))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Byte ricevuti ma non ancora consumati
+    pub fn pending(&self) -> usize {
+        self.buf.len()
+    }
+
+    // Svuota il buffer e rende i byte non ancora consumati
+    pub fn take_pending(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+// Legge un pacchetto dall'inizio di buf, se è già completo.
+// Rende il pacchetto e il numero di byte consumati
+pub fn parse_packet(buf: &[u8]) -> Result<Option<(ReceivedPacket, usize)>, HpkeProtoError> {
+    if buf.len() < HEADER_LEN {
+        return Ok(None);
+    }
+
+    let data_type = DataType::try_from(buf[0])?;
+    let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+    if len > MAX_PAYLOAD_LEN {
+        return Err(HpkeProtoError::framing(
+            format!("pacchetto di {} byte oltre il limite di {}", len, MAX_PAYLOAD_LEN)
+        ));
+    }
+    if buf.len() < HEADER_LEN + len {
+        return Ok(None);
+    }
+
+    let payload = buf[HEADER_LEN..HEADER_LEN + len].to_vec();
+    Ok(Some((ReceivedPacket { data_type, payload }, HEADER_LEN + len)))
+}
+
+// Legge dallo stream finché non è disponibile un pacchetto completo.
+// Rende None se il peer ha chiuso la connessione
+pub fn read_packet<R: Read>(stream: &mut R, decoder: &mut PacketDecoder) -> Result<Option<ReceivedPacket>, HpkeProtoError> {
+    let mut data = [0u8; 1024];
+
+    loop {
+        if let Some(pack) = decoder.next_packet()? {
+            return Ok(Some(pack));
+        }
+
+        let bytes_read = stream.read(&mut data)?;
+        if bytes_read == 0 {
+            if decoder.pending() != 0 {
+                return Err(HpkeProtoError::closed("connessione chiusa a metà pacchetto"));
+            }
+            return Ok(None);
+        }
+        decoder.feed(&data[..bytes_read]);
+    }
+}
+
+// Se false le funzioni display_* non stampano niente (logging.packet_dump)
+static PACKET_DUMP: AtomicBool = AtomicBool::new(true);
+
+pub fn set_packet_dump(enabled: bool) {
+    PACKET_DUMP.store(enabled, Ordering::Relaxed);
+}
+
+fn packet_dump() -> bool {
+    PACKET_DUMP.load(Ordering::Relaxed)
+}
+
+// Printa un buffer
+pub fn display_buf(buf: &[u8]) {
+    if !packet_dump() { return; }
+    print!("data: ");
+    for i in buf { print!("{} ", i); }
+    println!();
+}
+
+
+// Printa un vettore
+pub fn display_vec(vec: &[u8]) {
+    if !packet_dump() { return; }
+    print!("vettore: ");
+    for i in vec { print!("{} ", i); }
+    print!("\n\n");
+}
+
+
+// Printa il pacchetto completo
+pub fn display_pack(pack: &[u8]) {
+    if !packet_dump() { return; }
+    match DataType::try_from(pack[0]) {
+        Ok(dtype) => print!("{}: ", dtype),
+        Err(e) => print!("{}: ", e),
+    }
+    for i in pack {
+        print!("{} ",i);
+    }
+    println!("\nlen: {}\n", pack.len());
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_rejects_oversized_payload() {
+        let payload = vec![0u8; MAX_PAYLOAD_LEN + 1];
+        assert!(matches!(frame(DataType::Ciphertext, &payload), Err(HpkeProtoError::Framing(_))));
+        assert_eq!(frame(DataType::Ciphertext, &payload[..MAX_PAYLOAD_LEN]).unwrap().len(), HEADER_LEN + MAX_PAYLOAD_LEN);
+        assert!(create_packet(DataType::Ciphertext, payload).group().is_err());
+    }
+
+    #[test]
+    fn decoder_header_split_across_reads() {
+        let pack = frame(DataType::PskId, b"client-1").unwrap();
+        let mut decoder = PacketDecoder::new();
+
+        // Header spezzato a metà, poi il resto dell'header, poi il payload
+        decoder.feed(&pack[..2]);
+        assert!(decoder.next_packet().unwrap().is_none());
+        decoder.feed(&pack[2..HEADER_LEN]);
+        assert!(decoder.next_packet().unwrap().is_none());
+        decoder.feed(&pack[HEADER_LEN..]);
+
+        let received = decoder.next_packet().unwrap().unwrap();
+        assert_eq!(received.data_type, DataType::PskId);
+        assert_eq!(received.payload, b"client-1");
+        assert_eq!(decoder.pending(), 0);
+    }
+
+    #[test]
+    fn decoder_several_packets_in_one_buffer() {
+        let mut buf = frame(DataType::Ciphertext, &[1, 2, 3]).unwrap();
+        buf.extend(frame(DataType::TagBytes, &[]).unwrap());
+        buf.extend(frame(DataType::EndOfMessage, &[EOM_ACK_REQUESTED]).unwrap());
+        // Inizio del pacchetto successivo, ancora incompleto
+        buf.extend(&frame(DataType::Plaintext, b"ciao").unwrap()[..3]);
+
+        let mut decoder = PacketDecoder::new();
+        decoder.feed(&buf);
+
+        let expected = [
+            (DataType::Ciphertext, vec![1, 2, 3]),
+            (DataType::TagBytes, vec![]),
+            (DataType::EndOfMessage, vec![EOM_ACK_REQUESTED])
+        ];
+        for (data_type, payload) in expected {
+            let received = decoder.next_packet().unwrap().unwrap();
+            assert_eq!(received.data_type, data_type);
+            assert_eq!(received.payload, payload);
+        }
+        assert!(decoder.next_packet().unwrap().is_none());
+        assert_eq!(decoder.pending(), 3);
+    }
+
+    #[test]
+    fn decoder_rejects_length_above_limit() {
+        let mut header = vec![datatype_to_int(&DataType::Ciphertext)];
+        header.extend_from_slice(&(MAX_PAYLOAD_LEN as u32 + 1).to_be_bytes());
+
+        // L'errore arriva con il solo header, senza aspettare il payload
+        let mut decoder = PacketDecoder::new();
+        decoder.feed(&header);
+        assert!(matches!(decoder.next_packet(), Err(HpkeProtoError::Framing(_))));
+    }
+
+    #[test]
+    fn decoder_rejects_unknown_type() {
+        let mut decoder = PacketDecoder::new();
+        decoder.feed(&[0xFF, 0, 0, 0, 0]);
+        assert!(matches!(decoder.next_packet(), Err(HpkeProtoError::UnknownType(UnknownDataType(0xFF)))));
+    }
+
+    #[test]
+    fn read_packet_reports_truncated_stream() {
+        let pack = frame(DataType::Ciphertext, &[9; 10]).unwrap();
+        let mut decoder = PacketDecoder::new();
+        let mut truncated = &pack[..pack.len() - 1];
+        assert!(read_packet(&mut truncated, &mut decoder).is_err());
+
+        let mut decoder = PacketDecoder::new();
+        let mut complete = &pack[..];
+        assert_eq!(read_packet(&mut complete, &mut decoder).unwrap().unwrap().payload, vec![9; 10]);
+        assert!(read_packet(&mut complete, &mut decoder).unwrap().is_none());
+    }
+}
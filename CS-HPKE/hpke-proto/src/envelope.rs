@@ -0,0 +1,243 @@
+// Busta single-shot: un messaggio HPKE completo in un solo pacchetto,
+// per le API single-shot dell'RFC 9180 (sezione 6). Essendo autocontenuta
+// (algoritmi compresi) può anche essere salvata su disco e aperta in seguito.
+//
+// +--------+--------+--------+---------+-----+---------+-----+------------+-----+
+// | KEM id | KDF id | AEAD id| enc len | enc | AAD len | AAD | ciphertext | tag |
+// |  u16   |  u16   |  u16   |   u16   |     |   u32   |     |            | 16B |
+// +--------+--------+--------+---------+-----+---------+-----+------------+-----+
+//
+// Tutti gli interi sono big endian; la lunghezza del ciphertext si ricava
+// da quella della busta.
+
+
+use rand::{CryptoRng, RngCore};
+
+use crate::agility::{
+    self, AgileEncappedKey, AgileHpkeError, AgileOpModeR, AgileOpModeS, AgilePrivateKey, AgilePublicKey
+};
+use crate::ciphersuite::{Algorithm, AEADtype, Ciphersuite, KDFtype, KEMtype};
+use crate::replay::EncappedKeyCache;
+use crate::error::HpkeProtoError;
+use crate::kri::TAG_LEN;
+
+// Tre id di algoritmo, lunghezza di enc e lunghezza dell'AAD
+const FIXED_LEN: usize = 2 + 2 + 2 + 2 + 4;
+
+
+fn malformed(what: &str) -> HpkeProtoError {
+    HpkeProtoError::framing(format!("busta malformata: {}", what))
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub suite: Ciphersuite,
+    pub encapped_key: Vec<u8>,
+    pub aad: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    pub tag: Vec<u8>
+}
+
+impl Envelope {
+    // Cifra il messaggio in modo single-shot verso la chiave del destinatario
+    pub fn seal<R: CryptoRng + RngCore>(
+        suite: &Ciphersuite,
+        mode: &AgileOpModeS,
+        pk_recip: &AgilePublicKey,
+        info: &[u8],
+        plaintext: &[u8],
+        aad: &[u8],
+        csprng: &mut R,
+    ) -> Result<Envelope, AgileHpkeError> {
+        let (encapped_key, ciphertext, tag) =
+            agility::agile_single_shot_seal(suite, mode, pk_recip, info, plaintext, aad, csprng)?;
+        Ok(Envelope {
+            suite: *suite,
+            encapped_key: encapped_key.bytes,
+            aad: aad.to_vec(),
+            ciphertext,
+            tag
+        })
+    }
+
+    // Apre la busta; una encapped key già vista viene rifiutata come replay
+    pub fn open(
+        &self,
+        mode: &AgileOpModeR,
+        sk_recip: &AgilePrivateKey,
+        info: &[u8],
+        ek_cache: &mut EncappedKeyCache,
+    ) -> Result<Vec<u8>, AgileHpkeError> {
+        let encapped_key = AgileEncappedKey { kem: self.suite.kem, bytes: self.encapped_key.clone() };
+        let plaintext = agility::agile_single_shot_open(
+            &self.suite,
+            mode,
+            sk_recip,
+            &encapped_key,
+            info,
+            &self.ciphertext,
+            &self.aad,
+            &self.tag
+        )?;
+        // Si registra solo una busta autentica, così i falsi non riempiono la cache
+        ek_cache.check_and_insert(&self.encapped_key)?;
+        Ok(plaintext)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, HpkeProtoError> {
+        let enc_len = u16::try_from(self.encapped_key.len()).map_err(|_| malformed("enc troppo lunga"))?;
+        let aad_len = u32::try_from(self.aad.len()).map_err(|_| malformed("AAD troppo lungo"))?;
+        if self.tag.len() != TAG_LEN {
+            return Err(malformed("lunghezza del tag"));
+        }
+
+        let mut out = Vec::with_capacity(FIXED_LEN + self.encapped_key.len() + self.aad.len() + self.ciphertext.len() + TAG_LEN);
+        out.extend_from_slice(&self.suite.kem.to_bytes());
+        out.extend_from_slice(&self.suite.kdf.to_bytes());
+        out.extend_from_slice(&self.suite.aead.to_bytes());
+        out.extend_from_slice(&enc_len.to_be_bytes());
+        out.extend_from_slice(&self.encapped_key);
+        out.extend_from_slice(&aad_len.to_be_bytes());
+        out.extend_from_slice(&self.aad);
+        out.extend_from_slice(&self.ciphertext);
+        out.extend_from_slice(&self.tag);
+        Ok(out)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Envelope, HpkeProtoError> {
+        let mut rest = bytes;
+        let suite = Ciphersuite {
+            kem: KEMtype::from_bytes(take(&mut rest, 2)?)?,
+            kdf: KDFtype::from_bytes(take(&mut rest, 2)?)?,
+            aead: AEADtype::from_bytes(take(&mut rest, 2)?)?
+        };
+        let enc_len = u16::from_be_bytes(take(&mut rest, 2)?.try_into().unwrap()) as usize;
+        let encapped_key = take(&mut rest, enc_len)?.to_vec();
+        let aad_len = u32::from_be_bytes(take(&mut rest, 4)?.try_into().unwrap()) as usize;
+        let aad = take(&mut rest, aad_len)?.to_vec();
+        let ct_len = rest.len().checked_sub(TAG_LEN).ok_or_else(|| malformed("tag mancante"))?;
+        let ciphertext = take(&mut rest, ct_len)?.to_vec();
+        let tag = rest.to_vec();
+        Ok(Envelope { suite, encapped_key, aad, ciphertext, tag })
+    }
+}
+
+// Preleva i prossimi n byte, o errore se la busta è troncata
+fn take<'a>(rest: &mut &'a [u8], n: usize) -> Result<&'a [u8], HpkeProtoError> {
+    if rest.len() < n {
+        return Err(malformed("troncata"));
+    }
+    let (head, tail) = rest.split_at(n);
+    *rest = tail;
+    Ok(head)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::agility::AgileKeypair;
+
+    const INFO: &[u8] = b"envelope test";
+
+    fn suite() -> Ciphersuite {
+        Ciphersuite {
+            kem: KEMtype::X25519HkdfSha256,
+            kdf: KDFtype::HkdfSha256,
+            aead: AEADtype::AesGcm128
+        }
+    }
+
+    fn sealed(plaintext: &[u8]) -> (AgileKeypair, Envelope) {
+        let mut csprng = StdRng::from_entropy();
+        let keypair = agility::agile_gen_keypair(suite().kem, &mut csprng).unwrap();
+        let envelope = Envelope::seal(
+            &suite(),
+            &AgileOpModeS::Base,
+            keypair.public_key(),
+            INFO,
+            plaintext,
+            b"associated data",
+            &mut csprng
+        ).unwrap();
+        (keypair, envelope)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (keypair, envelope) = sealed(b"messaggio");
+        let bytes = envelope.to_bytes().unwrap();
+        assert_eq!(bytes.len(), FIXED_LEN + envelope.encapped_key.len() + envelope.aad.len() + b"messaggio".len() + TAG_LEN);
+
+        let parsed = Envelope::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, envelope);
+        let mut ek_cache = EncappedKeyCache::default();
+        let plaintext = parsed.open(&AgileOpModeR::Base, keypair.private_key(), INFO, &mut ek_cache).unwrap();
+        assert_eq!(plaintext, b"messaggio");
+    }
+
+    #[test]
+    fn empty_plaintext_round_trip() {
+        let (keypair, envelope) = sealed(b"");
+        let parsed = Envelope::from_bytes(&envelope.to_bytes().unwrap()).unwrap();
+        assert!(parsed.ciphertext.is_empty());
+        let mut ek_cache = EncappedKeyCache::default();
+        assert!(parsed.open(&AgileOpModeR::Base, keypair.private_key(), INFO, &mut ek_cache).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_envelope_is_rejected() {
+        let (keypair, envelope) = sealed(b"messaggio");
+        let bytes = envelope.to_bytes().unwrap();
+        // Senza tutti i campi prima del ciphertext e senza il tag la busta non si legge
+        let header_len = FIXED_LEN + envelope.encapped_key.len() + envelope.aad.len();
+        for len in 0..header_len + TAG_LEN {
+            assert!(Envelope::from_bytes(&bytes[..len]).is_err(), "accettata una busta di {} byte", len);
+        }
+        // Un taglio nel ciphertext si legge, ma il tag non torna
+        let parsed = Envelope::from_bytes(&bytes[..bytes.len() - 1]).unwrap();
+        let mut ek_cache = EncappedKeyCache::default();
+        assert!(parsed.open(&AgileOpModeR::Base, keypair.private_key(), INFO, &mut ek_cache).is_err());
+    }
+
+    #[test]
+    fn wrong_tag_length_is_not_serialized() {
+        let (_, mut envelope) = sealed(b"messaggio");
+        envelope.tag.pop();
+        assert!(envelope.to_bytes().is_err());
+    }
+
+    #[test]
+    fn tampered_envelope_does_not_open() {
+        let (keypair, envelope) = sealed(b"messaggio");
+        let bytes = envelope.to_bytes().unwrap();
+        let mut ek_cache = EncappedKeyCache::default();
+
+        // Un bit cambiato in enc, AAD, ciphertext o tag invalida la busta
+        let aad_start = FIXED_LEN + envelope.encapped_key.len();
+        for i in [FIXED_LEN, aad_start, aad_start + envelope.aad.len(), bytes.len() - 1] {
+            let mut tampered = bytes.clone();
+            tampered[i] ^= 0x01;
+            let parsed = Envelope::from_bytes(&tampered).unwrap();
+            assert!(parsed.open(&AgileOpModeR::Base, keypair.private_key(), INFO, &mut ek_cache).is_err());
+        }
+        // Le buste false non entrano nella cache: l'originale si apre ancora
+        assert!(ek_cache.is_empty());
+        assert!(envelope.open(&AgileOpModeR::Base, keypair.private_key(), INFO, &mut ek_cache).is_ok());
+    }
+
+    #[test]
+    fn replayed_envelope_is_rejected() {
+        let (keypair, envelope) = sealed(b"messaggio");
+        let mut ek_cache = EncappedKeyCache::default();
+        assert!(envelope.open(&AgileOpModeR::Base, keypair.private_key(), INFO, &mut ek_cache).is_ok());
+        assert!(matches!(
+            envelope.open(&AgileOpModeR::Base, keypair.private_key(), INFO, &mut ek_cache),
+            Err(AgileHpkeError::Replay(_))
+        ));
+    }
+}
@@ -0,0 +1,105 @@
+// Errore unico del protocollo, reso da tutte le funzioni della libreria.
+// Nessun errore di un peer deve far cadere il processo: il chiamante
+// decide se chiudere la sola connessione coinvolta.
+
+use std::fmt::Display;
+use std::io::{Error, ErrorKind};
+
+use thiserror::Error;
+
+use crate::agility::AgileHpkeError;
+use crate::ciphersuite::AlgorithmCategory;
+use crate::data_packets_manager::UnknownDataType;
+use crate::replay::ReplayError;
+
+
+#[derive(Debug, Error)]
+pub enum HpkeProtoError {
+    // Errore del trasporto, compresa la chiusura inattesa della connessione
+    #[error("errore di I/O: {0}")]
+    Io(#[from] Error),
+    // Pacchetto malformato o non atteso in questa fase del protocollo
+    #[error("pacchetto non valido: {0}")]
+    Framing(String),
+    // Il primo byte del pacchetto non corrisponde a nessun DataType
+    #[error(transparent)]
+    UnknownType(#[from] UnknownDataType),
+    // Client e server non hanno trovato un accordo, o il peer non è autorizzato
+    #[error("negoziazione fallita: {0}")]
+    Negotiation(String),
+    // Alert NegotiationFailed: nessun algoritmo in comune nelle categorie elencate
+    #[error("negoziazione fallita: nessun algoritmo in comune per {}", list_categories(.0))]
+    NegotiationFailed(Vec<AlgorithmCategory>),
+    // Cifratura, decifratura, replay e derivazione delle chiavi
+    #[error("errore crittografico: {0}")]
+    Crypto(#[from] AgileHpkeError),
+    // Archivio delle chiavi su disco non valido, illeggibile o con permessi troppo larghi
+    #[error("archivio delle chiavi: {0}")]
+    KeyStore(String),
+    // La chiave pubblica del server non corrisponde a quella attesa
+    #[error("chiave del server non attendibile: {0}")]
+    UntrustedServerKey(String),
+    // File delle chiavi fidate o impronta non validi
+    #[error("chiavi fidate: {0}")]
+    TrustStore(String),
+    // File di configurazione illeggibile o con valori non validi
+    #[error("configurazione non valida: {0}")]
+    Config(String),
+    // Testo ricevuto non in UTF-8
+    #[error("testo non UTF-8: {0}")]
+    Utf8(#[from] std::string::FromUtf8Error),
+}
+
+// Errore all'avvio di un binario: come per una configurazione non valida,
+// stampa il messaggio e termina il processo senza panic
+pub fn exit_on_error<T, E: Display>(result: Result<T, E>, what: &str) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            eprintln!("{}: {}", what, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn list_categories(categories: &[AlgorithmCategory]) -> String {
+    categories.iter().map(|c| c.to_string()).collect::<Vec<String>>().join(", ")
+}
+
+impl From<ReplayError> for HpkeProtoError {
+    fn from(e: ReplayError) -> HpkeProtoError {
+        HpkeProtoError::Crypto(AgileHpkeError::Replay(e))
+    }
+}
+
+impl HpkeProtoError {
+    pub fn framing(what: impl Into<String>) -> HpkeProtoError {
+        HpkeProtoError::Framing(what.into())
+    }
+
+    pub fn negotiation(what: impl Into<String>) -> HpkeProtoError {
+        HpkeProtoError::Negotiation(what.into())
+    }
+
+    pub fn key_store(what: impl Into<String>) -> HpkeProtoError {
+        HpkeProtoError::KeyStore(what.into())
+    }
+
+    pub fn trust_store(what: impl Into<String>) -> HpkeProtoError {
+        HpkeProtoError::TrustStore(what.into())
+    }
+
+    pub fn config(what: impl Into<String>) -> HpkeProtoError {
+        HpkeProtoError::Config(what.into())
+    }
+
+    // Il peer ha chiuso la connessione prima di quanto richiesto dal protocollo
+    pub fn closed(what: &str) -> HpkeProtoError {
+        HpkeProtoError::Io(Error::new(ErrorKind::UnexpectedEof, what.to_string()))
+    }
+
+    // Il peer non ha risposto, o non ha letto, entro il timeout configurato
+    pub fn timed_out(what: &str) -> HpkeProtoError {
+        HpkeProtoError::Io(Error::new(ErrorKind::TimedOut, what.to_string()))
+    }
+}
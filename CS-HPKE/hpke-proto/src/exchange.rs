@@ -0,0 +1,516 @@
+// Scambio dei messaggi dopo l'handshake, tra client e server principali.
+// - MessageSender: lato client, cifra ogni messaggio con la chiave dell'epoca
+//   corrente della sessione, o in una busta single-shot, e ne aspetta l'eco.
+// - MessageReceiver: lato server, apre la sessione all'arrivo della encapped
+//   key, decifra i messaggi e li rimanda al client.
+// Come per l'handshake, le versioni async valgono su qualsiasi PacketStream
+// e quelle bloccanti ne sono wrapper. Il timeout di lettura vale per ogni
+// pacchetto atteso, non per l'intera sessione.
+
+use std::net::TcpStream;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use futures_util::{Stream, StreamExt};
+use rand::{rngs::StdRng, SeedableRng};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::agility::{AgileEncappedKey, AgileHpkeError, AgileKeypair, AgileOpModeR, AgileOpModeS, AgilePublicKey};
+use crate::ciphersuite::Ciphersuite;
+use crate::data_packets_manager::{self, DataType, PacketDecoder, unexpected_packet};
+use crate::envelope::Envelope;
+use crate::error::HpkeProtoError;
+use crate::handshake::{ack_requested, handle_packet, send_message_ack_async};
+use crate::kri::{EpochReceiver, EpochSender, KriHeader, RekeyPolicy};
+use crate::replay::EncappedKeyCache;
+use crate::secondary::{self, SealedMessage};
+use crate::session::{ReceiverSession, SenderSession};
+use crate::transport::{self, PacketStream};
+
+
+// ##### LATO CLIENT #####
+
+// Mittente dei messaggi di una sessione già aperta: la encapped key è già
+// stata inviata. info è già legato al transcript della negoziazione
+pub struct MessageSender<'a> {
+    suite: Ciphersuite,
+    mode: &'a AgileOpModeS,
+    server_pk: &'a AgilePublicKey,
+    info: &'a [u8],
+    associated_data: &'a [u8],
+    sender: EpochSender<SenderSession>,
+    // Con single_shot ogni messaggio viaggia in una busta autocontenuta
+    single_shot: bool,
+    csprng: StdRng
+}
+
+impl<'a> MessageSender<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        session: SenderSession,
+        suite: &Ciphersuite,
+        mode: &'a AgileOpModeS,
+        server_pk: &'a AgilePublicKey,
+        info: &'a [u8],
+        associated_data: &'a [u8],
+        rekey: RekeyPolicy,
+        single_shot: bool
+    ) -> Result<MessageSender<'a>, HpkeProtoError> {
+        Ok(MessageSender {
+            suite: *suite,
+            mode,
+            server_pk,
+            info,
+            associated_data,
+            sender: EpochSender::new(session, suite.aead, rekey)?,
+            single_shot,
+            csprng: StdRng::from_entropy()
+        })
+    }
+
+    // Invia un messaggio e rende l'eco del server, None se ha chiuso la connessione
+    pub async fn exchange<S>(&mut self, stream: &mut PacketStream<S>, msg: &[u8]) -> Result<Option<Vec<u8>>, HpkeProtoError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin
+    {
+        if self.single_shot {
+            // => Envelope: un solo pacchetto per messaggio, senza EndOfMessage
+            let envelope = Envelope::seal(
+                &self.suite,
+                self.mode,
+                self.server_pk,
+                self.info,
+                msg,
+                self.associated_data,
+                &mut self.csprng
+            )?;
+            let pack = data_packets_manager::create_packet(DataType::Envelope, envelope.to_bytes()?);
+            transport::send_packet(stream, pack, "Envelope").await?;
+        } else {
+            // => KRI, Ciphertext, AssociatedData, Tag ed EndOfMessage in pipeline
+            let (header, ciphertext, tag) = self.sender.seal(msg, self.associated_data)?;
+            println!("Messaggio numero {} dell'epoca {}", header.seq, header.kri);
+            secondary::send_sealed_async(stream, SealedMessage {
+                kri: Some(header),
+                ciphertext,
+                associated_data: self.associated_data.to_vec(),
+                tag
+            }, false).await?;
+        }
+
+        match transport::read_packet(stream).await? {
+            Some(pack) if pack.data_type == DataType::Plaintext => Ok(Some(pack.payload)),
+            Some(pack) => Err(unexpected_packet(pack.data_type)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn show_reply(reply: &[u8]) {
+    println!("Il server ha inviato: {}", String::from_utf8_lossy(reply));
+}
+
+// Invia i messaggi uno alla volta e stampa l'eco del server.
+// Termina quando i messaggi finiscono o il server chiude la connessione
+pub async fn send_messages_async<S, M>(
+    stream: &mut PacketStream<S>,
+    sender: &mut MessageSender<'_>,
+    mut messages: M
+) -> Result<(), HpkeProtoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    M: Stream<Item = Vec<u8>> + Unpin
+{
+    while let Some(msg) = messages.next().await {
+        match sender.exchange(stream, &msg).await? {
+            Some(reply) => show_reply(&reply),
+            None => return Ok(()),
+        }
+    }
+    Ok(())
+}
+
+// Versione bloccante di send_messages_async: next_message rende None a fine input.
+// L'attesa del messaggio successivo non rientra nel timeout di lettura
+pub fn send_messages<F>(
+    stream: &TcpStream,
+    decoder: &mut PacketDecoder,
+    sender: &mut MessageSender<'_>,
+    mut next_message: F
+) -> Result<(), HpkeProtoError>
+where
+    F: FnMut() -> Result<Option<Vec<u8>>, HpkeProtoError>
+{
+    while let Some(msg) = next_message()? {
+        let reply = transport::block_on(stream, decoder, |blocking, stream| blocking.wait(sender.exchange(stream, &msg)))?;
+        match reply {
+            Some(reply) => show_reply(&reply),
+            None => return Ok(()),
+        }
+    }
+    Ok(())
+}
+
+
+// ##### LATO SERVER #####
+
+// La cache resta valida anche se un altro thread è andato in panic
+// tenendo il lock: la connessione prosegue invece di cadere a sua volta
+fn lock_cache(ek_cache: &Mutex<EncappedKeyCache>) -> MutexGuard<'_, EncappedKeyCache> {
+    ek_cache.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Destinatario dei messaggi di un client, dalla encapped key alla chiusura.
+// info è già legato al transcript della negoziazione
+pub struct MessageReceiver<'a> {
+    suite: Ciphersuite,
+    mode: &'a AgileOpModeR,
+    keypair: &'a AgileKeypair,
+    info: &'a [u8],
+    rekey: RekeyPolicy,
+    // Encapped key delle sessioni già aperte, condivise tra tutte le connessioni
+    ek_cache: &'a Mutex<EncappedKeyCache>,
+    // Sessione aperta dall'arrivo della encapped key, una per connessione
+    receiver: Option<EpochReceiver<ReceiverSession>>,
+    // Campi del messaggio in corso, completo solo all'arrivo di EndOfMessage
+    kri: Option<KriHeader>,
+    ct: Option<Vec<u8>>,
+    ad: Option<Vec<u8>>,
+    tb: Option<Vec<u8>>
+}
+
+impl<'a> MessageReceiver<'a> {
+    pub fn new(
+        suite: &Ciphersuite,
+        mode: &'a AgileOpModeR,
+        keypair: &'a AgileKeypair,
+        info: &'a [u8],
+        rekey: RekeyPolicy,
+        ek_cache: &'a Mutex<EncappedKeyCache>
+    ) -> MessageReceiver<'a> {
+        MessageReceiver {
+            suite: *suite,
+            mode,
+            keypair,
+            info,
+            rekey,
+            ek_cache,
+            receiver: None,
+            kri: None,
+            ct: None,
+            ad: None,
+            tb: None
+        }
+    }
+
+    // Gestisce il prossimo pacchetto del client; false se ha chiuso la connessione.
+    // on_session viene chiamata una volta, appena la sessione è aperta
+    pub async fn step<S, F>(&mut self, stream: &mut PacketStream<S>, on_session: &mut F) -> Result<bool, HpkeProtoError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        F: FnMut(&ReceiverSession) -> Result<(), HpkeProtoError>
+    {
+        let pack = match transport::read_packet(stream).await? {
+            Some(pack) => pack,
+            None => return Ok(false),
+        };
+
+        match pack.data_type {
+            // Richiesta della chiave pubblica
+            DataType::PublicKey => {
+                let pack = data_packets_manager::create_packet(DataType::PublicKey, self.keypair.public_key().bytes.clone());
+                transport::send_packet(stream, pack, "Chiave pubblica server").await?;
+            }
+            // Arrivo della Encapped Key: decap() una sola volta per sessione
+            DataType::EncappedKey if self.receiver.is_none() => {
+                handle_packet(&pack);
+                let encapped_key = AgileEncappedKey {
+                    kem: self.suite.kem,
+                    bytes: pack.payload
+                };
+                let session = ReceiverSession::new(
+                    &self.suite,
+                    self.mode,
+                    self.keypair.private_key(),
+                    &encapped_key,
+                    self.info
+                )?;
+                // Una sessione già vista riprodotta per intero va rifiutata.
+                // Come per le buste, si registra solo una encapped key valida
+                lock_cache(self.ek_cache).check_and_insert(&encapped_key.bytes)?;
+                println!("Sessione HPKE aperta");
+
+                on_session(&session)?;
+                self.receiver = Some(EpochReceiver::new(session, self.suite.aead, self.rekey)?);
+            }
+            // Arrivo di una busta single-shot: si apre e si risponde subito
+            DataType::Envelope => {
+                handle_packet(&pack);
+                let envelope = Envelope::from_bytes(&pack.payload)?;
+                if envelope.suite != self.suite {
+                    return Err(HpkeProtoError::negotiation("busta con una ciphersuite diversa da quella negoziata"));
+                }
+                // Il lock della cache non va tenuto durante l'invio
+                let opened = envelope.open(self.mode, self.keypair.private_key(), self.info, &mut lock_cache(self.ek_cache));
+                match opened {
+                    Ok(decrypted_msg) => self.reply(stream, decrypted_msg).await?,
+                    Err(AgileHpkeError::Replay(e)) => println!("Busta rifiutata: {}", e),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            // Arrivo del KRI, che precede gli altri pacchetti del messaggio
+            DataType::KeyRotationIndex => {
+                handle_packet(&pack);
+                self.kri = Some(KriHeader::from_bytes(&pack.payload)?);
+            }
+            DataType::Ciphertext => {
+                handle_packet(&pack);
+                self.ct = Some(pack.payload);
+            }
+            DataType::AssociatedData => {
+                handle_packet(&pack);
+                self.ad = Some(pack.payload);
+            }
+            DataType::TagBytes => {
+                handle_packet(&pack);
+                self.tb = Some(pack.payload);
+            }
+            // Fine del messaggio: si decripta quanto arrivato finora
+            DataType::EndOfMessage => {
+                handle_packet(&pack);
+                /* Il messaggio ricevuto viene mandato indietro
+                al client per verificare che sia corretto */
+                if let Some(decrypted_msg) = self.open_message()? {
+                    self.reply(stream, decrypted_msg).await?;
+                }
+                if ack_requested(&pack)? {
+                    send_message_ack_async(stream).await?;
+                }
+            }
+            other => return Err(unexpected_packet(other)),
+        }
+        Ok(true)
+    }
+
+    // Decripta un messaggio completo con la chiave dell'epoca indicata dal KRI.
+    // Rende None se il messaggio è un replay: viene scartato senza rispondere
+    fn open_message(&mut self) -> Result<Option<Vec<u8>>, HpkeProtoError> {
+        let incomplete = |what: &str| HpkeProtoError::framing(format!("messaggio senza {}", what));
+        let receiver = self.receiver.as_mut()
+            .ok_or_else(|| HpkeProtoError::framing("messaggio ricevuto prima della encapped key"))?;
+        let header = self.kri.take().ok_or_else(|| incomplete("KRI"))?;
+        let ct = self.ct.take().ok_or_else(|| incomplete("CipherText"))?;
+        let ad = self.ad.take().ok_or_else(|| incomplete("AssociatedData"))?;
+        let tb = self.tb.take().ok_or_else(|| incomplete("TagBytes"))?;
+
+        match receiver.open(&header, &ct, &ad, &tb) {
+            Ok(msg) => {
+                println!("Messaggio numero {} dell'epoca {}", header.seq, header.kri);
+                Ok(Some(msg))
+            }
+            // Il replay viene scartato senza rispondere, la sessione resta aperta
+            Err(AgileHpkeError::Replay(e)) => {
+                println!("Messaggio rifiutato: {}", e);
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn reply<S>(&self, stream: &mut PacketStream<S>, msg: Vec<u8>) -> Result<(), HpkeProtoError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin
+    {
+        transport::send_packet(stream, data_packets_manager::create_packet(DataType::Plaintext, msg), "Plaintext").await?;
+        println!("Ho riscritto al client");
+        Ok(())
+    }
+}
+
+// Serve i messaggi del client fino alla chiusura della connessione
+pub async fn serve_messages_async<S, F>(
+    stream: &mut PacketStream<S>,
+    receiver: &mut MessageReceiver<'_>,
+    mut on_session: F
+) -> Result<(), HpkeProtoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnMut(&ReceiverSession) -> Result<(), HpkeProtoError>
+{
+    while receiver.step(stream, &mut on_session).await? {}
+    Ok(())
+}
+
+// Versione bloccante di serve_messages_async
+pub fn serve_messages<F>(
+    stream: &TcpStream,
+    decoder: &mut PacketDecoder,
+    receiver: &mut MessageReceiver<'_>,
+    mut on_session: F
+) -> Result<(), HpkeProtoError>
+where
+    F: FnMut(&ReceiverSession) -> Result<(), HpkeProtoError>
+{
+    transport::block_on(stream, decoder, |blocking, stream| {
+        while blocking.wait(receiver.step(stream, &mut on_session))? {}
+        Ok(())
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use futures_util::{future, stream};
+    use tokio::io;
+    use tokio::runtime::Builder;
+
+    use crate::agility;
+    use crate::ciphersuite::{AEADtype, KDFtype, KEMtype};
+
+    const INFO: &[u8] = b"exchange test";
+    const AAD: &[u8] = b"associated data";
+    const SUITE: Ciphersuite = Ciphersuite { kem: KEMtype::X25519HkdfSha256, kdf: KDFtype::HkdfSha256, aead: AEADtype::AesGcm128 };
+
+    fn session(keypair: &AgileKeypair) -> (SenderSession, AgileEncappedKey) {
+        let mut csprng = StdRng::from_entropy();
+        SenderSession::new(&SUITE, &AgileOpModeS::Base, keypair.public_key(), INFO, &mut csprng).unwrap()
+    }
+
+    type ClientResult = Result<Vec<Vec<u8>>, HpkeProtoError>;
+    type ServerResult = Result<(), HpkeProtoError>;
+
+    // Client e server collegati da un canale in memoria. Il client invia la
+    // encapped key solo con send_ek, poi i messaggi uno alla volta.
+    // Rende gli echi ricevuti, l'esito del server e le sessioni aperte
+    fn run(single_shot: bool, send_ek: bool, messages: &[&[u8]]) -> (ClientResult, ServerResult, usize) {
+        let keypair = agility::agile_gen_keypair(SUITE.kem, &mut StdRng::from_entropy()).unwrap();
+        let ek_cache = Mutex::new(EncappedKeyCache::default());
+        let (client_io, server_io) = io::duplex(64 * 1024);
+        let mut opened = 0;
+
+        let client = async {
+            let mut stream = transport::packet_stream(client_io);
+            let (session, encapped_key) = session(&keypair);
+            if send_ek {
+                let pack = data_packets_manager::create_packet(DataType::EncappedKey, encapped_key.bytes);
+                transport::send_packet(&mut stream, pack, "EncappedKey").await?;
+            }
+            let mut sender = MessageSender::new(
+                session,
+                &SUITE,
+                &AgileOpModeS::Base,
+                keypair.public_key(),
+                INFO,
+                AAD,
+                RekeyPolicy::default(),
+                single_shot
+            )?;
+            let mut replies = vec![];
+            for msg in messages {
+                match sender.exchange(&mut stream, msg).await? {
+                    Some(reply) => replies.push(reply),
+                    None => break,
+                }
+            }
+            Ok(replies)
+        };
+        let server = async {
+            let mut stream = transport::packet_stream(server_io);
+            let mut receiver = MessageReceiver::new(&SUITE, &AgileOpModeR::Base, &keypair, INFO, RekeyPolicy::default(), &ek_cache);
+            serve_messages_async(&mut stream, &mut receiver, |_| {
+                opened += 1;
+                Ok(())
+            }).await
+        };
+
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let (client, server) = runtime.block_on(future::join(client, server));
+        (client, server, opened)
+    }
+
+    #[test]
+    fn session_messages_are_echoed() {
+        let messages: [&[u8]; 3] = [b"primo", b"secondo", b""];
+        let (client, server, opened) = run(false, true, &messages);
+        assert_eq!(client.unwrap(), messages);
+        server.unwrap();
+        assert_eq!(opened, 1);
+    }
+
+    #[test]
+    fn single_shot_messages_are_echoed() {
+        let messages: [&[u8]; 2] = [b"primo", b"secondo"];
+        let (client, server, _) = run(true, true, &messages);
+        assert_eq!(client.unwrap(), messages);
+        server.unwrap();
+    }
+
+    #[test]
+    fn message_before_the_encapped_key_is_rejected() {
+        let (client, server, opened) = run(false, false, &[b"primo"]);
+        // Il server chiude la connessione: il client non riceve nessun eco
+        assert!(client.unwrap().is_empty());
+        assert!(matches!(server, Err(HpkeProtoError::Framing(_))));
+        assert_eq!(opened, 0);
+    }
+
+    #[test]
+    fn message_stream_ends_the_exchange() {
+        let keypair = agility::agile_gen_keypair(SUITE.kem, &mut StdRng::from_entropy()).unwrap();
+        let ek_cache = Mutex::new(EncappedKeyCache::default());
+        let (client_io, server_io) = io::duplex(64 * 1024);
+
+        let client = async {
+            let mut stream = transport::packet_stream(client_io);
+            let (session, encapped_key) = session(&keypair);
+            let pack = data_packets_manager::create_packet(DataType::EncappedKey, encapped_key.bytes);
+            transport::send_packet(&mut stream, pack, "EncappedKey").await?;
+            let mut sender = MessageSender::new(session, &SUITE, &AgileOpModeS::Base, keypair.public_key(), INFO, AAD, RekeyPolicy::default(), false)?;
+            let messages = stream::iter(vec![b"primo".to_vec(), b"secondo".to_vec()]);
+            send_messages_async(&mut stream, &mut sender, messages).await
+        };
+        let server = async {
+            let mut stream = transport::packet_stream(server_io);
+            let mut receiver = MessageReceiver::new(&SUITE, &AgileOpModeR::Base, &keypair, INFO, RekeyPolicy::default(), &ek_cache);
+            serve_messages_async(&mut stream, &mut receiver, |_| Ok(())).await
+        };
+
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let (client, server) = runtime.block_on(future::join(client, server));
+        client.unwrap();
+        server.unwrap();
+    }
+
+    #[test]
+    fn blocking_wrappers_over_tcp() {
+        let keypair = agility::agile_gen_keypair(SUITE.kem, &mut StdRng::from_entropy()).unwrap();
+        let server_keypair = keypair.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let ek_cache = Mutex::new(EncappedKeyCache::default());
+            let mut receiver = MessageReceiver::new(&SUITE, &AgileOpModeR::Base, &server_keypair, INFO, RekeyPolicy::default(), &ek_cache);
+            serve_messages(&stream, &mut PacketDecoder::new(), &mut receiver, |_| Ok(()))
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let (session, encapped_key) = session(&keypair);
+        stream.write_all(&data_packets_manager::frame(DataType::EncappedKey, &encapped_key.bytes).unwrap()).unwrap();
+        let mut sender = MessageSender::new(session, &SUITE, &AgileOpModeS::Base, keypair.public_key(), INFO, AAD, RekeyPolicy::default(), false).unwrap();
+
+        let mut messages = vec![b"secondo".to_vec(), b"primo".to_vec()];
+        send_messages(&stream, &mut PacketDecoder::new(), &mut sender, || Ok(messages.pop())).unwrap();
+        assert!(messages.is_empty());
+
+        // Fine dei messaggi: il client chiude e il server termina senza errori
+        drop(stream);
+        server.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,962 @@
+use std::fmt;
+use std::net::{TcpStream, SocketAddr};
+use std::io::Write;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::agility::{self, AgileKeypair, AgilePskBundle, AgilePublicKey};
+use crate::ciphersuite::{Algorithm, AlgorithmCategory, AEADtype, Ciphersuite, HpkeMode, KDFtype, KEMtype, SelectionPolicy, read_id};
+use crate::error::HpkeProtoError;
+use crate::psk::PskStore;
+use crate::data_packets_manager::{
+    self, DataType, EOM_ACK_REQUESTED, PacketDecoder, ReceivedPacket, display_vec, display_pack, read_packet,
+    unexpected_packet
+};
+use crate::transport::{self, PacketStream};
+use crate::signing::{self, SigningKey, VerifyingKey};
+use crate::transcript::{self, Transcript, TRANSCRIPT_HASH_LEN};
+use crate::trust::{AuthorizedClients, ServerKeyPolicy};
+
+
+// Invia un pacchetto senza aspettare risposta: i pacchetti di un messaggio
+// viaggiano uno dietro l'altro e il messaggio si chiude con EndOfMessage
+pub fn send_packet(mut stream: &TcpStream, pack: &[u8], what: &str) -> Result<(), HpkeProtoError> {
+    stream.write_all(pack)?;
+    println!("{} inviata", what);
+    Ok(())
+}
+
+
+// Stampa il pacchetto arrivato
+pub fn handle_packet(pack: &ReceivedPacket) {
+    println!("Arrivato {}", pack.data_type);
+    display_vec(&pack.payload);
+}
+
+
+// Chiude il messaggio; con ack_requested il peer risponde con MessageAck
+pub fn send_end_of_message(stream: &TcpStream, ack_requested: bool) -> Result<(), HpkeProtoError> {
+    let flags = if ack_requested { EOM_ACK_REQUESTED } else { 0 };
+    send_packet(stream, &data_packets_manager::frame(DataType::EndOfMessage, &[flags])?, "EndOfMessage")
+}
+
+// Legge i flag di EndOfMessage: true se il mittente vuole l'ack finale
+pub fn ack_requested(pack: &ReceivedPacket) -> Result<bool, HpkeProtoError> {
+    match pack.payload.as_slice() {
+        [flags] => Ok(flags & EOM_ACK_REQUESTED != 0),
+        _ => Err(HpkeProtoError::framing("EndOfMessage malformato")),
+    }
+}
+
+// Conferma la ricezione dell'intero messaggio
+pub fn send_message_ack(stream: &TcpStream) -> Result<(), HpkeProtoError> {
+    send_packet(stream, &data_packets_manager::frame(DataType::MessageAck, &[])?, "MessageAck")
+}
+
+// Aspetta l'ack finale di un messaggio inviato con ack_requested
+pub fn wait_message_ack(mut stream: &TcpStream, decoder: &mut PacketDecoder) -> Result<(), HpkeProtoError> {
+    match read_packet(&mut stream, decoder)? {
+        Some(pack) if pack.data_type == DataType::MessageAck => {
+            println!("Il peer ha ricevuto il messaggio");
+            Ok(())
+        }
+        Some(pack) => Err(unexpected_packet(pack.data_type)),
+        None => Err(HpkeProtoError::closed("connessione chiusa prima dell'ack")),
+    }
+}
+
+// Versioni async di send_end_of_message, send_message_ack e wait_message_ack.
+// EndOfMessage e MessageAck svuotano anche i pacchetti accodati prima
+pub async fn send_end_of_message_async<S>(stream: &mut PacketStream<S>, ack_requested: bool) -> Result<(), HpkeProtoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin
+{
+    let flags = if ack_requested { EOM_ACK_REQUESTED } else { 0 };
+    transport::send_packet(stream, data_packets_manager::create_packet(DataType::EndOfMessage, vec![flags]), "EndOfMessage").await
+}
+
+pub async fn send_message_ack_async<S>(stream: &mut PacketStream<S>) -> Result<(), HpkeProtoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin
+{
+    transport::send_packet(stream, data_packets_manager::create_packet(DataType::MessageAck, vec![]), "MessageAck").await
+}
+
+pub async fn wait_message_ack_async<S>(stream: &mut PacketStream<S>) -> Result<(), HpkeProtoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin
+{
+    match transport::read_packet(stream).await? {
+        Some(pack) if pack.data_type == DataType::MessageAck => {
+            println!("Il peer ha ricevuto il messaggio");
+            Ok(())
+        }
+        Some(pack) => Err(unexpected_packet(pack.data_type)),
+        None => Err(HpkeProtoError::closed("connessione chiusa prima dell'ack")),
+    }
+}
+
+
+// Aggiunge alla lista l'algoritmo contenuto nel payload.
+// Gli algoritmi sconosciuti vengono ignorati: il client può offrirne
+// di più di quelli che il server conosce
+fn push_cps<T: Algorithm + fmt::Debug>(vec: &mut Vec<T>, payload: &[u8]) -> Result<(), HpkeProtoError> {
+    match T::try_from(read_id(payload)?) {
+        Ok(alg) => vec.push(alg),
+        Err(e) => println!("Ignorato: {}", e),
+    }
+    println!("vettore: {:?}\n", vec);
+    Ok(())
+}
+
+
+// Risultato della negoziazione, uguale per client e server
+pub struct Negotiated {
+    pub suite: Ciphersuite,
+    pub mode: HpkeMode,
+    // PSK_ID scelto, presente solo nei modi Psk e AuthPsk
+    pub psk_id: Option<Vec<u8>>,
+    // Chiave statica del client, ricevuta dal server nei modi Auth e AuthPsk
+    pub client_pk: Option<AgilePublicKey>,
+    // Hash dei pacchetti della negoziazione, fino alla PublicKey del server
+    pub transcript: [u8; TRANSCRIPT_HASH_LEN]
+}
+
+impl Negotiated {
+    // Recupera dall'archivio la PSK associata al PSK_ID negoziato
+    pub fn psk_bundle(&self, psks: &PskStore) -> Result<Option<AgilePskBundle>, HpkeProtoError> {
+        match &self.psk_id {
+            Some(psk_id) => {
+                let psk = psks.get(psk_id).ok_or_else(|| HpkeProtoError::negotiation(
+                    "PSK_ID negoziato assente dall'archivio"
+                ))?;
+                Ok(Some(AgilePskBundle { psk: psk.to_vec(), psk_id: psk_id.clone() }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // info da passare a HPKE: lega le chiavi della sessione alla negoziazione
+    pub fn session_info(&self, info: &[u8]) -> Vec<u8> {
+        transcript::bind_info(info, &self.transcript)
+    }
+}
+
+
+// Crea, registra nel transcript e accoda un singolo pacchetto
+async fn send_single<S>(
+    stream: &mut PacketStream<S>,
+    transcript: &mut Transcript,
+    dt: DataType,
+    payload: Vec<u8>,
+    what: &str
+) -> Result<(), HpkeProtoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin
+{
+    let pack = data_packets_manager::create_packet(dt, payload);
+    transcript.record_sent(&pack)?;
+    transport::queue_packet(stream, pack, what).await
+}
+
+
+// Accoda la lista di algoritmi disponibili, uno per pacchetto
+async fn send_cps<S, T: Algorithm>(
+    stream: &mut PacketStream<S>,
+    transcript: &mut Transcript,
+    dt: DataType,
+    available: &[T],
+    what: &str
+) -> Result<(), HpkeProtoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin
+{
+    for alg in available {
+        let cps_pack = data_packets_manager::create_packet(dt, alg.to_bytes());
+        display_pack(&cps_pack.group()?);
+        transcript.record_sent(&cps_pack)?;
+        transport::queue_packet(stream, cps_pack, what).await?;
+    }
+    Ok(())
+}
+
+
+// ###################################################################
+// ########################## LATO CLIENT ############################
+// ###################################################################
+
+// Primary client initiates a request to the primary server.
+// The request contains a list of available ciphersuites for KEM, KDF, and AEAD,
+// the HPKE modes and the PSK_IDs known by the client (never the PSKs themselves).
+#[allow(clippy::too_many_arguments)]
+pub fn handle_server(
+    remote: SocketAddr,
+    stream: &mut TcpStream,
+    decoder: &mut PacketDecoder,
+    server_pk: &mut Vec<u8>,
+    available_kem_cps: &[KEMtype],
+    available_kdf_cps: &[KDFtype],
+    available_aead_cps: &[AEADtype],
+    available_modes: &[HpkeMode],
+    psks: &PskStore,
+    client_keys: &[AgileKeypair],
+    server_keys: &ServerKeyPolicy,
+    verify_key: Option<&VerifyingKey>,
+) -> Result<Negotiated, HpkeProtoError> {
+    transport::block_on(stream, decoder, |blocking, stream| blocking.wait(handle_server_async(
+        remote,
+        stream,
+        server_pk,
+        available_kem_cps,
+        available_kdf_cps,
+        available_aead_cps,
+        available_modes,
+        psks,
+        client_keys,
+        server_keys,
+        verify_key
+    )))
+}
+
+// Versione async di handle_server
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_server_async<S>(
+    remote: SocketAddr,
+    stream: &mut PacketStream<S>,
+    server_pk: &mut Vec<u8>,
+    available_kem_cps: &[KEMtype],
+    available_kdf_cps: &[KDFtype],
+    available_aead_cps: &[AEADtype],
+    available_modes: &[HpkeMode],
+    psks: &PskStore,
+    client_keys: &[AgileKeypair],
+    server_keys: &ServerKeyPolicy,
+    verify_key: Option<&VerifyingKey>,
+) -> Result<Negotiated, HpkeProtoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin
+{
+
+    println!("\nConnessione al server avviata alla porta {}", remote);
+
+
+    // ##### INVIO DELLA CIPHERSUITE DISPONIBILE #####
+
+    println!("\nInvio ciphersuite e richiesta chiave pubblica al server\n");
+
+    // Ogni pacchetto inviato o ricevuto fino alla chiave pubblica entra nel transcript
+    let mut transcript = Transcript::new();
+
+    // => KEM
+    send_cps(stream, &mut transcript, DataType::EncCtxKem, available_kem_cps, "KEM cps").await?;
+    // => KDF
+    send_cps(stream, &mut transcript, DataType::EncCtxKdf, available_kdf_cps, "KDF cps").await?;
+    // => AEAD
+    send_cps(stream, &mut transcript, DataType::EncCtxAead, available_aead_cps, "AEAD cps").await?;
+    // => MODE
+    send_cps(stream, &mut transcript, DataType::EncCtxMode, available_modes, "MODE cps").await?;
+    // => PSK_ID, uno per ogni PSK in archivio
+    for psk_id in psks.ids() {
+        send_single(stream, &mut transcript, DataType::PskId, psk_id.clone(), "PSK_ID").await?;
+    }
+    // => Nonce per l'annuncio firmato, solo se il client sa verificarlo
+    let nonce = signing::client_nonce();
+    if verify_key.is_some() {
+        send_single(stream, &mut transcript, DataType::ClientNonce, nonce.to_vec(), "ClientNonce").await?;
+    }
+
+    // segnala a S che è stato inviato tutto il ciphersuite
+    let end_pack = data_packets_manager::create_packet(DataType::EndCiphersuite, vec![]);
+    transcript.record_sent(&end_pack)?;
+    transport::send_packet(stream, end_pack, "EndCiphersuite").await?;
+
+    println!("\nCiphersuite e richiesta chiave pubblica inviati");
+
+    // vect per memorizzare gli algoritmi scelti
+    let mut server_pubkey: Vec<u8> = vec![];
+    let mut choosen_kem: Option<KEMtype> = None;
+    let mut choosen_kdf: Option<KDFtype> = None;
+    let mut choosen_aead: Option<AEADtype> = None;
+    let mut choosen_mode: Option<HpkeMode> = None;
+    let mut choosen_psk_id: Option<Vec<u8>> = None;
+    let mut server_signature: Option<Vec<u8>> = None;
+
+    // flag per segnalare che è arrivata la chiave pubblica del server
+    let mut pk_pass = false;
+
+
+    // ##### RICEZIONE DELLA CIPHERSUITE DEL SERVER #####
+
+    println!("Aspetto la ciphersuite scellta dal server...");
+
+    loop {
+
+        let pack = match transport::read_packet(stream).await? {
+            Some(pack) => pack,
+            None => return Err(HpkeProtoError::closed("il server ha chiuso la connessione")),
+        };
+        transcript.record_received(&pack)?;
+
+        match pack.data_type {
+            // => Server's public key
+            DataType::PublicKey => {
+                handle_packet(&pack);
+                server_pubkey = pack.payload;
+                pk_pass = true;
+            }
+            // => KEM
+            DataType::EncCtxKem => {
+                handle_packet(&pack);
+                choosen_kem = Some(offered(KEMtype::from_bytes(&pack.payload)?, available_kem_cps, "KEM")?);
+            }
+            // => KDF
+            DataType::EncCtxKdf => {
+                handle_packet(&pack);
+                choosen_kdf = Some(offered(KDFtype::from_bytes(&pack.payload)?, available_kdf_cps, "KDF")?);
+            }
+            // => AEAD
+            DataType::EncCtxAead => {
+                handle_packet(&pack);
+                choosen_aead = Some(offered(AEADtype::from_bytes(&pack.payload)?, available_aead_cps, "AEAD")?);
+            }
+            // => MODE
+            DataType::EncCtxMode => {
+                handle_packet(&pack);
+                choosen_mode = Some(offered(HpkeMode::from_bytes(&pack.payload)?, available_modes, "MODE")?);
+            }
+            // => PSK_ID scelto dal server, deve essere uno di quelli offerti
+            DataType::PskId => {
+                handle_packet(&pack);
+                if !psks.contains(&pack.payload) {
+                    return Err(HpkeProtoError::negotiation("il server ha scelto un PSK_ID non offerto"));
+                }
+                choosen_psk_id = Some(pack.payload);
+            }
+            // => Firma dell'annuncio, subito prima della chiave pubblica
+            DataType::ServerKeySignature => {
+                handle_packet(&pack);
+                server_signature = Some(pack.payload);
+            }
+            // => Nessun accordo possibile col server
+            DataType::NegotiationFailed => {
+                handle_packet(&pack);
+                let failed = pack.payload.iter()
+                    .map(|byte| AlgorithmCategory::from_byte(*byte))
+                    .collect::<Result<Vec<AlgorithmCategory>, HpkeProtoError>>()?;
+                return Err(HpkeProtoError::NegotiationFailed(failed));
+            }
+            other => return Err(unexpected_packet(other)),
+        }
+        // La chiave pubblica è sempre l'ultimo pacchetto inviato dal server
+        if pk_pass {
+            let (Some(kem), Some(kdf), Some(aead), Some(mode)) = (choosen_kem, choosen_kdf, choosen_aead, choosen_mode) else {
+                return Err(HpkeProtoError::negotiation("ciphersuite del server incompleta"));
+            };
+            if mode.uses_psk() != choosen_psk_id.is_some() {
+                return Err(HpkeProtoError::negotiation("PSK_ID non coerente col modo scelto"));
+            }
+            println!("Client ha ricevuto la ciphersuite del server");
+
+            // La PublicKey chiude il transcript
+            let transcript_hash = transcript.hash();
+
+            // La chiave del server va verificata prima di cifrare qualsiasi cosa verso di lei.
+            // Prima la firma: in trust-on-first-use una chiave falsa non deve
+            // finire nel file delle chiavi fidate
+            let suite = Ciphersuite { kem, kdf, aead };
+            let announced_pk = AgilePublicKey { kem, bytes: server_pubkey.clone() };
+            if let Some(verify_key) = verify_key {
+                let signature = server_signature.ok_or_else(|| HpkeProtoError::UntrustedServerKey(
+                    "il server non ha firmato l'annuncio della chiave".to_string()
+                ))?;
+                signing::verify_announcement(verify_key, &suite, mode, &nonce, &announced_pk, &signature)?;
+                println!("Firma dell'annuncio verificata");
+            }
+            server_keys.verify(&remote.to_string(), &announced_pk)?;
+
+            // Nei modi Auth il server deve conoscere la chiave statica del client
+            if mode.uses_auth() {
+                let keypair = client_keys.iter().find(|k| k.kem() == kem).ok_or_else(|| HpkeProtoError::negotiation(
+                    format!("nessuna chiave del client per il KEM {:?}", kem)
+                ))?;
+                send_single(stream, &mut transcript, DataType::ClientPublicKey, keypair.public_key().bytes.clone(), "Client Public Key").await?;
+            }
+
+            // #### OUTPUT DEI RISULTATI ####
+            *server_pk = server_pubkey;
+            transport::send_packet(stream, data_packets_manager::create_packet(DataType::CiphersuiteAck, vec![]), "CiphersuiteAck").await?;
+
+            return Ok(Negotiated {
+                suite,
+                mode,
+                psk_id: choosen_psk_id,
+                client_pk: None,
+                transcript: transcript_hash
+            });
+        }
+
+    }
+
+}
+
+
+// Ogni scelta del server deve essere tra quelle offerte dal client:
+// altrimenti un attaccante potrebbe imporre ad esempio il modo Base
+fn offered<T: Algorithm + fmt::Debug>(choice: T, available: &[T], category: &str) -> Result<T, HpkeProtoError> {
+    if available.contains(&choice) {
+        Ok(choice)
+    } else {
+        Err(HpkeProtoError::negotiation(format!("il server ha scelto {} {:?} non offerto", category, choice)))
+    }
+}
+
+
+// ###################################################################
+// ########################## LATO SERVER ############################
+// ###################################################################
+
+// Primary server responds to the primary client with one of the
+// available ciphersuites and shares its public key.
+// Rende la negoziazione conclusa, o None se il client si è disconnesso
+pub fn handle_client(
+    stream: &TcpStream,
+    decoder: &mut PacketDecoder,
+    keys: &[AgileKeypair],
+    policy: &SelectionPolicy,
+    psks: &PskStore,
+    clients: &AuthorizedClients,
+    signing_key: Option<&SigningKey>,
+) -> Result<Option<Negotiated>, HpkeProtoError> {
+    println!("Incoming connection from: {}\n", stream.peer_addr()?);
+    transport::block_on(stream, decoder, |blocking, stream| blocking.wait(handle_client_async(
+        stream,
+        keys,
+        policy,
+        psks,
+        clients,
+        signing_key
+    )))
+}
+
+// Versione async di handle_client
+pub async fn handle_client_async<S>(
+    stream: &mut PacketStream<S>,
+    keys: &[AgileKeypair],
+    policy: &SelectionPolicy,
+    psks: &PskStore,
+    clients: &AuthorizedClients,
+    signing_key: Option<&SigningKey>,
+) -> Result<Option<Negotiated>, HpkeProtoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin
+{
+
+    // vettori dove vengono salvati gli algoritmi del C
+    let mut client_kem_cps = vec![];
+    let mut client_kdf_cps = vec![];
+    let mut client_aead_cps = vec![];
+    let mut client_modes = vec![];
+    let mut client_psk_ids: Vec<Vec<u8>> = vec![];
+    let mut client_nonce: Option<Vec<u8>> = None;
+
+    // negoziazione scelta, comunicata al client
+    let mut choosen: Option<Negotiated> = None;
+
+    // Pacchetti della negoziazione, nello stesso ordine visto dal client
+    let mut transcript = Transcript::new();
+
+    loop {
+
+        let mut finish_cps = false;   // segnala quando il client ha inviato tutti
+                                            // gli algoritmi che ha a disposizione
+
+        let pack = match transport::read_packet(stream).await? {
+            Some(pack) => pack,
+            None => return Ok(None),
+        };
+        transcript.record_received(&pack)?;
+
+
+        // ##### ARRIVO DELLE CIPHERSUITES DAL CLIENT #####
+
+        match pack.data_type {
+            // => KEM
+            DataType::EncCtxKem => {
+                handle_packet(&pack);
+                push_cps(&mut client_kem_cps, &pack.payload)?;
+            }
+            // => KDF
+            DataType::EncCtxKdf => {
+                handle_packet(&pack);
+                push_cps(&mut client_kdf_cps, &pack.payload)?;
+            }
+            // => AEAD
+            DataType::EncCtxAead => {
+                handle_packet(&pack);
+                push_cps(&mut client_aead_cps, &pack.payload)?;
+            }
+            // => MODE
+            DataType::EncCtxMode => {
+                handle_packet(&pack);
+                push_cps(&mut client_modes, &pack.payload)?;
+            }
+            // => PSK_ID
+            DataType::PskId => {
+                handle_packet(&pack);
+                client_psk_ids.push(pack.payload);
+            }
+            // => Nonce da firmare insieme alla chiave pubblica
+            DataType::ClientNonce => {
+                handle_packet(&pack);
+                if pack.payload.len() != signing::NONCE_LEN {
+                    return Err(HpkeProtoError::framing(format!("nonce di {} byte invece di {}", pack.payload.len(), signing::NONCE_LEN)));
+                }
+                client_nonce = Some(pack.payload);
+            }
+            // => Chiave statica del client, solo nei modi Auth e solo se autorizzata
+            DataType::ClientPublicKey if choosen.as_ref().is_some_and(|n| n.mode.uses_auth()) => {
+                handle_packet(&pack);
+                if let Some(negotiated) = choosen.as_mut() {
+                    let client_pk = agility::agile_pubkey_from_bytes(negotiated.suite.kem, &pack.payload)?;
+                    clients.verify(&client_pk)?;
+                    negotiated.client_pk = Some(client_pk);
+                }
+            }
+            // Invio di pecchetti terminato
+            DataType::EndCiphersuite => {
+                finish_cps = true;
+                println!("Arrivata tutta la cps del client\n");
+            }
+            // Trovata una ciphersuite comune -> esci
+            DataType::CiphersuiteAck if choosen.as_ref().is_some_and(|n| !n.mode.uses_auth() || n.client_pk.is_some()) => {
+                println!("Il client ha ricevuto tutto!");
+                return Ok(choosen);
+            }
+            other => return Err(unexpected_packet(other)),
+        }
+
+
+        // ##### CONTROLLO SE ESEITE UNA CIPHERSUITE COMUNE COL CLIENT #####
+
+        if finish_cps {
+
+            println!("Controllo quali algoritmi sono disponibili...\n");
+
+            // Sceglie il miglior algoritmo comune per KEM, KDF e AEAD
+            let (kem_id, kdf_id, aead_id) = policy.select(
+                &client_kem_cps,
+                &client_kdf_cps,
+                &client_aead_cps
+            );
+
+
+            // Primo PSK_ID del client presente anche nell'archivio del server
+            let psk_id = client_psk_ids.iter().find(|id| psks.contains(id)).cloned();
+            let auth_available = !clients.is_empty()?;
+            let mode = policy.select_mode(&client_modes, psk_id.is_some(), auth_available);
+
+            // Categorie senza accordo: il client viene avvisato invece di restare in attesa
+            let mut failed = vec![];
+            if kem_id.is_none() { failed.push(AlgorithmCategory::Kem); }
+            if kdf_id.is_none() { failed.push(AlgorithmCategory::Kdf); }
+            if aead_id.is_none() { failed.push(AlgorithmCategory::Aead); }
+            if mode.is_none() {
+                // Con una PSK in comune un modo ci sarebbe stato: manca solo il PSK_ID
+                let psk_missing = psk_id.is_none() && policy.select_mode(&client_modes, true, auth_available).is_some();
+                failed.push(if psk_missing { AlgorithmCategory::Psk } else { AlgorithmCategory::Mode });
+            }
+            if !failed.is_empty() {
+                let alert = failed.iter().map(|category| category.to_byte()).collect();
+                let alert_pack = data_packets_manager::create_packet(DataType::NegotiationFailed, alert);
+                transport::send_packet(stream, alert_pack, "NegotiationFailed").await?;
+                return Err(HpkeProtoError::NegotiationFailed(failed));
+            }
+
+            // Se esiste una ciphersuite completa tra C e S, segnala
+            // al client quale algoritmo usare e invia la chiave pubblica
+            if let (Some(kem_id), Some(kdf_id), Some(aead_id), Some(mode)) = (kem_id, kdf_id, aead_id, mode) {
+
+                // Chiave pubblica del server per il KEM scelto
+                let keypair = keys.iter().find(|k| k.kem() == kem_id).ok_or_else(|| HpkeProtoError::negotiation(
+                    format!("nessuna chiave del server per il KEM {:?}", kem_id)
+                ))?;
+
+                // ##### INVIO CIPHERSUITE AL CLIENT #####
+
+                // => KEM
+                let kem_id_pack = data_packets_manager::create_packet(
+                    DataType::EncCtxKem,
+                    kem_id.to_bytes()
+                );
+                transcript.record_sent(&kem_id_pack)?;
+                transport::queue_packet(stream, kem_id_pack, "Choosen KEM cps").await?;
+
+                // => KDF
+                let kdf_id_pack = data_packets_manager::create_packet(
+                    DataType::EncCtxKdf,
+                    kdf_id.to_bytes()
+                );
+                transcript.record_sent(&kdf_id_pack)?;
+                transport::queue_packet(stream, kdf_id_pack, "Choosen KDF cps").await?;
+
+                // => AEAD
+                let aead_id_pack = data_packets_manager::create_packet(
+                    DataType::EncCtxAead,
+                    aead_id.to_bytes()
+                );
+                transcript.record_sent(&aead_id_pack)?;
+                transport::queue_packet(stream, aead_id_pack, "Choosen AEAD cps").await?;
+
+                // => MODE
+                send_single(stream, &mut transcript, DataType::EncCtxMode, mode.to_bytes(), "Choosen MODE").await?;
+
+                // => PSK_ID, solo nei modi con PSK
+                let psk_id = if mode.uses_psk() { psk_id } else { None };
+                if let Some(psk_id) = &psk_id {
+                    send_single(stream, &mut transcript, DataType::PskId, psk_id.clone(), "Choosen PSK_ID").await?;
+                }
+
+                // => Firma dell'annuncio, se il client ha inviato un nonce
+                let suite = Ciphersuite { kem: kem_id, kdf: kdf_id, aead: aead_id };
+                if let (Some(signing_key), Some(nonce)) = (signing_key, &client_nonce) {
+                    let signature = signing::sign_announcement(signing_key, &suite, mode, nonce, keypair.public_key());
+                    send_single(stream, &mut transcript, DataType::ServerKeySignature, signature, "ServerKeySignature").await?;
+                }
+
+                // => Puclic Key, chiude la risposta: parte insieme ai pacchetti accodati
+                let pub_key_pack = data_packets_manager::create_packet(
+                    DataType::PublicKey,
+                    keypair.public_key().bytes.clone()
+                );
+                transcript.record_sent(&pub_key_pack)?;
+                transport::send_packet(stream, pub_key_pack, "Public Key").await?;
+
+                choosen = Some(Negotiated {
+                    suite,
+                    mode,
+                    psk_id,
+                    client_pk: None,
+                    transcript: transcript.hash()
+                });
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::PathBuf;
+
+    use futures_util::{future, SinkExt, StreamExt};
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+    use tokio::io::{self, AsyncRead, AsyncWrite};
+    use tokio::runtime::Builder;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    use crate::agility::{AgileOpModeR, AgileOpModeS};
+    use crate::session::{ReceiverSession, SenderSession};
+    use crate::transport::DataPacketCodec;
+    use crate::trust::Fingerprint;
+
+    const REMOTE: &str = "127.0.0.1:8888";
+    const INFO: &[u8] = b"handshake test";
+
+    // Configurazione dei due lati della negoziazione
+    struct Peers {
+        kems: Vec<KEMtype>,
+        kdfs: Vec<KDFtype>,
+        aeads: Vec<AEADtype>,
+        modes: Vec<HpkeMode>,
+        client_psks: PskStore,
+        client_keys: Vec<AgileKeypair>,
+        server_keys: ServerKeyPolicy,
+        verify_key: Option<VerifyingKey>,
+        keys: Vec<AgileKeypair>,
+        policy: SelectionPolicy,
+        psks: PskStore,
+        clients: AuthorizedClients,
+        signing_key: Option<SigningKey>
+    }
+
+    type ClientResult = Result<(Negotiated, Vec<u8>), HpkeProtoError>;
+    type ServerResult = Result<Option<Negotiated>, HpkeProtoError>;
+
+    impl Peers {
+        fn new() -> Peers {
+            Peers {
+                kems: KEMtype::to_vect(),
+                kdfs: KDFtype::to_vect(),
+                aeads: vec![AEADtype::AesGcm128, AEADtype::AesGcm256, AEADtype::ChaCha20Poly1305],
+                modes: vec![HpkeMode::Base],
+                client_psks: PskStore::new(),
+                client_keys: keypairs(),
+                server_keys: ServerKeyPolicy::AcceptAny,
+                verify_key: None,
+                keys: keypairs(),
+                policy: SelectionPolicy::default(),
+                psks: PskStore::new(),
+                clients: AuthorizedClients::default(),
+                signing_key: None
+            }
+        }
+
+        fn run(&self) -> (ClientResult, ServerResult) {
+            self.run_with(|_| false)
+        }
+
+        // Negoziazione attraverso un intermediario che scarta i pacchetti per cui drop è true
+        fn run_with(&self, drop: fn(&ReceivedPacket) -> bool) -> (ClientResult, ServerResult) {
+            let (client_io, client_side) = io::duplex(64 * 1024);
+            let (server_side, server_io) = io::duplex(64 * 1024);
+            let (client_read, client_write) = io::split(client_side);
+            let (server_read, server_write) = io::split(server_side);
+
+            let client = async {
+                let mut stream = transport::packet_stream(client_io);
+                let mut server_pk = vec![];
+                let negotiated = handle_server_async(
+                    REMOTE.parse().unwrap(),
+                    &mut stream,
+                    &mut server_pk,
+                    &self.kems,
+                    &self.kdfs,
+                    &self.aeads,
+                    &self.modes,
+                    &self.client_psks,
+                    &self.client_keys,
+                    &self.server_keys,
+                    self.verify_key.as_ref()
+                ).await?;
+                Ok((negotiated, server_pk))
+            };
+            let server = async {
+                let mut stream = transport::packet_stream(server_io);
+                handle_client_async(&mut stream, &self.keys, &self.policy, &self.psks, &self.clients, self.signing_key.as_ref()).await
+            };
+
+            let runtime = Builder::new_current_thread().build().unwrap();
+            let (client, server, _, _) = runtime.block_on(future::join4(
+                client,
+                server,
+                relay(client_read, server_write, drop),
+                relay(server_read, client_write, drop)
+            ));
+            (client, server)
+        }
+    }
+
+    // Inoltra i pacchetti da un lato all'altro, e la chiusura della connessione
+    async fn relay<R, W>(from: R, to: W, drop: fn(&ReceivedPacket) -> bool) -> Result<(), HpkeProtoError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin
+    {
+        let mut from = FramedRead::new(from, DataPacketCodec);
+        let mut to = FramedWrite::new(to, DataPacketCodec);
+        while let Some(pack) = from.next().await.transpose()? {
+            if !drop(&pack) {
+                to.send(data_packets_manager::create_packet(pack.data_type, pack.payload)).await?;
+            }
+        }
+        to.close().await
+    }
+
+    fn keypair(keys: &[AgileKeypair], kem: KEMtype) -> AgileKeypair {
+        keys.iter().find(|k| k.kem() == kem).unwrap().clone()
+    }
+
+    // Apre una sessione HPKE con quanto negoziato da ciascun lato: riesce
+    // solo se client e server hanno visto la stessa negoziazione
+    fn sessions_agree(peers: &Peers, client: &Negotiated, server_pk: &[u8], server: &Negotiated) -> bool {
+        let client_psk = client.psk_bundle(&peers.client_psks).unwrap();
+        let client_keys = keypair(&peers.client_keys, client.suite.kem);
+        let mode_s = match client.mode {
+            HpkeMode::Base => AgileOpModeS::Base,
+            HpkeMode::Psk => AgileOpModeS::Psk(client_psk.unwrap()),
+            HpkeMode::Auth => AgileOpModeS::Auth(client_keys),
+            HpkeMode::AuthPsk => AgileOpModeS::AuthPsk(client_keys, client_psk.unwrap()),
+        };
+        let server_psk = server.psk_bundle(&peers.psks).unwrap();
+        let client_pk = server.client_pk.clone();
+        let mode_r = match server.mode {
+            HpkeMode::Base => AgileOpModeR::Base,
+            HpkeMode::Psk => AgileOpModeR::Psk(server_psk.unwrap()),
+            HpkeMode::Auth => AgileOpModeR::Auth(client_pk.unwrap()),
+            HpkeMode::AuthPsk => AgileOpModeR::AuthPsk(client_pk.unwrap(), server_psk.unwrap()),
+        };
+
+        let pk = AgilePublicKey { kem: client.suite.kem, bytes: server_pk.to_vec() };
+        let (mut sender, encapped_key) =
+            SenderSession::new(&client.suite, &mode_s, &pk, &client.session_info(INFO), &mut StdRng::from_entropy()).unwrap();
+        let sk = keypair(&peers.keys, server.suite.kem);
+        let Ok(mut receiver) = ReceiverSession::new(&server.suite, &mode_r, sk.private_key(), &encapped_key, &server.session_info(INFO)) else {
+            return false;
+        };
+        let (ciphertext, tag) = sender.seal(b"messaggio", b"").unwrap();
+        receiver.open(&ciphertext, b"", &tag).is_ok()
+    }
+
+    fn psks() -> PskStore {
+        let mut psk = [0u8; 32];
+        StdRng::from_entropy().fill_bytes(&mut psk);
+        let mut psks = PskStore::new();
+        psks.insert(b"client-1", &psk).unwrap();
+        psks
+    }
+
+    fn keypairs() -> Vec<AgileKeypair> {
+        let mut csprng = StdRng::from_entropy();
+        KEMtype::to_vect().into_iter().map(|kem| agility::agile_gen_keypair(kem, &mut csprng).unwrap()).collect()
+    }
+
+    fn signing_key() -> SigningKey {
+        let mut seed = [0u8; 32];
+        StdRng::from_entropy().fill_bytes(&mut seed);
+        SigningKey::from_bytes(&seed)
+    }
+
+    // File delle chiavi fidate ancora da creare, rimosso alla fine del test
+    struct KnownHostsFile(PathBuf);
+
+    impl KnownHostsFile {
+        fn missing(name: &str) -> KnownHostsFile {
+            let path = std::env::temp_dir().join(format!("hpke-proto-{}-{}", std::process::id(), name));
+            let _ = fs::remove_file(&path);
+            KnownHostsFile(path)
+        }
+    }
+
+    impl Drop for KnownHostsFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn bad_signature_is_not_trusted_on_first_use() {
+        let file = KnownHostsFile::missing("handshake-tofu");
+        let server_key = signing_key();
+        let mut peers = Peers::new();
+        peers.server_keys = ServerKeyPolicy::KnownHosts { path: file.0.clone(), tofu: true };
+        peers.verify_key = Some(server_key.verifying_key());
+
+        // Annuncio firmato con un'altra chiave, o non firmato: la chiave non entra nel file
+        for signing_key in [Some(signing_key()), None] {
+            peers.signing_key = signing_key;
+            let (client, _) = peers.run();
+            assert!(matches!(client, Err(HpkeProtoError::UntrustedServerKey(_))));
+            assert!(!file.0.exists());
+        }
+
+        // Il server vero viene poi accettato e registrato
+        peers.signing_key = Some(server_key);
+        let (client, server) = peers.run();
+        let (negotiated, server_pk) = client.unwrap();
+        assert!(server.unwrap().is_some());
+        let announced = AgilePublicKey { kem: negotiated.suite.kem, bytes: server_pk };
+        assert_eq!(fs::read_to_string(&file.0).unwrap().lines().count(), 1);
+        assert!(ServerKeyPolicy::KnownHosts { path: file.0.clone(), tofu: false }.verify(REMOTE, &announced).is_ok());
+    }
+
+    #[test]
+    fn untouched_negotiation_opens_a_session() {
+        let peers = Peers::new();
+        let (client, server) = peers.run();
+        let (client, server_pk) = client.unwrap();
+        let server = server.unwrap().unwrap();
+        assert_eq!(client.suite, server.suite);
+        assert_eq!(client.mode, HpkeMode::Base);
+        assert_eq!(client.transcript, server.transcript);
+        assert!(sessions_agree(&peers, &client, &server_pk, &server));
+    }
+
+    #[test]
+    fn tampered_offer_breaks_the_session() {
+        // L'intermediario toglie X25519 dall'offerta: il server ripiega su P-256
+        let peers = Peers::new();
+        let (client, server) = peers.run_with(|pack| {
+            pack.data_type == DataType::EncCtxKem && pack.payload == KEMtype::X25519HkdfSha256.to_bytes()
+        });
+        let (client, server_pk) = client.unwrap();
+        let server = server.unwrap().unwrap();
+        assert_eq!(server.suite.kem, KEMtype::DhP256HkdfSha256);
+
+        // I due lati hanno visto offerte diverse: transcript diversi e nessuna sessione
+        assert_ne!(client.transcript, server.transcript);
+        assert!(!sessions_agree(&peers, &client, &server_pk, &server));
+    }
+
+    #[test]
+    fn every_mode_negotiates() {
+        for mode in [HpkeMode::Psk, HpkeMode::Auth, HpkeMode::AuthPsk] {
+            let mut peers = Peers::new();
+            peers.modes = vec![mode];
+            if mode.uses_psk() {
+                peers.client_psks = psks();
+                peers.psks = peers.client_psks.clone();
+            }
+            if mode.uses_auth() {
+                peers.clients.pins = peers.client_keys.iter().map(|k| Fingerprint::of(k.public_key())).collect();
+            }
+
+            let (client, server) = peers.run();
+            let (client, server_pk) = client.unwrap();
+            let server = server.unwrap().unwrap();
+            assert_eq!((client.mode, server.mode), (mode, mode));
+            assert_eq!(client.psk_id, mode.uses_psk().then(|| b"client-1".to_vec()));
+            assert_eq!(server.psk_id, client.psk_id);
+            let client_pk = keypair(&peers.client_keys, server.suite.kem).public_key().clone();
+            assert_eq!(server.client_pk, mode.uses_auth().then_some(client_pk));
+            assert!(sessions_agree(&peers, &client, &server_pk, &server), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn unauthorized_client_key_is_rejected() {
+        let mut peers = Peers::new();
+        peers.modes = vec![HpkeMode::Auth];
+        // Il server autorizza solo un altro client
+        peers.clients.pins = keypairs().iter().map(|k| Fingerprint::of(k.public_key())).collect();
+        let (_, server) = peers.run();
+        assert!(server.is_err());
+    }
+
+    #[test]
+    fn negotiation_failed_alert() {
+        // Nessun AEAD in comune
+        let mut peers = Peers::new();
+        peers.aeads = vec![AEADtype::ExportOnlyAead];
+        let (client, server) = peers.run();
+        assert!(matches!(client, Err(HpkeProtoError::NegotiationFailed(ref failed)) if failed == &[AlgorithmCategory::Aead]));
+        assert!(matches!(server, Err(HpkeProtoError::NegotiationFailed(_))));
+
+        // Solo modi con PSK, ma nessun PSK_ID in comune
+        let mut peers = Peers::new();
+        peers.modes = vec![HpkeMode::Psk];
+        peers.client_psks = psks();
+        let mut psk = [0u8; 32];
+        StdRng::from_entropy().fill_bytes(&mut psk);
+        peers.psks.insert(b"client-2", &psk).unwrap();
+        let (client, _) = peers.run();
+        assert!(matches!(client, Err(HpkeProtoError::NegotiationFailed(ref failed)) if failed == &[AlgorithmCategory::Psk]));
+    }
+
+    #[test]
+    fn pinned_server_key() {
+        let mut peers = Peers::new();
+        let pin = Fingerprint::of(keypair(&peers.keys, KEMtype::X25519HkdfSha256).public_key());
+        peers.server_keys = ServerKeyPolicy::Pinned(vec![pin]);
+        let (client, _) = peers.run();
+        assert_eq!(client.unwrap().0.suite.kem, KEMtype::X25519HkdfSha256);
+
+        // Chiave di un altro server
+        let other = Fingerprint::of(keypair(&keypairs(), KEMtype::X25519HkdfSha256).public_key());
+        peers.server_keys = ServerKeyPolicy::Pinned(vec![other]);
+        let (client, _) = peers.run();
+        assert!(matches!(client, Err(HpkeProtoError::UntrustedServerKey(_))));
+    }
+}
@@ -0,0 +1,211 @@
+// Key schedule per le entità secondarie (PDMv2): a partire dal contesto
+// HPKE già stabilito tra PC e PS si derivano chiavi per ogni flusso,
+// legate alla 5-tupla, al Client-ID e al Server-ID, senza rifare l'HPKE.
+// La derivazione usa export() (RFC 9180, 5.3): il contesto dell'exporter
+// codifica in modo univoco tutti i parametri del flusso.
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::agility::AgileHpkeError;
+use crate::session::{ReceiverSession, SenderSession};
+
+// Etichetta che separa queste chiavi da ogni altro uso di export()
+const FLOW_KEY_LABEL: &[u8] = b"PDMv2 flow key";
+
+// Lunghezza di default delle chiavi di flusso
+pub const FLOW_KEY_LEN: usize = 32;
+
+// Numeri di protocollo IANA
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+
+
+// Qualsiasi contesto HPKE da cui si può esportare un segreto
+pub trait Exporter {
+    fn export(&self, exporter_context: &[u8], len: usize) -> Result<Vec<u8>, AgileHpkeError>;
+}
+
+impl Exporter for SenderSession {
+    fn export(&self, exporter_context: &[u8], len: usize) -> Result<Vec<u8>, AgileHpkeError> {
+        SenderSession::export(self, exporter_context, len)
+    }
+}
+
+impl Exporter for ReceiverSession {
+    fn export(&self, exporter_context: &[u8], len: usize) -> Result<Vec<u8>, AgileHpkeError> {
+        ReceiverSession::export(self, exporter_context, len)
+    }
+}
+
+
+// 5-tupla che identifica un flusso; la direzione conta (src -> dst)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FiveTuple {
+    pub src_addr: IpAddr,
+    pub dst_addr: IpAddr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub protocol: u8
+}
+
+impl FiveTuple {
+    pub fn new(src: SocketAddr, dst: SocketAddr, protocol: u8) -> FiveTuple {
+        FiveTuple {
+            src_addr: src.ip(),
+            dst_addr: dst.ip(),
+            src_port: src.port(),
+            dst_port: dst.port(),
+            protocol
+        }
+    }
+
+    // Flusso nella direzione opposta
+    pub fn reversed(&self) -> FiveTuple {
+        FiveTuple {
+            src_addr: self.dst_addr,
+            dst_addr: self.src_addr,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            protocol: self.protocol
+        }
+    }
+
+    // Codifica a lunghezza fissa per famiglia: versione IP | src | dst | porte BE | protocollo
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        push_addr(&mut out, &self.src_addr);
+        push_addr(&mut out, &self.dst_addr);
+        out.extend_from_slice(&self.src_port.to_be_bytes());
+        out.extend_from_slice(&self.dst_port.to_be_bytes());
+        out.push(self.protocol);
+        out
+    }
+}
+
+fn push_addr(out: &mut Vec<u8>, addr: &IpAddr) {
+    match addr {
+        IpAddr::V4(v4) => {
+            out.push(4);
+            out.extend_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            out.push(6);
+            out.extend_from_slice(&v6.octets());
+        }
+    }
+}
+
+// Campo a lunghezza variabile, prefissato dalla lunghezza (u16 BE)
+fn push_field(out: &mut Vec<u8>, field: &[u8]) -> Result<(), AgileHpkeError> {
+    let len = u16::try_from(field.len()).map_err(|_| AgileHpkeError::InvalidInput("ID troppo lungo"))?;
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(field);
+    Ok(())
+}
+
+
+// Contesto dell'exporter: label | 5-tupla | Client-ID | Server-ID
+pub fn flow_key_context(tuple: &FiveTuple, client_id: &[u8], server_id: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
+    let mut ctx = FLOW_KEY_LABEL.to_vec();
+    ctx.extend_from_slice(&tuple.to_bytes());
+    push_field(&mut ctx, client_id)?;
+    push_field(&mut ctx, server_id)?;
+    Ok(ctx)
+}
+
+
+// Deriva la chiave di len byte per il flusso indicato.
+// PC e PS ottengono la stessa chiave e possono consegnarla alle entità secondarie
+pub fn derive_flow_key<E: Exporter>(
+    exporter: &E,
+    tuple: &FiveTuple,
+    client_id: &[u8],
+    server_id: &[u8],
+    len: usize
+) -> Result<Vec<u8>, AgileHpkeError> {
+    let ctx = flow_key_context(tuple, client_id, server_id)?;
+    exporter.export(&ctx, len)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::agility::{self, AgileOpModeR, AgileOpModeS};
+    use crate::ciphersuite::{AEADtype, Ciphersuite, KDFtype, KEMtype};
+
+    const INFO: &[u8] = b"keyschedule test";
+
+    fn sessions() -> (SenderSession, ReceiverSession) {
+        let suite = Ciphersuite { kem: KEMtype::X25519HkdfSha256, kdf: KDFtype::HkdfSha256, aead: AEADtype::AesGcm128 };
+        let mut csprng = StdRng::from_entropy();
+        let keypair = agility::agile_gen_keypair(suite.kem, &mut csprng).unwrap();
+        let (sender, encapped_key) = SenderSession::new(&suite, &AgileOpModeS::Base, keypair.public_key(), INFO, &mut csprng).unwrap();
+        let receiver = ReceiverSession::new(&suite, &AgileOpModeR::Base, keypair.private_key(), &encapped_key, INFO).unwrap();
+        (sender, receiver)
+    }
+
+    fn tuple() -> FiveTuple {
+        FiveTuple::new("10.0.0.1:40000".parse().unwrap(), "10.0.0.2:443".parse().unwrap(), PROTO_TCP)
+    }
+
+    #[test]
+    fn both_peers_derive_the_same_key() {
+        let (sender, receiver) = sessions();
+        let pc = derive_flow_key(&sender, &tuple(), b"sc", b"ss", FLOW_KEY_LEN).unwrap();
+        let ps = derive_flow_key(&receiver, &tuple(), b"sc", b"ss", FLOW_KEY_LEN).unwrap();
+        assert_eq!(pc, ps);
+        assert_eq!(pc.len(), FLOW_KEY_LEN);
+
+        // Un'altra sessione dà un'altra chiave per lo stesso flusso
+        let (other, _) = sessions();
+        assert_ne!(derive_flow_key(&other, &tuple(), b"sc", b"ss", FLOW_KEY_LEN).unwrap(), pc);
+    }
+
+    #[test]
+    fn every_flow_parameter_changes_the_key() {
+        let (sender, _) = sessions();
+        let key = |tuple: &FiveTuple, client_id: &[u8], server_id: &[u8]| {
+            derive_flow_key(&sender, tuple, client_id, server_id, FLOW_KEY_LEN).unwrap()
+        };
+        let base = key(&tuple(), b"sc", b"ss");
+
+        let mut other_port = tuple();
+        other_port.src_port += 1;
+        let mut other_proto = tuple();
+        other_proto.protocol = PROTO_UDP;
+        let v6 = FiveTuple::new("[::1]:40000".parse().unwrap(), "[::2]:443".parse().unwrap(), PROTO_TCP);
+
+        // La direzione conta: il flusso inverso ha una sua chiave
+        assert_ne!(key(&tuple().reversed(), b"sc", b"ss"), base);
+        assert_ne!(key(&other_port, b"sc", b"ss"), base);
+        assert_ne!(key(&other_proto, b"sc", b"ss"), base);
+        assert_ne!(key(&v6, b"sc", b"ss"), base);
+        assert_ne!(key(&tuple(), b"sc2", b"ss"), base);
+        assert_ne!(key(&tuple(), b"sc", b"ss2"), base);
+        // Gli ID sono prefissati dalla lunghezza: spostare un byte dall'uno all'altro cambia la chiave
+        assert_ne!(key(&tuple(), b"scs", b"s"), base);
+    }
+
+    #[test]
+    fn reversed_twice_is_the_same_flow() {
+        assert_eq!(tuple().reversed().reversed(), tuple());
+        assert_ne!(tuple().reversed().to_bytes(), tuple().to_bytes());
+    }
+
+    #[test]
+    fn oversize_id_is_rejected() {
+        let (sender, _) = sessions();
+        let long_id = vec![b'x'; u16::MAX as usize + 1];
+        assert!(matches!(
+            derive_flow_key(&sender, &tuple(), &long_id, b"ss", FLOW_KEY_LEN),
+            Err(AgileHpkeError::InvalidInput(_))
+        ));
+        assert!(flow_key_context(&tuple(), b"sc", &long_id).is_err());
+        // Il limite è incluso
+        assert!(flow_key_context(&tuple(), &long_id[1..], b"ss").is_ok());
+    }
+}
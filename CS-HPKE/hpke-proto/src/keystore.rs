@@ -0,0 +1,390 @@
+// Archivio su disco delle chiavi d'identità del server, una per KEM.
+// Il server le ricarica a ogni avvio, così la sua chiave pubblica non cambia
+// e i client possono fissarla o riceverla in anticipo.
+//
+// Formato del file (interi big endian):
+//
+//   magic      4 byte   "HPKS"
+//   versione   u8       1
+//   flag       u8       0 = chiavi in chiaro, 1 = cifrate con passphrase
+//   se cifrato:
+//     iterazioni u32    iterazioni di PBKDF2-HMAC-SHA256
+//     salt       16 byte
+//     nonce      12 byte
+//   corpo               in chiaro, oppure ChaCha20-Poly1305 del corpo con
+//                       l'header come AAD e il tag di 16 byte in coda
+//
+//   corpo:
+//     numero di chiavi u8
+//     per ogni chiave: KEM u16 | lunghezza u16 | chiave privata
+//                      | lunghezza u16 | chiave pubblica
+//
+// La libreria non espone il calcolo della pubblica dalla privata, quindi si
+// salvano entrambe; al caricamento vengono validate tutte e due.
+// Lo stesso contenitore (header, cifratura con passphrase) è usato anche per
+// la chiave di firma del server, con un corpo diverso (vedi signing.rs).
+// Il file viene creato con permessi 0600 e rifiutato se leggibile da altri.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use sha2::Sha256;
+
+use crate::agility::{self, AgileKeypair};
+use crate::ciphersuite::{Algorithm, KEMtype};
+use crate::error::HpkeProtoError;
+
+const MAGIC: &[u8] = b"HPKS";
+const VERSION: u8 = 1;
+
+const FLAG_PLAIN: u8 = 0;
+const FLAG_ENCRYPTED: u8 = 1;
+
+// Iterazioni di default per i nuovi file; quelle di un file esistente
+// sono lette dall'header
+pub const PBKDF2_ITERATIONS: u32 = 600_000;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+const PLAIN_HEADER_LEN: usize = 6;
+const ENCRYPTED_HEADER_LEN: usize = PLAIN_HEADER_LEN + 4 + SALT_LEN + NONCE_LEN;
+
+
+// Serializza le chiavi nel formato descritto sopra
+pub fn encode(keys: &[AgileKeypair], passphrase: Option<&[u8]>) -> Result<Vec<u8>, HpkeProtoError> {
+    if keys.len() > u8::MAX as usize {
+        return Err(HpkeProtoError::key_store("troppe chiavi per un solo file"));
+    }
+    let mut body = vec![keys.len() as u8];
+    for keypair in keys {
+        let sk = keypair.private_key();
+        if keys.iter().filter(|k| k.kem() == sk.kem).count() > 1 {
+            return Err(HpkeProtoError::key_store(format!("più chiavi per il KEM {}", sk.kem)));
+        }
+        body.extend_from_slice(&sk.kem.to_bytes());
+        for bytes in [&sk.bytes, &keypair.public_key().bytes] {
+            body.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+            body.extend_from_slice(bytes);
+        }
+    }
+
+    seal_body(&body, passphrase)
+}
+
+// Legge le chiavi da un file nel formato descritto sopra.
+// Un file cifrato richiede la passphrase
+pub fn decode(file: &[u8], passphrase: Option<&[u8]>) -> Result<Vec<AgileKeypair>, HpkeProtoError> {
+    parse_body(&open_body(file, passphrase)?)
+}
+
+// Header del contenitore seguito dal corpo, cifrato se c'è la passphrase
+pub(crate) fn seal_body(body: &[u8], passphrase: Option<&[u8]>) -> Result<Vec<u8>, HpkeProtoError> {
+    seal_body_with(body, passphrase, PBKDF2_ITERATIONS)
+}
+
+fn seal_body_with(body: &[u8], passphrase: Option<&[u8]>, iterations: u32) -> Result<Vec<u8>, HpkeProtoError> {
+    let mut file = MAGIC.to_vec();
+    file.push(VERSION);
+    match passphrase {
+        None => {
+            file.push(FLAG_PLAIN);
+            file.extend_from_slice(body);
+        }
+        Some(passphrase) => {
+            let mut csprng = StdRng::from_entropy();
+            let mut salt = [0u8; SALT_LEN];
+            let mut nonce = [0u8; NONCE_LEN];
+            csprng.fill_bytes(&mut salt);
+            csprng.fill_bytes(&mut nonce);
+
+            file.push(FLAG_ENCRYPTED);
+            file.extend_from_slice(&iterations.to_be_bytes());
+            file.extend_from_slice(&salt);
+            file.extend_from_slice(&nonce);
+
+            let cipher = wrapping_cipher(passphrase, &salt, iterations);
+            let sealed = cipher.encrypt(&nonce.into(), Payload { msg: body, aad: &file })
+                .map_err(|_| HpkeProtoError::key_store("cifratura delle chiavi fallita"))?;
+            file.extend_from_slice(&sealed);
+        }
+    }
+    Ok(file)
+}
+
+// Controlla l'header e restituisce il corpo in chiaro
+pub(crate) fn open_body(file: &[u8], passphrase: Option<&[u8]>) -> Result<Vec<u8>, HpkeProtoError> {
+    if file.len() < PLAIN_HEADER_LEN || &file[..4] != MAGIC {
+        return Err(HpkeProtoError::key_store("non è un archivio di chiavi"));
+    }
+    if file[4] != VERSION {
+        return Err(HpkeProtoError::key_store(format!("versione {} non supportata", file[4])));
+    }
+
+    match (file[5], passphrase) {
+        (FLAG_PLAIN, _) => Ok(file[PLAIN_HEADER_LEN..].to_vec()),
+        (FLAG_ENCRYPTED, None) => Err(HpkeProtoError::key_store("archivio cifrato: serve la passphrase")),
+        (FLAG_ENCRYPTED, Some(passphrase)) => {
+            if file.len() < ENCRYPTED_HEADER_LEN + TAG_LEN {
+                return Err(HpkeProtoError::key_store("archivio cifrato troncato"));
+            }
+            let (header, sealed) = file.split_at(ENCRYPTED_HEADER_LEN);
+            let iterations = u32::from_be_bytes(header[6..10].try_into().unwrap());
+            let salt = &header[10..10 + SALT_LEN];
+            let nonce: [u8; NONCE_LEN] = header[10 + SALT_LEN..].try_into().unwrap();
+
+            let cipher = wrapping_cipher(passphrase, salt, iterations);
+            cipher.decrypt(&nonce.into(), Payload { msg: sealed, aad: header })
+                .map_err(|_| HpkeProtoError::key_store("passphrase errata o archivio danneggiato"))
+        }
+        (flag, _) => Err(HpkeProtoError::key_store(format!("flag {} sconosciuto", flag))),
+    }
+}
+
+fn parse_body(body: &[u8]) -> Result<Vec<AgileKeypair>, HpkeProtoError> {
+    let truncated = || HpkeProtoError::key_store("elenco delle chiavi troncato");
+    let (count, mut rest) = body.split_first().ok_or_else(truncated)?;
+
+    let mut keys: Vec<AgileKeypair> = vec![];
+    for _ in 0..*count {
+        if rest.len() < 2 {
+            return Err(truncated());
+        }
+        let kem = KEMtype::from_bytes(&rest[..2])?;
+        rest = &rest[2..];
+        let sk = take_field(&mut rest).ok_or_else(truncated)?;
+        let pk = take_field(&mut rest).ok_or_else(truncated)?;
+
+        if keys.iter().any(|k| k.kem() == kem) {
+            return Err(HpkeProtoError::key_store(format!("più chiavi per il KEM {}", kem)));
+        }
+        keys.push(agility::agile_keypair_from_bytes(kem, sk, pk)?);
+    }
+    if !rest.is_empty() {
+        return Err(HpkeProtoError::key_store("byte in eccesso dopo l'elenco delle chiavi"));
+    }
+    Ok(keys)
+}
+
+// Campo lunghezza u16 | byte, in testa a `rest`
+fn take_field<'a>(rest: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u16::from_be_bytes([*rest.first()?, *rest.get(1)?]) as usize;
+    let field = rest.get(2..2 + len)?;
+    *rest = &rest[2 + len..];
+    Some(field)
+}
+
+// Chiave di cifratura del file: PBKDF2-HMAC-SHA256(passphrase, salt)
+fn wrapping_cipher(passphrase: &[u8], salt: &[u8], iterations: u32) -> ChaCha20Poly1305 {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, salt, iterations, &mut key);
+    ChaCha20Poly1305::new(&key.into())
+}
+
+
+// Carica le chiavi dal file, controllandone prima i permessi
+pub fn load(path: &Path, passphrase: Option<&[u8]>) -> Result<Vec<AgileKeypair>, HpkeProtoError> {
+    check_permissions(path)?;
+    decode(&fs::read(path)?, passphrase)
+}
+
+// Salva le chiavi con permessi 0600. Il file viene scritto accanto a quello
+// vecchio e poi rinominato, così un'interruzione non lascia un archivio a metà
+pub fn save(path: &Path, keys: &[AgileKeypair], passphrase: Option<&[u8]>) -> Result<(), HpkeProtoError> {
+    write_private(path, &encode(keys, passphrase)?)
+}
+
+pub(crate) fn write_private(path: &Path, data: &[u8]) -> Result<(), HpkeProtoError> {
+    let tmp = path.with_extension("tmp");
+    let mut file = create_private(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// Chiavi del server per i KEM richiesti, nell'ordine dato.
+// Le chiavi già presenti nel file vengono riusate; per i KEM mancanti se ne
+// genera una nuova e il file viene aggiornato. I KEM non implementati dalla
+// libreria vengono saltati
+pub fn load_or_create(path: &Path, kems: &[KEMtype], passphrase: Option<&[u8]>) -> Result<Vec<AgileKeypair>, HpkeProtoError> {
+    let mut stored = if path.exists() {
+        let keys = load(path, passphrase)?;
+        println!("Caricate {} chiavi da {}", keys.len(), path.display());
+        keys
+    } else {
+        vec![]
+    };
+
+    let mut csprng = StdRng::from_entropy();
+    let mut changed = false;
+    for kem in kems {
+        if stored.iter().any(|k| k.kem() == *kem) {
+            continue;
+        }
+        match agility::agile_gen_keypair(*kem, &mut csprng) {
+            Ok(keypair) => {
+                println!("Generata una nuova chiave per {}", kem);
+                stored.push(keypair);
+                changed = true;
+            }
+            Err(e) => println!("Nessuna chiave generata: {}", e),
+        }
+    }
+    if changed {
+        save(path, &stored, passphrase)?;
+        println!("Chiavi salvate in {}", path.display());
+    }
+
+    // Le chiavi nel file per KEM non più abilitati restano salvate ma non vengono usate
+    Ok(kems.iter()
+        .filter_map(|kem| stored.iter().find(|k| k.kem() == *kem).cloned())
+        .collect())
+}
+
+
+#[cfg(unix)]
+pub(crate) fn create_private(path: &Path) -> Result<File, HpkeProtoError> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    // mode() vale solo per i file nuovi: un file già esistente va corretto
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn create_private(path: &Path) -> Result<File, HpkeProtoError> {
+    Ok(OpenOptions::new().write(true).create(true).truncate(true).open(path)?)
+}
+
+// Come ssh: un archivio leggibile dal gruppo o da altri utenti viene rifiutato
+#[cfg(unix)]
+pub(crate) fn check_permissions(path: &Path) -> Result<(), HpkeProtoError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(HpkeProtoError::key_store(format!(
+            "permessi {:o} troppo larghi per {}: usare 0600", mode & 0o777, path.display()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn check_permissions(_path: &Path) -> Result<(), HpkeProtoError> {
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Poche iterazioni: i test non devono pagare il costo di PBKDF2
+    const TEST_ITERATIONS: u32 = 1000;
+
+    fn sample_keys() -> Vec<AgileKeypair> {
+        let mut csprng = StdRng::from_entropy();
+        [KEMtype::X25519HkdfSha256, KEMtype::DhP256HkdfSha256].iter()
+            .map(|kem| agility::agile_gen_keypair(*kem, &mut csprng).unwrap())
+            .collect()
+    }
+
+    fn body(keys: &[AgileKeypair]) -> Vec<u8> {
+        // Il corpo di un archivio in chiaro è quello che viene cifrato
+        encode(keys, None).unwrap()[PLAIN_HEADER_LEN..].to_vec()
+    }
+
+    fn assert_same_keys(decoded: &[AgileKeypair], keys: &[AgileKeypair]) {
+        assert_eq!(decoded.len(), keys.len());
+        for (a, b) in decoded.iter().zip(keys) {
+            assert_eq!(a.kem(), b.kem());
+            assert_eq!(a.private_key().bytes, b.private_key().bytes);
+            assert_eq!(a.public_key().bytes, b.public_key().bytes);
+        }
+    }
+
+    #[test]
+    fn plain_round_trip() {
+        let keys = sample_keys();
+        let file = encode(&keys, None).unwrap();
+        assert_eq!(&file[..PLAIN_HEADER_LEN], &[b'H', b'P', b'K', b'S', VERSION, FLAG_PLAIN]);
+        assert_same_keys(&decode(&file, None).unwrap(), &keys);
+        // La passphrase non serve, ma non fa fallire un archivio in chiaro
+        assert_same_keys(&decode(&file, Some(b"ignored")).unwrap(), &keys);
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let keys = sample_keys();
+        let file = seal_body_with(&body(&keys), Some(b"passphrase"), TEST_ITERATIONS).unwrap();
+        assert_eq!(file[5], FLAG_ENCRYPTED);
+        assert_same_keys(&decode(&file, Some(b"passphrase")).unwrap(), &keys);
+    }
+
+    #[test]
+    fn wrong_or_missing_passphrase() {
+        let file = seal_body_with(&body(&sample_keys()), Some(b"passphrase"), TEST_ITERATIONS).unwrap();
+        assert!(matches!(decode(&file, Some(b"Passphrase")), Err(HpkeProtoError::KeyStore(_))));
+        assert!(matches!(decode(&file, None), Err(HpkeProtoError::KeyStore(_))));
+    }
+
+    #[test]
+    fn iterations_are_read_from_header() {
+        let file = seal_body_with(&body(&sample_keys()), Some(b"passphrase"), TEST_ITERATIONS).unwrap();
+        assert_eq!(&file[6..10], &TEST_ITERATIONS.to_be_bytes());
+        assert!(decode(&file, Some(b"passphrase")).is_ok());
+
+        // L'header è autenticato: un numero di iterazioni diverso non apre l'archivio
+        let mut tampered = file;
+        tampered[6..10].copy_from_slice(&(TEST_ITERATIONS + 1).to_be_bytes());
+        assert!(decode(&tampered, Some(b"passphrase")).is_err());
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        let file = encode(&sample_keys(), None).unwrap();
+
+        let mut wrong_magic = file.clone();
+        wrong_magic[0] = b'X';
+        assert!(decode(&wrong_magic, None).is_err());
+
+        let mut wrong_version = file.clone();
+        wrong_version[4] = VERSION + 1;
+        assert!(decode(&wrong_version, None).is_err());
+
+        let mut wrong_flag = file.clone();
+        wrong_flag[5] = 7;
+        assert!(decode(&wrong_flag, None).is_err());
+
+        assert!(decode(&file[..PLAIN_HEADER_LEN - 1], None).is_err());
+        assert!(decode(&file[..file.len() - 1], None).is_err());
+
+        let mut trailing = file;
+        trailing.push(0);
+        assert!(decode(&trailing, None).is_err());
+
+        let encrypted = seal_body_with(b"", Some(b"passphrase"), TEST_ITERATIONS).unwrap();
+        assert!(decode(&encrypted[..ENCRYPTED_HEADER_LEN + TAG_LEN - 1], Some(b"passphrase")).is_err());
+    }
+
+    #[test]
+    fn duplicate_kem_is_rejected() {
+        let keys = sample_keys();
+        let twice = vec![keys[0].clone(), keys[0].clone()];
+        assert!(encode(&twice, None).is_err());
+
+        // Anche un file scritto a mano con due chiavi per lo stesso KEM
+        let mut file = encode(&keys[..1], None).unwrap();
+        let entry = file[PLAIN_HEADER_LEN + 1..].to_vec();
+        file[PLAIN_HEADER_LEN] = 2;
+        file.extend_from_slice(&entry);
+        assert!(decode(&file, None).is_err());
+    }
+}
@@ -0,0 +1,406 @@
+// Key Rotation Index (KRI): ogni messaggio di una sessione porta l'epoca
+// della chiave con cui è cifrato e il suo numero di sequenza nell'epoca.
+// La chiave e il nonce base di ogni epoca si derivano dal contesto HPKE con
+// export(), quindi entrambi i peer li calcolano senza altri scambi.
+// Il mittente passa all'epoca successiva dopo un numero massimo di messaggi
+// o un tempo massimo; il destinatario accetta anche le ultime `window`
+// epoche, per i messaggi ancora in volo durante il cambio.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use aes_gcm::aead::{AeadInPlace, NewAead};
+use chacha20poly1305::ChaCha20Poly1305;
+use hpke::HpkeError;
+
+use crate::agility::AgileHpkeError;
+use crate::ciphersuite::AEADtype;
+use crate::keyschedule::Exporter;
+use crate::replay::SlidingWindow;
+use crate::error::HpkeProtoError;
+
+const KRI_LABEL: &[u8] = b"PDMv2 KRI epoch";
+
+pub const NONCE_LEN: usize = 12;
+// Tag di tutti gli AEAD del registro (AES-GCM e ChaCha20-Poly1305)
+pub const TAG_LEN: usize = 16;
+// KRI (u32 BE) | sequenza nell'epoca (u64 BE)
+pub const KRI_HEADER_LEN: usize = 12;
+
+
+// Quando ruotare la chiave e quante epoche vecchie accettare
+#[derive(Debug, Clone, Copy)]
+pub struct RekeyPolicy {
+    pub max_messages: u64,
+    pub max_age: Duration,
+    pub window: u32
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> RekeyPolicy {
+        RekeyPolicy {
+            max_messages: 1 << 16,
+            max_age: Duration::from_secs(3600),
+            window: 2
+        }
+    }
+}
+
+
+// Intestazione trasportata con ogni messaggio nel pacchetto KeyRotationIndex
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KriHeader {
+    pub kri: u32,
+    pub seq: u64
+}
+
+impl KriHeader {
+    pub fn to_bytes(&self) -> [u8; KRI_HEADER_LEN] {
+        let mut out = [0u8; KRI_HEADER_LEN];
+        out[..4].copy_from_slice(&self.kri.to_be_bytes());
+        out[4..].copy_from_slice(&self.seq.to_be_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<KriHeader, HpkeProtoError> {
+        if bytes.len() != KRI_HEADER_LEN {
+            return Err(HpkeProtoError::framing("intestazione KRI di lunghezza errata"));
+        }
+        Ok(KriHeader {
+            kri: u32::from_be_bytes(bytes[..4].try_into().unwrap()),
+            seq: u64::from_be_bytes(bytes[4..].try_into().unwrap())
+        })
+    }
+}
+
+
+// AEAD istanziato con la chiave di un'epoca (le chiavi AES espanse sono grandi)
+enum EpochAead {
+    AesGcm128(Box<Aes128Gcm>),
+    AesGcm256(Box<Aes256Gcm>),
+    ChaCha20Poly1305(ChaCha20Poly1305)
+}
+
+// Usata anche da pdm.rs, con un'etichetta diversa
+pub(crate) struct EpochKey {
+    aead: EpochAead,
+    base_nonce: [u8; NONCE_LEN]
+}
+
+fn key_len(aead: AEADtype) -> Result<usize, AgileHpkeError> {
+    match aead {
+        AEADtype::AesGcm128 => Ok(16),
+        AEADtype::AesGcm256 | AEADtype::ChaCha20Poly1305 => Ok(32),
+        AEADtype::ExportOnlyAead => Err(AgileHpkeError::InvalidInput("AEAD export-only: nessuna cifratura possibile")),
+    }
+}
+
+// Chiave e nonce base dell'epoca: export(label | epoca, Nk + Nn)
+fn derive_epoch<E: Exporter>(exporter: &E, aead: AEADtype, epoch: u32) -> Result<EpochKey, AgileHpkeError> {
+    derive_epoch_key(exporter, KRI_LABEL, aead, epoch)
+}
+
+// Come derive_epoch; label separa chiavi e nonce dei diversi usi
+pub(crate) fn derive_epoch_key<E: Exporter>(exporter: &E, label: &[u8], aead: AEADtype, epoch: u32) -> Result<EpochKey, AgileHpkeError> {
+    let nk = key_len(aead)?;
+    let mut ctx = label.to_vec();
+    ctx.extend_from_slice(&epoch.to_be_bytes());
+    let secret = exporter.export(&ctx, nk + NONCE_LEN)?;
+
+    let (key, nonce) = secret.split_at(nk);
+    let invalid = |_| AgileHpkeError::InvalidInput("lunghezza della chiave di epoca");
+    let aead = match aead {
+        AEADtype::AesGcm128 => EpochAead::AesGcm128(Box::new(Aes128Gcm::new_from_slice(key).map_err(invalid)?)),
+        AEADtype::AesGcm256 => EpochAead::AesGcm256(Box::new(Aes256Gcm::new_from_slice(key).map_err(invalid)?)),
+        AEADtype::ChaCha20Poly1305 => EpochAead::ChaCha20Poly1305(ChaCha20Poly1305::new_from_slice(key).map_err(invalid)?),
+        AEADtype::ExportOnlyAead => return Err(AgileHpkeError::InvalidInput("AEAD export-only: nessuna cifratura possibile")),
+    };
+    Ok(EpochKey { aead, base_nonce: nonce.try_into().unwrap() })
+}
+
+impl EpochKey {
+    // Come in RFC 9180 5.2: nonce = base_nonce XOR sequenza
+    fn nonce(&self, seq: u64) -> [u8; NONCE_LEN] {
+        let mut nonce = self.base_nonce;
+        for (n, s) in nonce[NONCE_LEN - 8..].iter_mut().zip(seq.to_be_bytes()) {
+            *n ^= s;
+        }
+        nonce
+    }
+
+    pub(crate) fn seal(&self, header: &KriHeader, msg: &mut [u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
+        let nonce = self.nonce(header.seq);
+        let aad = bound_aad(header, aad);
+        let tag = match &self.aead {
+            EpochAead::AesGcm128(c) => c.encrypt_in_place_detached(&nonce.into(), &aad, msg).map(|t| t.to_vec()),
+            EpochAead::AesGcm256(c) => c.encrypt_in_place_detached(&nonce.into(), &aad, msg).map(|t| t.to_vec()),
+            EpochAead::ChaCha20Poly1305(c) => c.encrypt_in_place_detached(&nonce.into(), &aad, msg).map(|t| t.to_vec()),
+        };
+        tag.map_err(|_| AgileHpkeError::InvalidInput("messaggio troppo lungo per l'AEAD"))
+    }
+
+    pub(crate) fn open(&self, header: &KriHeader, ciphertext: &mut [u8], aad: &[u8], tag: &[u8]) -> Result<(), AgileHpkeError> {
+        if tag.len() != TAG_LEN {
+            return Err(AgileHpkeError::HpkeError(HpkeError::OpenError));
+        }
+        let nonce = self.nonce(header.seq);
+        let aad = bound_aad(header, aad);
+        let res = match &self.aead {
+            EpochAead::AesGcm128(c) => c.decrypt_in_place_detached(&nonce.into(), &aad, ciphertext, tag.into()),
+            EpochAead::AesGcm256(c) => c.decrypt_in_place_detached(&nonce.into(), &aad, ciphertext, tag.into()),
+            EpochAead::ChaCha20Poly1305(c) => c.decrypt_in_place_detached(&nonce.into(), &aad, ciphertext, tag.into()),
+        };
+        res.map_err(|_| AgileHpkeError::HpkeError(HpkeError::OpenError))
+    }
+}
+
+// L'intestazione KRI viaggia in chiaro: la si autentica insieme all'associated data
+fn bound_aad(header: &KriHeader, aad: &[u8]) -> Vec<u8> {
+    let mut out = header.to_bytes().to_vec();
+    out.extend_from_slice(aad);
+    out
+}
+
+
+// ###################################################################
+// ########################### MITTENTE ##############################
+// ###################################################################
+
+pub struct EpochSender<E: Exporter> {
+    exporter: E,
+    aead: AEADtype,
+    policy: RekeyPolicy,
+    epoch: u32,
+    seq: u64,
+    started: Instant,
+    key: EpochKey
+}
+
+impl<E: Exporter> EpochSender<E> {
+    pub fn new(exporter: E, aead: AEADtype, policy: RekeyPolicy) -> Result<EpochSender<E>, AgileHpkeError> {
+        let key = derive_epoch(&exporter, aead, 0)?;
+        Ok(EpochSender { exporter, aead, policy, epoch: 0, seq: 0, started: Instant::now(), key })
+    }
+
+    // Cifra il messaggio con la chiave dell'epoca corrente, ruotandola se serve
+    pub fn seal(&mut self, msg: &[u8], aad: &[u8]) -> Result<(KriHeader, Vec<u8>, Vec<u8>), AgileHpkeError> {
+        if self.seq >= self.policy.max_messages || self.started.elapsed() >= self.policy.max_age {
+            self.rotate()?;
+        }
+        let header = KriHeader { kri: self.epoch, seq: self.seq };
+        let mut ciphertext = msg.to_vec();
+        let tag = self.key.seal(&header, &mut ciphertext, aad)?;
+        self.seq += 1;
+        Ok((header, ciphertext, tag))
+    }
+
+    // Passa all'epoca successiva; la chiave precedente viene scartata
+    pub fn rotate(&mut self) -> Result<(), AgileHpkeError> {
+        let next = self.epoch.checked_add(1).ok_or(AgileHpkeError::MessageLimitReached)?;
+        self.key = derive_epoch(&self.exporter, self.aead, next)?;
+        self.epoch = next;
+        self.seq = 0;
+        self.started = Instant::now();
+        println!("Chiave ruotata: KRI {}", self.epoch);
+        Ok(())
+    }
+
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    pub fn exporter(&self) -> &E {
+        &self.exporter
+    }
+}
+
+
+// ###################################################################
+// ######################### DESTINATARIO ############################
+// ###################################################################
+
+pub struct EpochReceiver<E: Exporter> {
+    exporter: E,
+    aead: AEADtype,
+    window: u32,
+    // epoca più recente vista
+    current: u32,
+    // chiave e finestra anti-replay di ogni epoca ammessa
+    keys: BTreeMap<u32, (EpochKey, SlidingWindow)>
+}
+
+impl<E: Exporter> EpochReceiver<E> {
+    pub fn new(exporter: E, aead: AEADtype, policy: RekeyPolicy) -> Result<EpochReceiver<E>, AgileHpkeError> {
+        let mut keys = BTreeMap::new();
+        keys.insert(0, (derive_epoch(&exporter, aead, 0)?, SlidingWindow::new()));
+        Ok(EpochReceiver { exporter, aead, window: policy.window, current: 0, keys })
+    }
+
+    // Decifra un messaggio di un'epoca ammessa: la corrente, una successiva
+    // (il mittente ha ruotato) o una delle ultime `window` precedenti.
+    // Le sequenze già ricevute nell'epoca vengono rifiutate come replay
+    pub fn open(&mut self, header: &KriHeader, ciphertext: &[u8], aad: &[u8], tag: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
+        if header.kri < self.current.saturating_sub(self.window) {
+            return Err(AgileHpkeError::StaleEpoch(header.kri));
+        }
+        // La chiave di un'epoca nuova entra nella mappa solo dopo un messaggio autentico
+        let (key, mut replay_window) = match self.keys.remove(&header.kri) {
+            Some(state) => state,
+            None => (derive_epoch(&self.exporter, self.aead, header.kri)?, SlidingWindow::new()),
+        };
+        let mut plaintext = ciphertext.to_vec();
+        let res = replay_window.check(header.kri, header.seq)
+            .map_err(AgileHpkeError::from)
+            .and_then(|_| key.open(header, &mut plaintext, aad, tag));
+        if res.is_ok() {
+            replay_window.update(header.seq);
+        }
+        if res.is_ok() || header.kri <= self.current {
+            self.keys.insert(header.kri, (key, replay_window));
+        }
+        res?;
+
+        // Solo un messaggio autentico può far avanzare l'epoca
+        if header.kri > self.current {
+            self.current = header.kri;
+            println!("Il mittente è passato a KRI {}", self.current);
+            let oldest = self.current.saturating_sub(self.window);
+            self.keys = self.keys.split_off(&oldest);
+        }
+        Ok(plaintext)
+    }
+
+    pub fn epoch(&self) -> u32 {
+        self.current
+    }
+
+    pub fn exporter(&self) -> &E {
+        &self.exporter
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::agility::{self, AgileOpModeR, AgileOpModeS};
+    use crate::ciphersuite::{Ciphersuite, KDFtype, KEMtype};
+    use crate::replay::ReplayError;
+    use crate::session::{ReceiverSession, SenderSession};
+
+    const INFO: &[u8] = b"kri test";
+    const AAD: &[u8] = b"associated data";
+
+    fn suite(aead: AEADtype) -> Ciphersuite {
+        Ciphersuite { kem: KEMtype::X25519HkdfSha256, kdf: KDFtype::HkdfSha256, aead }
+    }
+
+    // Mittente e destinatario sullo stesso contesto HPKE
+    fn pair(aead: AEADtype, policy: RekeyPolicy) -> (EpochSender<SenderSession>, EpochReceiver<ReceiverSession>) {
+        let mut csprng = StdRng::from_entropy();
+        let keypair = agility::agile_gen_keypair(suite(aead).kem, &mut csprng).unwrap();
+        let (session, encapped_key) = SenderSession::new(&suite(aead), &AgileOpModeS::Base, keypair.public_key(), INFO, &mut csprng).unwrap();
+        let receiver = ReceiverSession::new(&suite(aead), &AgileOpModeR::Base, keypair.private_key(), &encapped_key, INFO).unwrap();
+        (EpochSender::new(session, aead, policy).unwrap(), EpochReceiver::new(receiver, aead, policy).unwrap())
+    }
+
+    fn policy(max_messages: u64, max_age: Duration, window: u32) -> RekeyPolicy {
+        RekeyPolicy { max_messages, max_age, window }
+    }
+
+    #[test]
+    fn round_trip_every_aead() {
+        for aead in [AEADtype::AesGcm128, AEADtype::AesGcm256, AEADtype::ChaCha20Poly1305] {
+            let (mut sender, mut receiver) = pair(aead, RekeyPolicy::default());
+            for msg in [&b"primo"[..], b"", b"terzo"] {
+                let (header, ciphertext, tag) = sender.seal(msg, AAD).unwrap();
+                assert_eq!(tag.len(), TAG_LEN);
+                assert_eq!(receiver.open(&header, &ciphertext, AAD, &tag).unwrap(), msg);
+            }
+        }
+    }
+
+    #[test]
+    fn export_only_aead_is_an_error() {
+        let (sender, _) = pair(AEADtype::AesGcm128, RekeyPolicy::default());
+        assert!(matches!(
+            EpochSender::new(sender.exporter, AEADtype::ExportOnlyAead, RekeyPolicy::default()),
+            Err(AgileHpkeError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn rotation_at_max_messages() {
+        let (mut sender, mut receiver) = pair(AEADtype::AesGcm128, policy(3, Duration::from_secs(3600), 2));
+        for i in 0..7u64 {
+            let (header, ciphertext, tag) = sender.seal(b"messaggio", AAD).unwrap();
+            assert_eq!(header, KriHeader { kri: (i / 3) as u32, seq: i % 3 });
+            receiver.open(&header, &ciphertext, AAD, &tag).unwrap();
+        }
+        assert_eq!(sender.epoch(), 2);
+        assert_eq!(receiver.epoch(), 2);
+    }
+
+    #[test]
+    fn rotation_at_max_age() {
+        let (mut sender, mut receiver) = pair(AEADtype::ChaCha20Poly1305, policy(1 << 16, Duration::from_millis(50), 2));
+        let (header, ciphertext, tag) = sender.seal(b"prima", AAD).unwrap();
+        assert_eq!(header.kri, 0);
+        receiver.open(&header, &ciphertext, AAD, &tag).unwrap();
+
+        thread::sleep(Duration::from_millis(60));
+        let (header, ciphertext, tag) = sender.seal(b"dopo", AAD).unwrap();
+        assert_eq!(header, KriHeader { kri: 1, seq: 0 });
+        assert_eq!(receiver.open(&header, &ciphertext, AAD, &tag).unwrap(), b"dopo");
+    }
+
+    #[test]
+    fn old_epochs_inside_the_window() {
+        // Un messaggio per epoca: 0, 1, 2, 3
+        let (mut sender, mut receiver) = pair(AEADtype::AesGcm256, policy(1, Duration::from_secs(3600), 1));
+        let sealed: Vec<_> = (0..4).map(|_| sender.seal(b"messaggio", AAD).unwrap()).collect();
+
+        // Arriva per primo l'ultimo: l'epoca 2 è ancora nella finestra, la 1 e la 0 no
+        let (header, ciphertext, tag) = &sealed[3];
+        receiver.open(header, ciphertext, AAD, tag).unwrap();
+        let (header, ciphertext, tag) = &sealed[2];
+        assert_eq!(receiver.open(header, ciphertext, AAD, tag).unwrap(), b"messaggio");
+        for (header, ciphertext, tag) in &sealed[..2] {
+            assert!(matches!(receiver.open(header, ciphertext, AAD, tag), Err(AgileHpkeError::StaleEpoch(kri)) if kri == header.kri));
+        }
+    }
+
+    #[test]
+    fn replay_within_an_epoch() {
+        let (mut sender, mut receiver) = pair(AEADtype::AesGcm128, RekeyPolicy::default());
+        let (header, ciphertext, tag) = sender.seal(b"messaggio", AAD).unwrap();
+        receiver.open(&header, &ciphertext, AAD, &tag).unwrap();
+        assert!(matches!(
+            receiver.open(&header, &ciphertext, AAD, &tag),
+            Err(AgileHpkeError::Replay(ReplayError::Replayed { kri: 0, seq: 0 }))
+        ));
+
+        // Un messaggio alterato non consuma la sequenza
+        let (header, mut ciphertext, tag) = sender.seal(b"secondo", AAD).unwrap();
+        ciphertext[0] ^= 1;
+        assert!(receiver.open(&header, &ciphertext, AAD, &tag).is_err());
+        ciphertext[0] ^= 1;
+        assert_eq!(receiver.open(&header, &ciphertext, AAD, &tag).unwrap(), b"secondo");
+    }
+
+    #[test]
+    fn forged_epoch_does_not_advance_the_receiver() {
+        let (mut sender, mut receiver) = pair(AEADtype::AesGcm128, policy(1 << 16, Duration::from_secs(3600), 0));
+        let (header, ciphertext, tag) = sender.seal(b"messaggio", AAD).unwrap();
+        let forged = KriHeader { kri: 100, seq: 0 };
+        assert!(receiver.open(&forged, &ciphertext, AAD, &tag).is_err());
+        assert_eq!(receiver.epoch(), 0);
+        receiver.open(&header, &ciphertext, AAD, &tag).unwrap();
+    }
+}
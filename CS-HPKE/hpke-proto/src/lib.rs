@@ -0,0 +1,25 @@
+// Libreria condivisa tra client e server: codifica dei pacchetti,
+// registro delle ciphersuite e macchine a stati dell'handshake.
+// Ogni modifica al protocollo va fatta solo qui.
+
+pub mod error;
+pub mod data_packets_manager;
+pub mod ciphersuite;
+pub mod handshake;
+pub mod agility;
+pub mod psk;
+pub mod session;
+pub mod keyschedule;
+pub mod secondary;
+pub mod pdm;
+pub mod kri;
+pub mod replay;
+pub mod envelope;
+pub mod connections;
+pub mod transport;
+pub mod exchange;
+pub mod keystore;
+pub mod trust;
+pub mod signing;
+pub mod transcript;
+pub mod config;
@@ -0,0 +1,495 @@
+// Opzione PDMv2 (draft-ietf-ippm-encrypted-pdmv2-02) per l'header
+// Destination Options di IPv6. La parte in chiaro contiene solo ciò che
+// serve a chi riceve per scegliere la chiave e ordinare i pacchetti;
+// i campi di misura di PDM (RFC 8250) viaggiano cifrati con l'AEAD negoziato.
+//
+//  0                   1                   2                   3
+//  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |  Option Type  | Option Length | Vrsn  |     Reserved Bits     |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |             Epoch             |        PSN This Packet        |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// :             Encrypted PDM Data (ciphertext | tag)             :
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//
+// PDM Data in chiaro (prima della cifratura):
+//
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |      PSN Last Received        |           Reserved            |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |                        Global Pointer                         |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |  Scale DTLR   |  Scale DTLS   |           Reserved            |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |  Delta Time Last Received     |    Delta Time Last Sent       |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//
+// I pacchetti IPv6 possono andare persi o arrivare in disordine, quindi il
+// nonce non può venire da un contatore implicito: chiave e nonce base di
+// ogni epoca si derivano dal contesto HPKE con export(), come per il KRI
+// (kri.rs) ma con un'altra etichetta, e il nonce è nonce base XOR PSN.
+// Il mittente non deve riusare una PSN nella stessa epoca: PdmSender tiene
+// il contatore e passa all'epoca successiva quando le PSN sono finite.
+
+use std::time::Duration;
+
+use crate::agility::AgileHpkeError;
+use crate::ciphersuite::AEADtype;
+use crate::error::HpkeProtoError;
+use crate::keyschedule::Exporter;
+use crate::kri::{self, EpochKey, KriHeader, TAG_LEN};
+
+// Il draft lascia il tipo da assegnare (TBD): si usa il valore sperimentale
+// dell'RFC 4727 (act = 00, chg = 0, rest = 11110)
+pub const PDMV2_OPTION_TYPE: u8 = 0x1E;
+pub const PDMV2_VERSION: u8 = 2;
+
+// Type + Length + Version/Reserved + Epoch + PSN This Packet
+pub const PDMV2_CLEAR_LEN: usize = 8;
+// Campi PDM cifrati
+pub const PDM_DATA_LEN: usize = 16;
+
+// Gli RFC 8250 misurano i delta in attosecondi, scalati di 2^scale
+const ATTOS_PER_NANO: u128 = 1_000_000_000;
+
+// Etichetta che separa le chiavi PDM da quelle dei messaggi (KRI)
+const PDM_LABEL: &[u8] = b"PDMv2 PDM epoch";
+
+
+fn malformed(what: &str) -> HpkeProtoError {
+    HpkeProtoError::framing(format!("opzione PDMv2 malformata: {}", what))
+}
+
+
+// Campi di misura di PDM, trasportati cifrati
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PdmData {
+    pub psn_last_recv: u16,
+    pub global_pointer: u32,
+    pub scale_dtlr: u8,
+    pub scale_dtls: u8,
+    pub delta_last_recv: u16,
+    pub delta_last_sent: u16
+}
+
+impl PdmData {
+    pub fn to_bytes(&self) -> [u8; PDM_DATA_LEN] {
+        let mut out = [0u8; PDM_DATA_LEN];
+        out[0..2].copy_from_slice(&self.psn_last_recv.to_be_bytes());
+        // out[2..4] riservati
+        out[4..8].copy_from_slice(&self.global_pointer.to_be_bytes());
+        out[8] = self.scale_dtlr;
+        out[9] = self.scale_dtls;
+        // out[10..12] riservati
+        out[12..14].copy_from_slice(&self.delta_last_recv.to_be_bytes());
+        out[14..16].copy_from_slice(&self.delta_last_sent.to_be_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<PdmData, HpkeProtoError> {
+        if bytes.len() != PDM_DATA_LEN {
+            return Err(malformed("lunghezza dei dati PDM"));
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        Ok(PdmData {
+            psn_last_recv: u16_at(0),
+            global_pointer: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            scale_dtlr: bytes[8],
+            scale_dtls: bytes[9],
+            delta_last_recv: u16_at(12),
+            delta_last_sent: u16_at(14)
+        })
+    }
+
+    // Imposta il delta dall'ultimo pacchetto ricevuto
+    pub fn set_delta_last_recv(&mut self, delta: Duration) {
+        let (value, scale) = scale_delta(delta);
+        self.delta_last_recv = value;
+        self.scale_dtlr = scale;
+    }
+
+    // Imposta il delta dall'ultimo pacchetto inviato
+    pub fn set_delta_last_sent(&mut self, delta: Duration) {
+        let (value, scale) = scale_delta(delta);
+        self.delta_last_sent = value;
+        self.scale_dtls = scale;
+    }
+
+    pub fn delta_last_recv(&self) -> Duration {
+        unscale_delta(self.delta_last_recv, self.scale_dtlr)
+    }
+
+    pub fn delta_last_sent(&self) -> Duration {
+        unscale_delta(self.delta_last_sent, self.scale_dtls)
+    }
+}
+
+
+// Porta un intervallo nel formato di PDM: valore a 16 bit e scala minima
+// tale che valore * 2^scala attosecondi approssimi l'intervallo per difetto
+pub fn scale_delta(delta: Duration) -> (u16, u8) {
+    let attos = delta.as_nanos() * ATTOS_PER_NANO;
+    let mut scale = 0u8;
+    while (attos >> scale) > u16::MAX as u128 {
+        scale += 1;
+    }
+    ((attos >> scale) as u16, scale)
+}
+
+// Operazione inversa di scale_delta, con risoluzione al nanosecondo
+pub fn unscale_delta(value: u16, scale: u8) -> Duration {
+    let attos = (value as u128).checked_shl(scale as u32).unwrap_or(u128::MAX);
+    let nanos = attos / ATTOS_PER_NANO;
+    Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+}
+
+
+// Chiave PDM di un'epoca, derivata dal contesto HPKE di mittente o destinatario
+pub struct PdmKey {
+    epoch: u16,
+    key: EpochKey
+}
+
+impl PdmKey {
+    pub fn derive<E: Exporter>(exporter: &E, aead: AEADtype, epoch: u16) -> Result<PdmKey, HpkeProtoError> {
+        let key = kri::derive_epoch_key(exporter, PDM_LABEL, aead, epoch as u32)?;
+        Ok(PdmKey { epoch, key })
+    }
+
+    pub fn epoch(&self) -> u16 {
+        self.epoch
+    }
+
+    // Epoca e PSN, da cui EpochKey calcola il nonce
+    fn header(&self, psn_this_packet: u16) -> KriHeader {
+        KriHeader { kri: self.epoch as u32, seq: psn_this_packet as u64 }
+    }
+}
+
+
+// Opzione PDMv2 completa, con i dati PDM già cifrati
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pdmv2Option {
+    pub epoch: u16,
+    pub psn_this_packet: u16,
+    // ciphertext | tag
+    pub encrypted: Vec<u8>
+}
+
+impl Pdmv2Option {
+    // Option Length: byte dell'opzione esclusi Option Type e Option Length
+    fn option_len(&self) -> Result<u8, HpkeProtoError> {
+        u8::try_from(PDMV2_CLEAR_LEN - 2 + self.encrypted.len())
+            .map_err(|_| HpkeProtoError::framing("opzione PDMv2 oltre i 255 byte"))
+    }
+
+    // Parte in chiaro dell'opzione, usata anche come associated data
+    pub fn clear_bytes(&self) -> Result<[u8; PDMV2_CLEAR_LEN], HpkeProtoError> {
+        let mut out = [0u8; PDMV2_CLEAR_LEN];
+        out[0] = PDMV2_OPTION_TYPE;
+        out[1] = self.option_len()?;
+        // Versione nei 4 bit alti, i 12 bit riservati restano a zero
+        out[2] = PDMV2_VERSION << 4;
+        out[4..6].copy_from_slice(&self.epoch.to_be_bytes());
+        out[6..8].copy_from_slice(&self.psn_this_packet.to_be_bytes());
+        Ok(out)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, HpkeProtoError> {
+        let mut out = self.clear_bytes()?.to_vec();
+        out.extend_from_slice(&self.encrypted);
+        Ok(out)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Pdmv2Option, HpkeProtoError> {
+        if bytes.len() < PDMV2_CLEAR_LEN {
+            return Err(malformed("troppo corta"));
+        }
+        if bytes[0] != PDMV2_OPTION_TYPE {
+            return Err(malformed("Option Type"));
+        }
+        if bytes[1] as usize != bytes.len() - 2 {
+            return Err(malformed("Option Length"));
+        }
+        if bytes[2] >> 4 != PDMV2_VERSION {
+            return Err(malformed("versione"));
+        }
+        Ok(Pdmv2Option {
+            epoch: u16::from_be_bytes([bytes[4], bytes[5]]),
+            psn_this_packet: u16::from_be_bytes([bytes[6], bytes[7]]),
+            encrypted: bytes[PDMV2_CLEAR_LEN..].to_vec()
+        })
+    }
+
+    // Cifra i dati PDM con la chiave dell'epoca e il nonce della PSN;
+    // la parte in chiaro è autenticata come associated data.
+    // La PSN la sceglie PdmSender, che non la riusa mai
+    fn seal(key: &PdmKey, psn_this_packet: u16, data: &PdmData) -> Result<Pdmv2Option, HpkeProtoError> {
+        let mut option = Pdmv2Option {
+            epoch: key.epoch,
+            psn_this_packet,
+            encrypted: vec![0u8; PDM_DATA_LEN + TAG_LEN]
+        };
+        let aad = option.clear_bytes()?;
+        let mut ciphertext = data.to_bytes().to_vec();
+        let tag = key.key.seal(&key.header(psn_this_packet), &mut ciphertext, &aad)?;
+        ciphertext.extend_from_slice(&tag);
+        option.encrypted = ciphertext;
+        Ok(option)
+    }
+
+    // Verifica il tag e decifra i dati PDM. Non dipende dai pacchetti
+    // ricevuti prima: ammette perdite e riordino
+    pub fn open(&self, key: &PdmKey) -> Result<PdmData, HpkeProtoError> {
+        if self.encrypted.len() != PDM_DATA_LEN + TAG_LEN {
+            return Err(malformed("lunghezza della parte cifrata"));
+        }
+        if self.epoch != key.epoch {
+            return Err(AgileHpkeError::InvalidInput("opzione PDMv2 di un'altra epoca").into());
+        }
+        let aad = self.clear_bytes()?;
+        let (ciphertext, tag) = self.encrypted.split_at(PDM_DATA_LEN);
+        let mut plaintext = ciphertext.to_vec();
+        key.key.open(&key.header(self.psn_this_packet), &mut plaintext, &aad, tag)?;
+        PdmData::from_bytes(&plaintext)
+    }
+}
+
+
+// Lato mittente: assegna le PSN in ordine. Dopo la PSN 65535 passa
+// all'epoca successiva invece di ricominciare da 0 con la stessa chiave;
+// finite anche le epoche si ferma con MessageLimitReached
+pub struct PdmSender<E: Exporter> {
+    exporter: E,
+    aead: AEADtype,
+    key: PdmKey,
+    // None quando le PSN dell'epoca sono esaurite
+    next_psn: Option<u16>
+}
+
+impl<E: Exporter> PdmSender<E> {
+    pub fn new(exporter: E, aead: AEADtype) -> Result<PdmSender<E>, HpkeProtoError> {
+        let key = PdmKey::derive(&exporter, aead, 0)?;
+        Ok(PdmSender { exporter, aead, key, next_psn: Some(0) })
+    }
+
+    pub fn seal(&mut self, data: &PdmData) -> Result<Pdmv2Option, HpkeProtoError> {
+        let psn = match self.next_psn {
+            Some(psn) => psn,
+            None => {
+                self.rotate()?;
+                0
+            }
+        };
+        let option = Pdmv2Option::seal(&self.key, psn, data)?;
+        self.next_psn = psn.checked_add(1);
+        Ok(option)
+    }
+
+    fn rotate(&mut self) -> Result<(), HpkeProtoError> {
+        let next = self.key.epoch.checked_add(1).ok_or(AgileHpkeError::MessageLimitReached)?;
+        self.key = PdmKey::derive(&self.exporter, self.aead, next)?;
+        self.next_psn = Some(0);
+        println!("PSN esaurite: chiave PDM dell'epoca {}", next);
+        Ok(())
+    }
+
+    pub fn epoch(&self) -> u16 {
+        self.key.epoch
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::agility::{self, AgileOpModeR, AgileOpModeS};
+    use crate::ciphersuite::{Ciphersuite, KDFtype, KEMtype};
+    use crate::session::{ReceiverSession, SenderSession};
+
+    fn sample_data() -> PdmData {
+        PdmData {
+            psn_last_recv: 0x1234,
+            global_pointer: 0xDEADBEEF,
+            scale_dtlr: 7,
+            scale_dtls: 9,
+            delta_last_recv: 0xABCD,
+            delta_last_sent: 0x0102
+        }
+    }
+
+    #[test]
+    fn pdm_data_layout() {
+        let bytes = sample_data().to_bytes();
+        assert_eq!(bytes, [
+            0x12, 0x34, 0x00, 0x00,
+            0xDE, 0xAD, 0xBE, 0xEF,
+            0x07, 0x09, 0x00, 0x00,
+            0xAB, 0xCD, 0x01, 0x02
+        ]);
+        assert_eq!(PdmData::from_bytes(&bytes).unwrap(), sample_data());
+    }
+
+    #[test]
+    fn option_layout_round_trip() {
+        let option = Pdmv2Option {
+            epoch: 0x0A0B,
+            psn_this_packet: 0x0C0D,
+            encrypted: vec![0xEE; PDM_DATA_LEN + TAG_LEN]
+        };
+        let bytes = option.to_bytes().unwrap();
+        assert_eq!(bytes.len(), PDMV2_CLEAR_LEN + PDM_DATA_LEN + TAG_LEN);
+        assert_eq!(&bytes[..PDMV2_CLEAR_LEN], &[
+            PDMV2_OPTION_TYPE, (bytes.len() - 2) as u8,
+            0x20, 0x00,
+            0x0A, 0x0B, 0x0C, 0x0D
+        ]);
+        assert_eq!(Pdmv2Option::from_bytes(&bytes).unwrap(), option);
+    }
+
+    #[test]
+    fn option_rejects_malformed() {
+        let option = Pdmv2Option { epoch: 1, psn_this_packet: 2, encrypted: vec![0; 4] };
+        let bytes = option.to_bytes().unwrap();
+
+        assert!(Pdmv2Option::from_bytes(&bytes[..PDMV2_CLEAR_LEN - 1]).is_err());
+
+        let mut wrong_type = bytes.clone();
+        wrong_type[0] = 0x0F;
+        assert!(Pdmv2Option::from_bytes(&wrong_type).is_err());
+
+        let mut wrong_len = bytes.clone();
+        wrong_len[1] += 1;
+        assert!(Pdmv2Option::from_bytes(&wrong_len).is_err());
+
+        let mut wrong_version = bytes;
+        wrong_version[2] = 0x10;
+        assert!(Pdmv2Option::from_bytes(&wrong_version).is_err());
+    }
+
+    #[test]
+    fn delta_scaling() {
+        assert_eq!(scale_delta(Duration::ZERO), (0, 0));
+
+        for delta in [Duration::from_micros(1), Duration::from_millis(3), Duration::from_secs(2)] {
+            let (value, scale) = scale_delta(delta);
+            assert!(value > u16::MAX / 2, "la scala scelta non è la minima");
+            let back = unscale_delta(value, scale);
+            assert!(back <= delta);
+            // 16 bit significativi: errore relativo sotto 2^-15
+            assert!(delta - back <= delta / (1 << 15) + Duration::from_nanos(1));
+        }
+    }
+
+    // I due capi di una sessione HPKE
+    fn sessions() -> (SenderSession, ReceiverSession) {
+        let suite = Ciphersuite {
+            kem: KEMtype::X25519HkdfSha256,
+            kdf: KDFtype::HkdfSha256,
+            aead: AEADtype::ChaCha20Poly1305
+        };
+        let mut csprng = StdRng::from_entropy();
+        let keypair = agility::agile_gen_keypair(suite.kem, &mut csprng).unwrap();
+        let info = b"pdmv2 test";
+
+        let (sender, encapped_key) =
+            SenderSession::new(&suite, &AgileOpModeS::Base, keypair.public_key(), info, &mut csprng).unwrap();
+        let receiver =
+            ReceiverSession::new(&suite, &AgileOpModeR::Base, keypair.private_key(), &encapped_key, info).unwrap();
+        (sender, receiver)
+    }
+
+    // Chiavi PDM dell'epoca derivate dai due capi di una sessione HPKE
+    fn epoch_keys(epoch: u16) -> (PdmKey, PdmKey) {
+        let (sender, receiver) = sessions();
+        (
+            PdmKey::derive(&sender, AEADtype::ChaCha20Poly1305, epoch).unwrap(),
+            PdmKey::derive(&receiver, AEADtype::ChaCha20Poly1305, epoch).unwrap()
+        )
+    }
+
+    #[test]
+    fn seal_open_round_trip() {
+        let (sender, receiver) = epoch_keys(3);
+
+        let option = Pdmv2Option::seal(&sender, 42, &sample_data()).unwrap();
+        let parsed = Pdmv2Option::from_bytes(&option.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed.epoch, 3);
+        assert_eq!(parsed.open(&receiver).unwrap(), sample_data());
+
+        // La parte in chiaro è autenticata: cambiare la PSN invalida il tag
+        let mut tampered = Pdmv2Option::seal(&sender, 43, &sample_data()).unwrap();
+        tampered.psn_this_packet = 44;
+        assert!(tampered.open(&receiver).is_err());
+
+        // Un'opzione di un'altra epoca non si apre con questa chiave
+        let mut other_epoch = Pdmv2Option::seal(&sender, 45, &sample_data()).unwrap();
+        other_epoch.epoch = 4;
+        assert!(other_epoch.open(&receiver).is_err());
+    }
+
+    #[test]
+    fn open_after_loss_and_reordering() {
+        let (sender, receiver) = epoch_keys(0);
+
+        let options: Vec<Pdmv2Option> = (0..5u16)
+            .map(|psn| {
+                let data = PdmData { psn_last_recv: psn, ..sample_data() };
+                Pdmv2Option::seal(&sender, psn, &data).unwrap()
+            })
+            .collect();
+
+        // Il pacchetto 1 va perso, il 3 arriva prima del 2
+        for psn in [0usize, 3, 2, 4] {
+            let data = options[psn].open(&receiver).unwrap();
+            assert_eq!(data.psn_last_recv, psn as u16);
+        }
+    }
+
+    #[test]
+    fn sender_assigns_psns_in_order() {
+        let (sender, receiver) = sessions();
+        let receiver = PdmKey::derive(&receiver, AEADtype::ChaCha20Poly1305, 0).unwrap();
+        let mut sender = PdmSender::new(sender, AEADtype::ChaCha20Poly1305).unwrap();
+        for psn in 0..3u16 {
+            let option = sender.seal(&sample_data()).unwrap();
+            assert_eq!((option.epoch, option.psn_this_packet), (0, psn));
+            assert_eq!(option.open(&receiver).unwrap(), sample_data());
+        }
+    }
+
+    #[test]
+    fn sender_rekeys_when_psns_wrap() {
+        let (sender, receiver) = sessions();
+        let mut sender = PdmSender::new(sender, AEADtype::ChaCha20Poly1305).unwrap();
+        sender.next_psn = Some(u16::MAX);
+
+        let last = sender.seal(&sample_data()).unwrap();
+        assert_eq!((last.epoch, last.psn_this_packet), (0, u16::MAX));
+        // La PSN 0 non viene riusata con la chiave dell'epoca 0
+        let next = sender.seal(&sample_data()).unwrap();
+        assert_eq!((next.epoch, next.psn_this_packet), (1, 0));
+        assert_eq!(sender.epoch(), 1);
+
+        let epoch_0 = PdmKey::derive(&receiver, AEADtype::ChaCha20Poly1305, 0).unwrap();
+        let epoch_1 = PdmKey::derive(&receiver, AEADtype::ChaCha20Poly1305, 1).unwrap();
+        assert_eq!(last.open(&epoch_0).unwrap(), sample_data());
+        assert_eq!(next.open(&epoch_1).unwrap(), sample_data());
+    }
+
+    #[test]
+    fn sender_stops_after_the_last_epoch() {
+        let (sender, _) = sessions();
+        let mut sender = PdmSender::new(sender, AEADtype::ChaCha20Poly1305).unwrap();
+        sender.key = PdmKey::derive(&sender.exporter, AEADtype::ChaCha20Poly1305, u16::MAX).unwrap();
+        sender.next_psn = None;
+        assert!(matches!(
+            sender.seal(&sample_data()),
+            Err(HpkeProtoError::Crypto(AgileHpkeError::MessageLimitReached))
+        ));
+    }
+}
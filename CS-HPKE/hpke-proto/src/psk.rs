@@ -0,0 +1,52 @@
+// Archivio delle PSK: client e server possono avere più PSK, ognuna
+// associata a un PSK_ID. Sul canale viaggia solo il PSK_ID, mai la PSK.
+
+use std::collections::HashMap;
+use crate::agility::AgileHpkeError;
+use crate::error::HpkeProtoError;
+
+// L'RFC 9180 (sezione 9.5) richiede almeno 32 byte di entropia nella PSK
+pub const MIN_PSK_LEN: usize = 32;
+
+#[derive(Clone, Default)]
+pub struct PskStore {
+    psks: HashMap<Vec<u8>, Vec<u8>>,
+    // ordine di inserimento, usato per offrire gli ID sempre nello stesso ordine
+    ids: Vec<Vec<u8>>
+}
+
+impl PskStore {
+    pub fn new() -> PskStore {
+        PskStore::default()
+    }
+
+    // Aggiunge una PSK, rifiutando ID vuoti e PSK troppo corte
+    pub fn insert(&mut self, psk_id: &[u8], psk: &[u8]) -> Result<(), HpkeProtoError> {
+        if psk_id.is_empty() {
+            return Err(AgileHpkeError::InvalidInput("PSK_ID vuoto").into());
+        }
+        if psk.len() < MIN_PSK_LEN {
+            return Err(AgileHpkeError::InvalidInput("PSK più corta di 32 byte").into());
+        }
+        if self.psks.insert(psk_id.to_vec(), psk.to_vec()).is_none() {
+            self.ids.push(psk_id.to_vec());
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, psk_id: &[u8]) -> Option<&[u8]> {
+        self.psks.get(psk_id).map(|psk| psk.as_slice())
+    }
+
+    pub fn contains(&self, psk_id: &[u8]) -> bool {
+        self.psks.contains_key(psk_id)
+    }
+
+    pub fn ids(&self) -> &[Vec<u8>] {
+        &self.ids
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
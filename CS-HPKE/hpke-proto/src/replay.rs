@@ -0,0 +1,227 @@
+// Protezione dai replay.
+// - Sessioni con contesto: finestra scorrevole sui numeri di sequenza
+//   (come in IPsec, RFC 4303 3.4.3), una per epoca KRI.
+// - Messaggi single-shot e apertura di sessione: cache delle encapped key
+//   già viste, ognuna valida per un TTL. Il destinatario HPKE non aggiunge
+//   casualità, quindi una encapped key ripetuta è sempre un replay.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Numero di sequenze tracciate dietro la più alta ricevuta
+pub const REPLAY_WINDOW: u64 = 64;
+
+pub const DEFAULT_EK_TTL: Duration = Duration::from_secs(300);
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError {
+    // Sequenza già ricevuta in questa epoca
+    Replayed { kri: u32, seq: u64 },
+    // Sequenza più vecchia della finestra: non si può sapere se è un replay
+    TooOld { kri: u32, seq: u64 },
+    // Encapped key già usata entro il TTL
+    ReusedEncappedKey
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Replayed { kri, seq } =>
+                write!(f, "replay: messaggio {} dell'epoca {} già ricevuto", seq, kri),
+            ReplayError::TooOld { kri, seq } =>
+                write!(f, "replay: messaggio {} dell'epoca {} fuori dalla finestra", seq, kri),
+            ReplayError::ReusedEncappedKey => write!(f, "replay: encapped key già usata"),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+
+// Finestra scorrevole: bit i di `bitmap` = sequenza (highest - i) ricevuta
+#[derive(Debug, Clone, Default)]
+pub struct SlidingWindow {
+    highest: Option<u64>,
+    bitmap: u64
+}
+
+impl SlidingWindow {
+    pub fn new() -> SlidingWindow {
+        SlidingWindow::default()
+    }
+
+    // Controlla la sequenza senza registrarla: va registrata con update()
+    // solo dopo aver verificato il tag, altrimenti un attaccante potrebbe
+    // far avanzare la finestra con pacchetti falsi
+    pub fn check(&self, kri: u32, seq: u64) -> Result<(), ReplayError> {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => return Ok(()),
+        };
+        if seq > highest {
+            return Ok(());
+        }
+        let offset = highest - seq;
+        if offset >= REPLAY_WINDOW {
+            return Err(ReplayError::TooOld { kri, seq });
+        }
+        if self.bitmap & (1 << offset) != 0 {
+            return Err(ReplayError::Replayed { kri, seq });
+        }
+        Ok(())
+    }
+
+    pub fn update(&mut self, seq: u64) {
+        match self.highest {
+            None => {
+                self.highest = Some(seq);
+                self.bitmap = 1;
+            }
+            Some(highest) if seq > highest => {
+                let shift = seq - highest;
+                self.bitmap = if shift >= REPLAY_WINDOW { 0 } else { self.bitmap << shift };
+                self.bitmap |= 1;
+                self.highest = Some(seq);
+            }
+            Some(highest) => {
+                let offset = highest - seq;
+                if offset < REPLAY_WINDOW {
+                    self.bitmap |= 1 << offset;
+                }
+            }
+        }
+    }
+}
+
+
+// Encapped key viste di recente, con scadenza
+pub struct EncappedKeyCache {
+    ttl: Duration,
+    seen: HashMap<Vec<u8>, Instant>
+}
+
+impl EncappedKeyCache {
+    pub fn new(ttl: Duration) -> EncappedKeyCache {
+        EncappedKeyCache { ttl, seen: HashMap::new() }
+    }
+
+    // Registra la encapped key, rifiutandola se è già stata vista entro il TTL.
+    // I messaggi più vecchi del TTL vanno rifiutati a un livello superiore
+    // (es. con un timestamp autenticato), altrimenti tornano riproducibili
+    pub fn check_and_insert(&mut self, encapped_key: &[u8]) -> Result<(), ReplayError> {
+        let now = Instant::now();
+        let ttl = self.ttl;
+        self.seen.retain(|_, seen_at| now.duration_since(*seen_at) < ttl);
+
+        if self.seen.contains_key(encapped_key) {
+            return Err(ReplayError::ReusedEncappedKey);
+        }
+        self.seen.insert(encapped_key.to_vec(), now);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+impl Default for EncappedKeyCache {
+    fn default() -> EncappedKeyCache {
+        EncappedKeyCache::new(DEFAULT_EK_TTL)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn window_accepts_first_and_newer() {
+        let mut window = SlidingWindow::new();
+        assert_eq!(window.check(0, 0), Ok(()));
+        window.update(0);
+        assert_eq!(window.check(0, 0), Err(ReplayError::Replayed { kri: 0, seq: 0 }));
+
+        for seq in 1..10 {
+            assert_eq!(window.check(0, seq), Ok(()));
+            window.update(seq);
+        }
+        // Un salto in avanti oltre la finestra è ammesso
+        assert_eq!(window.check(0, 1000), Ok(()));
+    }
+
+    #[test]
+    fn window_boundaries() {
+        let mut window = SlidingWindow::new();
+        window.update(REPLAY_WINDOW);
+
+        // highest - 63 è l'ultima sequenza dentro la finestra, highest - 64 la prima fuori
+        assert_eq!(window.check(1, 1), Ok(()));
+        assert_eq!(window.check(1, 0), Err(ReplayError::TooOld { kri: 1, seq: 0 }));
+
+        window.update(1);
+        assert_eq!(window.check(1, 1), Err(ReplayError::Replayed { kri: 1, seq: 1 }));
+        assert_eq!(window.check(1, REPLAY_WINDOW), Err(ReplayError::Replayed { kri: 1, seq: REPLAY_WINDOW }));
+        assert_eq!(window.check(1, REPLAY_WINDOW - 1), Ok(()));
+    }
+
+    #[test]
+    fn window_out_of_order_and_duplicates() {
+        let mut window = SlidingWindow::new();
+        for seq in [5, 3, 7, 4] {
+            assert_eq!(window.check(2, seq), Ok(()));
+            window.update(seq);
+        }
+        for seq in [3, 4, 5, 7] {
+            assert_eq!(window.check(2, seq), Err(ReplayError::Replayed { kri: 2, seq }));
+        }
+        assert_eq!(window.check(2, 6), Ok(()));
+
+        // Avanzando, le sequenze ancora in finestra restano registrate
+        window.update(7 + REPLAY_WINDOW - 1);
+        assert_eq!(window.check(2, 7), Err(ReplayError::Replayed { kri: 2, seq: 7 }));
+        assert_eq!(window.check(2, 6), Err(ReplayError::TooOld { kri: 2, seq: 6 }));
+    }
+
+    #[test]
+    fn window_shift_past_width_clears_bitmap() {
+        let mut window = SlidingWindow::new();
+        window.update(0);
+        window.update(REPLAY_WINDOW * 2);
+        assert_eq!(window.check(0, REPLAY_WINDOW + 1), Ok(()));
+        assert_eq!(window.check(0, REPLAY_WINDOW), Err(ReplayError::TooOld { kri: 0, seq: REPLAY_WINDOW }));
+    }
+
+    #[test]
+    fn encapped_key_rejected_within_ttl() {
+        let mut cache = EncappedKeyCache::default();
+        assert!(cache.is_empty());
+        assert_eq!(cache.check_and_insert(b"ek-1"), Ok(()));
+        assert_eq!(cache.check_and_insert(b"ek-2"), Ok(()));
+        assert_eq!(cache.check_and_insert(b"ek-1"), Err(ReplayError::ReusedEncappedKey));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn encapped_key_expires_after_ttl() {
+        let ttl = Duration::from_millis(50);
+        let mut cache = EncappedKeyCache::new(ttl);
+        assert_eq!(cache.check_and_insert(b"ek-1"), Ok(()));
+
+        thread::sleep(ttl + Duration::from_millis(10));
+        // Le voci scadute vengono rimosse al primo inserimento successivo
+        assert_eq!(cache.check_and_insert(b"ek-2"), Ok(()));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.check_and_insert(b"ek-1"), Ok(()));
+        assert_eq!(cache.check_and_insert(b"ek-1"), Err(ReplayError::ReusedEncappedKey));
+    }
+}
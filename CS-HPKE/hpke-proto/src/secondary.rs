@@ -0,0 +1,498 @@
+// Entità secondarie (PDMv2): SC e SS si registrano presso il rispettivo
+// primario (PC e PS) e ricevono la chiave del flusso SC -> SS, derivata da
+// PC e PS con il key schedule sul loro contesto HPKE. Con quella chiave come
+// PSK, SC e SS eseguono tra loro l'handshake in modo Psk e si scambiano
+// dati cifrati senza passare dai primari.
+//
+// Registrazione (la connessione TCP è aperta dal secondario):
+//   Sec -> Pri: SecondaryId
+//   Pri e Sec negoziano con handshake::handle_server / handle_client, in cui
+//   il primario fa da client HPKE e il secondario da server HPKE; è ammesso
+//   solo il modo Psk con la PSK di registrazione condivisa in anticipo
+//   (registration.psk nella configurazione), che autentica entrambi i lati
+//   Pri -> Sec: EncappedKey, Ciphertext, AssociatedData, TagBytes
+//   (il ciphertext contiene il materiale di chiave del flusso)
+
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::{rngs::StdRng, SeedableRng};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::agility::{self, AgileEncappedKey, AgileHpkeError, AgileKeypair, AgileOpModeR, AgileOpModeS, AgilePublicKey};
+use crate::ciphersuite::{AEADtype, HpkeMode, KDFtype, KEMtype, SelectionPolicy};
+use crate::data_packets_manager::{self, DataType, PacketDecoder, read_packet, unexpected_packet};
+use crate::handshake::{
+    self, Negotiated, ack_requested, handle_packet, send_end_of_message_async, send_message_ack_async, send_packet,
+    wait_message_ack
+};
+use crate::error::HpkeProtoError;
+use crate::trust::{AuthorizedClients, ServerKeyPolicy};
+use crate::keyschedule::{self, Exporter, FiveTuple, FLOW_KEY_LEN, PROTO_TCP};
+use crate::kri::KriHeader;
+use crate::psk::PskStore;
+use crate::replay::EncappedKeyCache;
+use crate::session::{ReceiverSession, SenderSession};
+use crate::transport::{self, PacketStream};
+
+
+// ##### TOPOLOGIA: SC e SS devono conoscersi in anticipo #####
+
+// Sezione [registration] della configurazione: server.toml per PS e SS,
+// client.toml per PC e SC
+pub struct Registration {
+    // Indirizzo su cui il primario accetta la registrazione del secondario
+    pub primary: SocketAddr,
+    // Attesa massima del secondario, anche per connettersi e registrarsi; None = nessun limite
+    pub timeout: Option<Duration>,
+    pub sc_id: Vec<u8>,
+    pub ss_id: Vec<u8>,
+    // Indirizzo dichiarato da SC e indirizzo di ascolto di SS: identificano il flusso
+    pub sc_addr: SocketAddr,
+    pub ss_addr: SocketAddr,
+    // PSK di registrazione, condivisa tra il primario e il suo secondario
+    pub psks: PskStore
+}
+
+impl Registration {
+    // Flusso SC -> SS per cui PC e PS derivano la chiave
+    pub fn flow_tuple(&self) -> FiveTuple {
+        FiveTuple::new(self.sc_addr, self.ss_addr, PROTO_TCP)
+    }
+
+    fn registration_psks(&self) -> Result<&PskStore, HpkeProtoError> {
+        if self.psks.is_empty() {
+            return Err(HpkeProtoError::config("registration.psk: serve la PSK di registrazione condivisa col primario"));
+        }
+        Ok(&self.psks)
+    }
+}
+
+const INFO_STR: &[u8] = b"PDMv2 secondary registration";
+const MATERIAL_AD: &[u8] = b"PDMv2 flow key material";
+
+// Intervallo con cui il primario controlla se il secondario si è connesso
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
+
+// Negoziazione tra entità secondarie o con i primari: sempre e solo modo Psk
+pub fn secondary_policy() -> SelectionPolicy {
+    SelectionPolicy { modes: vec![HpkeMode::Psk], ..SelectionPolicy::default() }
+}
+
+
+// Materiale di chiave consegnato al secondario:
+// [len psk_id (u16 BE) | psk_id | chiave del flusso]
+pub struct FlowKeyMaterial {
+    pub psk_id: Vec<u8>,
+    pub key: Vec<u8>
+}
+
+impl FlowKeyMaterial {
+    // Deriva dal contesto del primario la chiave del flusso SC -> SS
+    pub fn derive<E: Exporter>(exporter: &E, registration: &Registration) -> Result<FlowKeyMaterial, HpkeProtoError> {
+        let key = keyschedule::derive_flow_key(
+            exporter,
+            &registration.flow_tuple(),
+            &registration.sc_id,
+            &registration.ss_id,
+            FLOW_KEY_LEN
+        )?;
+        let mut psk_id = registration.sc_id.clone();
+        psk_id.extend_from_slice(b"->");
+        psk_id.extend_from_slice(&registration.ss_id);
+        Ok(FlowKeyMaterial { psk_id, key })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, HpkeProtoError> {
+        let len = u16::try_from(self.psk_id.len())
+            .map_err(|_| HpkeProtoError::framing("PSK_ID del flusso troppo lungo"))?;
+        let mut out = len.to_be_bytes().to_vec();
+        out.extend_from_slice(&self.psk_id);
+        out.extend_from_slice(&self.key);
+        Ok(out)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<FlowKeyMaterial, HpkeProtoError> {
+        let malformed = || HpkeProtoError::framing("materiale di chiave malformato");
+        let len_bytes: [u8; 2] = bytes.get(..2).ok_or_else(malformed)?.try_into().unwrap();
+        let id_end = 2 + u16::from_be_bytes(len_bytes) as usize;
+        let psk_id = bytes.get(2..id_end).ok_or_else(malformed)?.to_vec();
+        let key = bytes[id_end..].to_vec();
+        if key.is_empty() {
+            return Err(malformed());
+        }
+        Ok(FlowKeyMaterial { psk_id, key })
+    }
+
+    // Archivio PSK con la chiave del flusso, usato da SC e SS per l'handshake diretto
+    pub fn to_psk_store(&self) -> Result<PskStore, HpkeProtoError> {
+        let mut psks = PskStore::new();
+        psks.insert(&self.psk_id, &self.key)?;
+        Ok(psks)
+    }
+}
+
+
+// Messaggio cifrato; il KRI manca solo nei messaggi cifrati col contesto HPKE
+pub struct SealedMessage {
+    pub kri: Option<KriHeader>,
+    pub ciphertext: Vec<u8>,
+    pub associated_data: Vec<u8>,
+    pub tag: Vec<u8>
+}
+
+// Invia (KRI), ciphertext, associated data e tag in fila e chiude il messaggio.
+// Con ack_requested il chiamante deve poi aspettare il MessageAck
+pub fn send_sealed(stream: &TcpStream, message: SealedMessage, ack_requested: bool) -> Result<(), HpkeProtoError> {
+    // Si invia soltanto: il decoder resta vuoto
+    transport::block_on(stream, &mut PacketDecoder::new(), |blocking, stream| {
+        blocking.send(send_sealed_async(stream, message, ack_requested))
+    })
+}
+
+// Versione async di send_sealed: i pacchetti partono tutti con EndOfMessage
+pub async fn send_sealed_async<S>(stream: &mut PacketStream<S>, message: SealedMessage, ack_requested: bool) -> Result<(), HpkeProtoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin
+{
+    if let Some(header) = message.kri {
+        let pack = data_packets_manager::create_packet(DataType::KeyRotationIndex, header.to_bytes().to_vec());
+        transport::queue_packet(stream, pack, "KRI").await?;
+    }
+    for (dt, payload, what) in [
+        (DataType::Ciphertext, message.ciphertext, "Ciphertext"),
+        (DataType::AssociatedData, message.associated_data, "AssociatedData"),
+        (DataType::TagBytes, message.tag, "Tag"),
+    ] {
+        transport::queue_packet(stream, data_packets_manager::create_packet(dt, payload), what).await?;
+    }
+    send_end_of_message_async(stream, ack_requested).await
+}
+
+// Riceve un messaggio fino a EndOfMessage, inviando l'ack finale se richiesto.
+// None se il peer ha chiuso la connessione
+pub fn read_sealed(stream: &TcpStream, decoder: &mut PacketDecoder) -> Result<Option<SealedMessage>, HpkeProtoError> {
+    transport::block_on(stream, decoder, |blocking, stream| blocking.wait(read_sealed_async(stream)))
+}
+
+// Versione async di read_sealed
+pub async fn read_sealed_async<S>(stream: &mut PacketStream<S>) -> Result<Option<SealedMessage>, HpkeProtoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin
+{
+    let mut kri = None;
+    let mut ct = None;
+    let mut ad = None;
+    let mut tb = None;
+    loop {
+        let pack = match transport::read_packet(stream).await? {
+            Some(pack) => pack,
+            None => return Ok(None),
+        };
+        handle_packet(&pack);
+        match pack.data_type {
+            // Il KRI precede sempre gli altri pacchetti del messaggio
+            DataType::KeyRotationIndex if ct.is_none() && ad.is_none() && tb.is_none() =>
+                kri = Some(KriHeader::from_bytes(&pack.payload)?),
+            DataType::Ciphertext => ct = Some(pack.payload),
+            DataType::AssociatedData => ad = Some(pack.payload),
+            DataType::TagBytes => tb = Some(pack.payload),
+            DataType::EndOfMessage => {
+                let (Some(ciphertext), Some(associated_data), Some(tag)) = (ct, ad, tb) else {
+                    return Err(HpkeProtoError::framing("messaggio incompleto"));
+                };
+                if ack_requested(&pack)? {
+                    send_message_ack_async(stream).await?;
+                }
+                return Ok(Some(SealedMessage { kri, ciphertext, associated_data, tag }));
+            }
+            other => return Err(unexpected_packet(other)),
+        }
+    }
+}
+
+// Riceve la encapped key e apre la sessione del destinatario.
+// Le encapped key già viste vengono rifiutate come replay.
+// info viene legato al transcript della negoziazione
+pub fn open_receiver_session(
+    stream: &TcpStream,
+    decoder: &mut PacketDecoder,
+    negotiated: &Negotiated,
+    psks: &PskStore,
+    keypair: &AgileKeypair,
+    info: &[u8],
+    ek_cache: &mut EncappedKeyCache
+) -> Result<ReceiverSession, HpkeProtoError> {
+    transport::block_on(stream, decoder, |blocking, stream| blocking.wait(open_receiver_session_async(
+        stream,
+        negotiated,
+        psks,
+        keypair,
+        info,
+        ek_cache
+    )))
+}
+
+// Versione async di open_receiver_session
+pub async fn open_receiver_session_async<S>(
+    stream: &mut PacketStream<S>,
+    negotiated: &Negotiated,
+    psks: &PskStore,
+    keypair: &AgileKeypair,
+    info: &[u8],
+    ek_cache: &mut EncappedKeyCache
+) -> Result<ReceiverSession, HpkeProtoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin
+{
+    let pack = transport::read_packet(stream).await?
+        .ok_or_else(|| HpkeProtoError::closed("connessione chiusa prima della encapped key"))?;
+    if pack.data_type != DataType::EncappedKey {
+        return Err(unexpected_packet(pack.data_type));
+    }
+    handle_packet(&pack);
+
+    let bundle = negotiated.psk_bundle(psks)?
+        .ok_or_else(|| HpkeProtoError::negotiation("il peer non ha usato una PSK"))?;
+    let encapped_key = AgileEncappedKey { kem: negotiated.suite.kem, bytes: pack.payload };
+    let session = ReceiverSession::new(
+        &negotiated.suite,
+        &AgileOpModeR::Psk(bundle),
+        keypair.private_key(),
+        &encapped_key,
+        &negotiated.session_info(info)
+    )?;
+    // Si registra solo una encapped key valida, così i falsi non riempiono la cache
+    ek_cache.check_and_insert(&encapped_key.bytes).map_err(AgileHpkeError::from)?;
+    Ok(session)
+}
+
+// Apre la sessione del mittente e invia la encapped key.
+// info viene legato al transcript della negoziazione
+pub fn open_sender_session(
+    stream: &TcpStream,
+    negotiated: &Negotiated,
+    psks: &PskStore,
+    server_pk: &[u8],
+    info: &[u8]
+) -> Result<SenderSession, HpkeProtoError> {
+    // Si invia soltanto: il decoder resta vuoto
+    transport::block_on(stream, &mut PacketDecoder::new(), |blocking, stream| blocking.send(open_sender_session_async(
+        stream,
+        negotiated,
+        psks,
+        server_pk,
+        info
+    )))
+}
+
+// Versione async di open_sender_session
+pub async fn open_sender_session_async<S>(
+    stream: &mut PacketStream<S>,
+    negotiated: &Negotiated,
+    psks: &PskStore,
+    server_pk: &[u8],
+    info: &[u8]
+) -> Result<SenderSession, HpkeProtoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin
+{
+    let bundle = negotiated.psk_bundle(psks)?
+        .ok_or_else(|| HpkeProtoError::negotiation("il peer non ha usato una PSK"))?;
+    let server_pk: AgilePublicKey = agility::agile_pubkey_from_bytes(negotiated.suite.kem, server_pk)?;
+    let (session, encapped_key) = SenderSession::new(
+        &negotiated.suite,
+        &AgileOpModeS::Psk(bundle),
+        &server_pk,
+        &negotiated.session_info(info),
+        &mut StdRng::from_entropy()
+    )?;
+    let ek_pack = data_packets_manager::create_packet(DataType::EncappedKey, encapped_key.bytes);
+    transport::send_packet(stream, ek_pack, "EncappedKey").await?;
+    Ok(session)
+}
+
+
+// ###################################################################
+// ########################## LATO PRIMARIO ##########################
+// ###################################################################
+
+// Accetta la registrazione del secondario atteso e gli consegna il materiale di chiave.
+// Il listener è condiviso tra le connessioni del primario, ma resta bloccato
+// solo per il tempo di ogni accept non bloccante
+pub fn serve_registration(
+    listener: &Mutex<TcpListener>,
+    registration: &Registration,
+    expected_id: &[u8],
+    material: &FlowKeyMaterial
+) -> Result<(), HpkeProtoError> {
+    let psks = registration.registration_psks()?;
+    println!("\nIn attesa della registrazione di {}...", String::from_utf8_lossy(expected_id));
+    let (mut stream, remote) = accept_secondary(listener, registration.timeout)?;
+    stream.set_read_timeout(registration.timeout)?;
+    stream.set_write_timeout(registration.timeout)?;
+    let mut decoder = PacketDecoder::new();
+
+    // => SecondaryId
+    let pack = read_packet(&mut stream, &mut decoder)?
+        .ok_or_else(|| HpkeProtoError::closed("il secondario ha chiuso la connessione"))?;
+    if pack.data_type != DataType::SecondaryId {
+        return Err(unexpected_packet(pack.data_type));
+    }
+    handle_packet(&pack);
+    if pack.payload != expected_id {
+        return Err(HpkeProtoError::negotiation("secondario sconosciuto"));
+    }
+
+    // Il primario fa da client HPKE: solo modo Psk con la PSK di registrazione
+    let mut server_pk = vec![];
+    let negotiated = handshake::handle_server(
+        remote,
+        &mut stream,
+        &mut decoder,
+        &mut server_pk,
+        &KEMtype::to_vect(),
+        &KDFtype::to_vect(),
+        &AEADtype::to_vect(),
+        &[HpkeMode::Psk],
+        psks,
+        &[],
+        // Il secondario è autenticato dalla PSK di registrazione
+        &ServerKeyPolicy::AcceptAny,
+        None
+    )?;
+
+    let mut session = open_sender_session(&stream, &negotiated, psks, &server_pk, INFO_STR)?;
+    let (ciphertext, tag) = session.seal(&material.to_bytes()?, MATERIAL_AD)?;
+    // Il primario prosegue solo quando il secondario conferma la ricezione
+    send_sealed(&stream, SealedMessage {
+        kri: None,
+        ciphertext,
+        associated_data: MATERIAL_AD.to_vec(),
+        tag
+    }, true)?;
+    wait_message_ack(&stream, &mut decoder)?;
+
+    println!("Materiale di chiave consegnato a {}", String::from_utf8_lossy(expected_id));
+    Ok(())
+}
+
+// Aspetta la connessione del secondario per al più timeout.
+// Il listener è non bloccante, come quello di connections::serve
+fn accept_secondary(listener: &Mutex<TcpListener>, timeout: Option<Duration>) -> Result<(TcpStream, SocketAddr), HpkeProtoError> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        // Un thread andato in panic col lock non rende il listener inutilizzabile
+        let accepted = listener.lock().unwrap_or_else(PoisonError::into_inner).accept();
+        match accepted {
+            Ok((stream, remote)) => {
+                stream.set_nonblocking(false)?;
+                return Ok((stream, remote));
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    return Err(HpkeProtoError::timed_out("nessun secondario si è registrato entro il timeout"));
+                }
+                thread::sleep(ACCEPT_POLL);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Listener delle registrazioni, non bloccante per accept_secondary
+pub fn bind_registration(registration: &Registration) -> Result<Mutex<TcpListener>, HpkeProtoError> {
+    let listener = TcpListener::bind(registration.primary)?;
+    listener.set_nonblocking(true)?;
+    Ok(Mutex::new(listener))
+}
+
+
+// ###################################################################
+// ######################### LATO SECONDARIO #########################
+// ###################################################################
+
+// Si registra presso il primario e ne riceve il materiale di chiave del flusso
+pub fn register(registration: &Registration, id: &[u8]) -> Result<FlowKeyMaterial, HpkeProtoError> {
+    let psks = registration.registration_psks()?;
+    let stream = match registration.timeout {
+        Some(timeout) => TcpStream::connect_timeout(&registration.primary, timeout)?,
+        None => TcpStream::connect(registration.primary)?,
+    };
+    stream.set_read_timeout(registration.timeout)?;
+    stream.set_write_timeout(registration.timeout)?;
+    let mut decoder = PacketDecoder::new();
+
+    println!("Registrazione presso il primario {}", registration.primary);
+
+    // => SecondaryId
+    let id_pack = data_packets_manager::create_packet(DataType::SecondaryId, id.to_vec()).group()?;
+    send_packet(&stream, &id_pack, "SecondaryId")?;
+
+    // Il secondario fa da server HPKE con una chiave effimera
+    let mut csprng = StdRng::from_entropy();
+    let policy = secondary_policy();
+    let keys: Vec<AgileKeypair> = policy.kems.iter()
+        .filter_map(|kem| agility::agile_gen_keypair(*kem, &mut csprng).ok())
+        .collect();
+
+    let negotiated = handshake::handle_client(&stream, &mut decoder, &keys, &policy, psks, &AuthorizedClients::default(), None)?
+        .ok_or_else(|| HpkeProtoError::closed("il primario ha chiuso la connessione"))?;
+    let keypair = keys.iter().find(|k| k.kem() == negotiated.suite.kem)
+        .ok_or_else(|| HpkeProtoError::negotiation("nessuna chiave per il KEM negoziato"))?;
+
+    // La chiave del secondario è effimera: basta una cache locale
+    let mut ek_cache = EncappedKeyCache::default();
+    let mut session = open_receiver_session(
+        &stream,
+        &mut decoder,
+        &negotiated,
+        psks,
+        keypair,
+        INFO_STR,
+        &mut ek_cache
+    )?;
+    let sealed = read_sealed(&stream, &mut decoder)?
+        .ok_or_else(|| HpkeProtoError::closed("materiale di chiave non ricevuto"))?;
+    let material = session.open(&sealed.ciphertext, &sealed.associated_data, &sealed.tag)?;
+
+    FlowKeyMaterial::from_bytes(&material)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn material(psk_id: Vec<u8>) -> FlowKeyMaterial {
+        FlowKeyMaterial { psk_id, key: vec![7; FLOW_KEY_LEN] }
+    }
+
+    #[test]
+    fn material_round_trip() {
+        let bytes = material(b"sc->ss".to_vec()).to_bytes().unwrap();
+        let decoded = FlowKeyMaterial::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.psk_id, b"sc->ss");
+        assert_eq!(decoded.key, vec![7; FLOW_KEY_LEN]);
+    }
+
+    #[test]
+    fn oversize_psk_id_is_an_error() {
+        let long_id = vec![b'x'; u16::MAX as usize + 1];
+        assert!(matches!(material(long_id.clone()).to_bytes(), Err(HpkeProtoError::Framing(_))));
+        assert!(material(long_id[1..].to_vec()).to_bytes().is_ok());
+    }
+
+    #[test]
+    fn malformed_material_is_rejected() {
+        let bytes = material(b"sc->ss".to_vec()).to_bytes().unwrap();
+        // Senza chiave, con il PSK_ID troncato o senza lunghezza
+        assert!(FlowKeyMaterial::from_bytes(&bytes[..2 + 6]).is_err());
+        assert!(FlowKeyMaterial::from_bytes(&bytes[..5]).is_err());
+        assert!(FlowKeyMaterial::from_bytes(&bytes[..1]).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+// Sessione HPKE di lunga durata: il contesto viene creato una sola volta
+// (un solo encap/decap) e poi usato per cifrare e decifrare un flusso di
+// messaggi. Il nonce di ogni messaggio dipende dal numero di sequenza del
+// contesto (RFC 9180, sezione 5.2), quindi i messaggi vanno aperti
+// nello stesso ordine in cui sono stati sigillati.
+// seal/open cifrano col contesto stesso (materiale della registrazione dei
+// secondari, secondary.rs); i messaggi dell'utente usano invece le chiavi di
+// epoca che kri.rs deriva con export().
+
+use rand::{CryptoRng, RngCore};
+
+use crate::agility::{
+    self, AgileAeadCtxR, AgileAeadCtxS, AgileEncappedKey, AgileHpkeError, AgileOpModeR, AgileOpModeS,
+    AgilePrivateKey, AgilePublicKey
+};
+use crate::ciphersuite::Ciphersuite;
+
+
+// Lato mittente
+pub struct SenderSession {
+    ctx: Box<dyn AgileAeadCtxS>,
+    seq: u64,
+    exhausted: bool
+}
+
+impl SenderSession {
+    // Esegue encap() verso il destinatario: la encapped key va inviata una sola volta
+    pub fn new<R: CryptoRng + RngCore>(
+        suite: &Ciphersuite,
+        mode: &AgileOpModeS,
+        pk_recip: &AgilePublicKey,
+        info: &[u8],
+        csprng: &mut R,
+    ) -> Result<(SenderSession, AgileEncappedKey), AgileHpkeError> {
+        let (encapped_key, ctx) = agility::agile_setup_sender(suite, mode, pk_recip, info, csprng)?;
+        Ok((SenderSession { ctx, seq: 0, exhausted: false }, encapped_key))
+    }
+
+    // Cifra il prossimo messaggio della sessione, rende (ciphertext, tag)
+    pub fn seal(&mut self, msg: &[u8], aad: &[u8]) -> Result<(Vec<u8>, Vec<u8>), AgileHpkeError> {
+        let next = self.next_seq()?;
+        let mut ciphertext = msg.to_vec();
+        let tag = match self.ctx.seal_in_place_detached(&mut ciphertext, aad) {
+            Ok(tag) => tag,
+            Err(e) => {
+                if let AgileHpkeError::MessageLimitReached = e {
+                    println!("Sequenza dei nonce esaurita dopo {} messaggi", self.seq);
+                    self.exhausted = true;
+                }
+                return Err(e);
+            }
+        };
+        self.seq = next;
+        Ok((ciphertext, tag))
+    }
+
+    // Il contatore non può ripartire da 0: finito, la sessione è esaurita
+    fn next_seq(&mut self) -> Result<u64, AgileHpkeError> {
+        if self.exhausted {
+            return Err(AgileHpkeError::MessageLimitReached);
+        }
+        self.seq.checked_add(1).ok_or_else(|| {
+            println!("Sequenza dei messaggi esaurita dopo {} messaggi", self.seq);
+            self.exhausted = true;
+            AgileHpkeError::MessageLimitReached
+        })
+    }
+
+    // Numero di messaggi già sigillati
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    // export(exporter_context, L): segreto di len byte, uguale sui due lati
+    pub fn export(&self, exporter_context: &[u8], len: usize) -> Result<Vec<u8>, AgileHpkeError> {
+        let mut secret = vec![0u8; len];
+        self.ctx.export(exporter_context, &mut secret)?;
+        Ok(secret)
+    }
+}
+
+
+// Lato destinatario
+pub struct ReceiverSession {
+    ctx: Box<dyn AgileAeadCtxR>,
+    seq: u64,
+    exhausted: bool
+}
+
+impl ReceiverSession {
+    // Esegue decap() della encapped key ricevuta all'apertura della sessione
+    pub fn new(
+        suite: &Ciphersuite,
+        mode: &AgileOpModeR,
+        sk_recip: &AgilePrivateKey,
+        encapped_key: &AgileEncappedKey,
+        info: &[u8],
+    ) -> Result<ReceiverSession, AgileHpkeError> {
+        let ctx = agility::agile_setup_receiver(suite, mode, sk_recip, encapped_key, info)?;
+        Ok(ReceiverSession { ctx, seq: 0, exhausted: false })
+    }
+
+    // Decifra il prossimo messaggio della sessione
+    pub fn open(&mut self, ciphertext: &[u8], aad: &[u8], tag: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
+        let next = self.next_seq()?;
+        let mut plaintext = ciphertext.to_vec();
+        if let Err(e) = self.ctx.open_in_place_detached(&mut plaintext, aad, tag) {
+            if let AgileHpkeError::MessageLimitReached = e {
+                println!("Sequenza dei nonce esaurita dopo {} messaggi", self.seq);
+                self.exhausted = true;
+            }
+            return Err(e);
+        }
+        self.seq = next;
+        Ok(plaintext)
+    }
+
+    fn next_seq(&mut self) -> Result<u64, AgileHpkeError> {
+        if self.exhausted {
+            return Err(AgileHpkeError::MessageLimitReached);
+        }
+        self.seq.checked_add(1).ok_or_else(|| {
+            println!("Sequenza dei messaggi esaurita dopo {} messaggi", self.seq);
+            self.exhausted = true;
+            AgileHpkeError::MessageLimitReached
+        })
+    }
+
+    // Numero di messaggi già aperti
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    // export(exporter_context, L): segreto di len byte, uguale sui due lati
+    pub fn export(&self, exporter_context: &[u8], len: usize) -> Result<Vec<u8>, AgileHpkeError> {
+        let mut secret = vec![0u8; len];
+        self.ctx.export(exporter_context, &mut secret)?;
+        Ok(secret)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::ciphersuite::{AEADtype, KDFtype, KEMtype};
+
+    const INFO: &[u8] = b"session test";
+    const AAD: &[u8] = b"associated data";
+
+    fn sessions(aead: AEADtype) -> (SenderSession, ReceiverSession) {
+        let suite = Ciphersuite { kem: KEMtype::X25519HkdfSha256, kdf: KDFtype::HkdfSha256, aead };
+        let mut csprng = StdRng::from_entropy();
+        let keypair = agility::agile_gen_keypair(suite.kem, &mut csprng).unwrap();
+        let (sender, encapped_key) = SenderSession::new(&suite, &AgileOpModeS::Base, keypair.public_key(), INFO, &mut csprng).unwrap();
+        let receiver = ReceiverSession::new(&suite, &AgileOpModeR::Base, keypair.private_key(), &encapped_key, INFO).unwrap();
+        (sender, receiver)
+    }
+
+    #[test]
+    fn messages_in_order() {
+        let (mut sender, mut receiver) = sessions(AEADtype::AesGcm128);
+        for msg in [&b"primo"[..], b"secondo", b""] {
+            let (ciphertext, tag) = sender.seal(msg, AAD).unwrap();
+            assert_eq!(receiver.open(&ciphertext, AAD, &tag).unwrap(), msg);
+        }
+        assert_eq!(sender.seq(), 3);
+        assert_eq!(receiver.seq(), 3);
+    }
+
+    #[test]
+    fn out_of_order_does_not_open() {
+        let (mut sender, mut receiver) = sessions(AEADtype::ChaCha20Poly1305);
+        let first = sender.seal(b"primo", AAD).unwrap();
+        let second = sender.seal(b"secondo", AAD).unwrap();
+
+        // Il nonce dipende dalla sequenza: il secondo non si apre al posto del primo
+        assert!(receiver.open(&second.0, AAD, &second.1).is_err());
+        assert_eq!(receiver.seq(), 0);
+        assert_eq!(receiver.open(&first.0, AAD, &first.1).unwrap(), b"primo");
+        assert_eq!(receiver.open(&second.0, AAD, &second.1).unwrap(), b"secondo");
+    }
+
+    #[test]
+    fn exhausted_sessions_stay_closed() {
+        let (mut sender, mut receiver) = sessions(AEADtype::AesGcm256);
+        let (ciphertext, tag) = sender.seal(b"messaggio", AAD).unwrap();
+
+        sender.seq = u64::MAX;
+        receiver.seq = u64::MAX;
+        for _ in 0..2 {
+            assert!(matches!(sender.seal(b"messaggio", AAD), Err(AgileHpkeError::MessageLimitReached)));
+            assert!(matches!(receiver.open(&ciphertext, AAD, &tag), Err(AgileHpkeError::MessageLimitReached)));
+        }
+    }
+
+    #[test]
+    fn export_matches_on_both_sides() {
+        let (sender, receiver) = sessions(AEADtype::ExportOnlyAead);
+        let secret = sender.export(b"contesto", 32).unwrap();
+        assert_eq!(secret, receiver.export(b"contesto", 32).unwrap());
+        assert_ne!(secret, receiver.export(b"altro contesto", 32).unwrap());
+    }
+}
@@ -0,0 +1,214 @@
+// Annuncio firmato della chiave del server.
+// Il client invia un nonce casuale (ClientNonce) insieme alla sua offerta;
+// il server risponde, prima della PublicKey, con una firma Ed25519
+// (ServerKeySignature) fatta con la sua chiave di firma di lungo periodo su:
+//
+//   etichetta | KEM u16 | KDF u16 | AEAD u16 | modo u16 | nonce (32) | chiave pubblica
+//
+// Il client la verifica con la chiave di verifica configurata prima di
+// cifrare verso la chiave ricevuta: un attaccante non può sostituire la
+// chiave né cambiare la ciphersuite scelta, e il nonce impedisce di
+// riusare un annuncio registrato in una sessione precedente.
+//
+// La chiave di firma del server è salvata nello stesso contenitore HPKS
+// dell'archivio delle chiavi (permessi 0600, cifrato se c'è la passphrase),
+// con i 32 byte del seed come corpo.
+
+use std::fs;
+use std::path::Path;
+
+use ed25519_dalek::{Signature, Signer};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+use crate::agility::AgilePublicKey;
+use crate::ciphersuite::{Algorithm, Ciphersuite, HpkeMode};
+use crate::error::HpkeProtoError;
+use crate::keystore;
+use crate::trust;
+
+const ANNOUNCEMENT_LABEL: &[u8] = b"CS-HPKE server key announcement v1";
+
+pub const NONCE_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+const SEED_LEN: usize = 32;
+
+// Chiave di firma del server e chiave di verifica dei client
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+
+// Nonce del client per un nuovo annuncio
+pub fn client_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    StdRng::from_entropy().fill_bytes(&mut nonce);
+    nonce
+}
+
+// Messaggio firmato dal server
+fn announcement(suite: &Ciphersuite, mode: HpkeMode, nonce: &[u8], pk: &AgilePublicKey) -> Vec<u8> {
+    let mut msg = ANNOUNCEMENT_LABEL.to_vec();
+    msg.extend_from_slice(&suite.kem.to_bytes());
+    msg.extend_from_slice(&suite.kdf.to_bytes());
+    msg.extend_from_slice(&suite.aead.to_bytes());
+    msg.extend_from_slice(&mode.to_bytes());
+    msg.extend_from_slice(nonce);
+    msg.extend_from_slice(&pk.bytes);
+    msg
+}
+
+pub fn sign_announcement(
+    key: &SigningKey,
+    suite: &Ciphersuite,
+    mode: HpkeMode,
+    nonce: &[u8],
+    pk: &AgilePublicKey
+) -> Vec<u8> {
+    key.sign(&announcement(suite, mode, nonce, pk)).to_bytes().to_vec()
+}
+
+// Rende errore se la firma non è del server o non copre esattamente
+// la ciphersuite, il modo e la chiave ricevuti.
+// verify_strict rifiuta anche le firme malleabili e le chiavi di ordine basso
+pub fn verify_announcement(
+    key: &VerifyingKey,
+    suite: &Ciphersuite,
+    mode: HpkeMode,
+    nonce: &[u8],
+    pk: &AgilePublicKey,
+    signature: &[u8]
+) -> Result<(), HpkeProtoError> {
+    let signature = Signature::from_slice(signature)
+        .map_err(|_| HpkeProtoError::framing(format!("firma di {} byte invece di {}", signature.len(), SIGNATURE_LEN)))?;
+    key.verify_strict(&announcement(suite, mode, nonce, pk), &signature)
+        .map_err(|_| HpkeProtoError::UntrustedServerKey("firma dell'annuncio non valida".to_string()))
+}
+
+
+// Chiave di verifica scritta come 64 cifre esadecimali
+pub fn parse_verifying_key(text: &str) -> Result<VerifyingKey, HpkeProtoError> {
+    let invalid = || HpkeProtoError::trust_store(format!("chiave di verifica non valida: {}", text));
+    let mut bytes = [0u8; 32];
+    trust::parse_hex(text.trim(), &mut bytes).ok_or_else(invalid)?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| invalid())
+}
+
+pub fn verifying_key_to_hex(key: &VerifyingKey) -> String {
+    trust::to_hex(key.as_bytes())
+}
+
+
+// Chiave di firma del server: viene letta da path, o generata e salvata al primo avvio.
+// La passphrase è la stessa dell'archivio delle chiavi d'identità
+pub fn load_or_create_signing_key(path: &Path, passphrase: Option<&[u8]>) -> Result<SigningKey, HpkeProtoError> {
+    if path.exists() {
+        keystore::check_permissions(path)?;
+        let seed = keystore::open_body(&fs::read(path)?, passphrase)?;
+        let seed: [u8; SEED_LEN] = seed.try_into()
+            .map_err(|_| HpkeProtoError::key_store(format!("{}: non contiene una chiave di firma", path.display())))?;
+        return Ok(SigningKey::from_bytes(&seed));
+    }
+
+    let mut seed = [0u8; SEED_LEN];
+    StdRng::from_entropy().fill_bytes(&mut seed);
+    keystore::write_private(path, &keystore::seal_body(&seed, passphrase)?)?;
+    println!("Generata una nuova chiave di firma in {}", path.display());
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::agility;
+    use crate::ciphersuite::{AEADtype, KDFtype, KEMtype};
+
+    fn suite() -> Ciphersuite {
+        Ciphersuite {
+            kem: KEMtype::X25519HkdfSha256,
+            kdf: KDFtype::HkdfSha256,
+            aead: AEADtype::AesGcm128
+        }
+    }
+
+    fn signing_key() -> SigningKey {
+        let mut seed = [0u8; SEED_LEN];
+        StdRng::from_entropy().fill_bytes(&mut seed);
+        SigningKey::from_bytes(&seed)
+    }
+
+    fn public_key() -> AgilePublicKey {
+        let keypair = agility::agile_gen_keypair(suite().kem, &mut StdRng::from_entropy()).unwrap();
+        keypair.public_key().clone()
+    }
+
+    fn untrusted(result: Result<(), HpkeProtoError>) -> bool {
+        matches!(result, Err(HpkeProtoError::UntrustedServerKey(_)))
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = signing_key();
+        let nonce = client_nonce();
+        let pk = public_key();
+        let signature = sign_announcement(&key, &suite(), HpkeMode::Base, &nonce, &pk);
+        assert_eq!(signature.len(), SIGNATURE_LEN);
+        verify_announcement(&key.verifying_key(), &suite(), HpkeMode::Base, &nonce, &pk, &signature).unwrap();
+    }
+
+    #[test]
+    fn wrong_nonce_is_rejected() {
+        let key = signing_key();
+        let pk = public_key();
+        let signature = sign_announcement(&key, &suite(), HpkeMode::Base, &client_nonce(), &pk);
+        let result = verify_announcement(&key.verifying_key(), &suite(), HpkeMode::Base, &client_nonce(), &pk, &signature);
+        assert!(untrusted(result));
+    }
+
+    #[test]
+    fn wrong_suite_or_mode_is_rejected() {
+        let key = signing_key();
+        let nonce = client_nonce();
+        let pk = public_key();
+        let signature = sign_announcement(&key, &suite(), HpkeMode::Base, &nonce, &pk);
+
+        let other = Ciphersuite { aead: AEADtype::ChaCha20Poly1305, ..suite() };
+        let result = verify_announcement(&key.verifying_key(), &other, HpkeMode::Base, &nonce, &pk, &signature);
+        assert!(untrusted(result));
+        let result = verify_announcement(&key.verifying_key(), &suite(), HpkeMode::Psk, &nonce, &pk, &signature);
+        assert!(untrusted(result));
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let nonce = client_nonce();
+        let pk = public_key();
+        let signature = sign_announcement(&signing_key(), &suite(), HpkeMode::Base, &nonce, &pk);
+
+        // Firma di un'altra chiave di firma
+        let result = verify_announcement(&signing_key().verifying_key(), &suite(), HpkeMode::Base, &nonce, &pk, &signature);
+        assert!(untrusted(result));
+
+        // Firma giusta su un'altra chiave pubblica HPKE
+        let key = signing_key();
+        let signature = sign_announcement(&key, &suite(), HpkeMode::Base, &nonce, &pk);
+        let result = verify_announcement(&key.verifying_key(), &suite(), HpkeMode::Base, &nonce, &public_key(), &signature);
+        assert!(untrusted(result));
+    }
+
+    #[test]
+    fn truncated_signature_is_a_framing_error() {
+        let key = signing_key();
+        let nonce = client_nonce();
+        let pk = public_key();
+        let signature = sign_announcement(&key, &suite(), HpkeMode::Base, &nonce, &pk);
+        let result = verify_announcement(&key.verifying_key(), &suite(), HpkeMode::Base, &nonce, &pk, &signature[..SIGNATURE_LEN - 1]);
+        assert!(result.is_err() && !untrusted(result));
+    }
+
+    #[test]
+    fn verifying_key_hex_round_trip() {
+        let key = signing_key().verifying_key();
+        assert_eq!(parse_verifying_key(&verifying_key_to_hex(&key)).unwrap(), key);
+        assert!(parse_verifying_key("zz").is_err());
+    }
+}
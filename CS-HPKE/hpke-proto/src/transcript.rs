@@ -0,0 +1,56 @@
+// Transcript della negoziazione: SHA-256 di tutti i pacchetti scambiati
+// durante l'handshake, nell'ordine in cui sono stati inviati o ricevuti
+// e così come viaggiano sul filo ([DataType|len|payload]).
+// Il transcript si chiude con la PublicKey del server; il suo hash entra
+// nell'info di HPKE, quindi se un attaccante altera l'offerta del client
+// (es. togliendo gli algoritmi più forti) o la risposta del server, i due
+// peer derivano chiavi diverse e la prima decifratura fallisce.
+
+use sha2::{Digest, Sha256};
+
+use crate::data_packets_manager::{self, DataPacket, ReceivedPacket};
+use crate::error::HpkeProtoError;
+
+const TRANSCRIPT_LABEL: &[u8] = b"CS-HPKE negotiation transcript v1";
+
+pub const TRANSCRIPT_HASH_LEN: usize = 32;
+
+
+#[derive(Clone)]
+pub struct Transcript(Sha256);
+
+impl Default for Transcript {
+    fn default() -> Transcript {
+        Transcript(Sha256::new_with_prefix(TRANSCRIPT_LABEL))
+    }
+}
+
+impl Transcript {
+    pub fn new() -> Transcript {
+        Transcript::default()
+    }
+
+    // Pacchetto in uscita, da registrare prima di accodarlo
+    pub fn record_sent(&mut self, pack: &DataPacket) -> Result<(), HpkeProtoError> {
+        self.0.update(pack.group()?);
+        Ok(())
+    }
+
+    // Pacchetto appena letto dallo stream
+    pub fn record_received(&mut self, pack: &ReceivedPacket) -> Result<(), HpkeProtoError> {
+        self.0.update(data_packets_manager::frame(pack.data_type, &pack.payload)?);
+        Ok(())
+    }
+
+    // Hash dei pacchetti registrati finora
+    pub fn hash(&self) -> [u8; TRANSCRIPT_HASH_LEN] {
+        self.0.clone().finalize().into()
+    }
+}
+
+// info effettivo di HPKE: info dell'applicazione | hash del transcript
+pub fn bind_info(info: &[u8], transcript_hash: &[u8]) -> Vec<u8> {
+    let mut bound = info.to_vec();
+    bound.extend_from_slice(transcript_hash);
+    bound
+}
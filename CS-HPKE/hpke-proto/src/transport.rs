@@ -0,0 +1,275 @@
+// Trasporto asincrono su tokio.
+// - DataPacketCodec: codec per Framed, con lo stesso formato
+//   [DataType|len (u32 BE)|payload] del decoder bloccante.
+// - Le macchine a stati del protocollo (handshake, messaggi sigillati,
+//   scambio dei messaggi in exchange.rs)
+//   sono scritte una volta sola in versione async su PacketStream.
+// - Le funzioni bloccanti restano con la stessa firma e sono wrapper delle
+//   versioni async: block_on aggancia il TcpStream std a un runtime tokio
+//   locale, riprendendo i byte già letti dal PacketDecoder e restituendoglieli
+//   alla fine, così lo stream resta utilizzabile anche in modo bloccante.
+//   I timeout di lettura e scrittura dello stream valgono anche qui.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::net::TcpStream;
+use std::time::Duration;
+
+use bytes::{Buf, BytesMut};
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::runtime::{Builder, Runtime};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedParts};
+
+use crate::data_packets_manager::{self, DataPacket, PacketDecoder, ReceivedPacket};
+use crate::error::HpkeProtoError;
+
+
+// Codec dei pacchetti: in uscita DataPacket, in entrata ReceivedPacket
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DataPacketCodec;
+
+impl Decoder for DataPacketCodec {
+    type Item = ReceivedPacket;
+    type Error = HpkeProtoError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ReceivedPacket>, HpkeProtoError> {
+        match data_packets_manager::parse_packet(src)? {
+            Some((pack, used)) => {
+                src.advance(used);
+                Ok(Some(pack))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<ReceivedPacket>, HpkeProtoError> {
+        match self.decode(src)? {
+            Some(pack) => Ok(Some(pack)),
+            None if src.is_empty() => Ok(None),
+            None => Err(HpkeProtoError::closed("connessione chiusa a metà pacchetto")),
+        }
+    }
+}
+
+impl Encoder<DataPacket> for DataPacketCodec {
+    type Error = HpkeProtoError;
+
+    fn encode(&mut self, pack: DataPacket, dst: &mut BytesMut) -> Result<(), HpkeProtoError> {
+        dst.extend_from_slice(&pack.group()?);
+        Ok(())
+    }
+}
+
+
+// Connessione a pacchetti su un qualsiasi trasporto async
+pub type PacketStream<S> = Framed<S, DataPacketCodec>;
+
+pub fn packet_stream<S: AsyncRead + AsyncWrite>(io: S) -> PacketStream<S> {
+    Framed::new(io, DataPacketCodec)
+}
+
+
+// Accoda un pacchetto senza inviarlo: i pacchetti di un messaggio partono
+// insieme al primo flush, o prima della lettura successiva
+pub async fn queue_packet<S>(stream: &mut PacketStream<S>, pack: DataPacket, what: &str) -> Result<(), HpkeProtoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin
+{
+    stream.feed(pack).await?;
+    println!("{} inviata", what);
+    Ok(())
+}
+
+// Invia i pacchetti accodati e il pacchetto dato
+pub async fn send_packet<S>(stream: &mut PacketStream<S>, pack: DataPacket, what: &str) -> Result<(), HpkeProtoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin
+{
+    stream.send(pack).await?;
+    println!("{} inviata", what);
+    Ok(())
+}
+
+// Versione async di data_packets_manager::read_packet.
+// Prima di aspettare il peer invia i pacchetti ancora accodati
+pub async fn read_packet<S>(stream: &mut PacketStream<S>) -> Result<Option<ReceivedPacket>, HpkeProtoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin
+{
+    stream.flush().await?;
+    stream.next().await.transpose()
+}
+
+
+// Esecuzione bloccante delle versioni async, con i timeout del TcpStream std:
+// impostati con set_read_timeout/set_write_timeout, non varrebbero sul socket
+// non bloccante usato da tokio, quindi vengono applicati qui con tokio::time
+pub struct Blocking<'a> {
+    runtime: &'a Runtime,
+    read: Option<Duration>,
+    write: Option<Duration>
+}
+
+impl Blocking<'_> {
+    // Operazione che aspetta il peer: limitata dal timeout di lettura
+    pub fn wait<T>(&self, future: impl Future<Output = Result<T, HpkeProtoError>>) -> Result<T, HpkeProtoError> {
+        self.run(self.read, "nessuna risposta dal peer entro il timeout di lettura", future)
+    }
+
+    // Operazione di sola scrittura: limitata dal timeout di scrittura
+    pub fn send<T>(&self, future: impl Future<Output = Result<T, HpkeProtoError>>) -> Result<T, HpkeProtoError> {
+        self.run(self.write, "invio non completato entro il timeout di scrittura", future)
+    }
+
+    fn run<T>(
+        &self,
+        limit: Option<Duration>,
+        what: &str,
+        future: impl Future<Output = Result<T, HpkeProtoError>>
+    ) -> Result<T, HpkeProtoError> {
+        match limit {
+            Some(limit) => self.runtime.block_on(tokio::time::timeout(limit, future))
+                .map_err(|_| HpkeProtoError::timed_out(what))?,
+            None => self.runtime.block_on(future),
+        }
+    }
+}
+
+
+// Runtime del thread, creato alla prima chiamata di block_on e riusato da
+// tutte le successive. Il server serve ogni connessione in un thread
+// dedicato, quindi il runtime è uno per connessione
+thread_local! {
+    static RUNTIME: RefCell<Option<Runtime>> = const { RefCell::new(None) };
+}
+
+fn with_runtime<T>(run: impl FnOnce(&Runtime) -> Result<T, HpkeProtoError>) -> Result<T, HpkeProtoError> {
+    RUNTIME.with(|cell| {
+        if cell.borrow().is_none() {
+            let runtime = Builder::new_current_thread().enable_io().enable_time().build()?;
+            *cell.borrow_mut() = Some(runtime);
+        }
+        let runtime = cell.borrow();
+        run(runtime.as_ref().unwrap())
+    })
+}
+
+
+// Esegue run su un PacketStream costruito sopra il TcpStream bloccante.
+// run riceve un Blocking, con cui attendere la funzione async.
+// I byte letti ma non consumati tornano nel decoder
+pub fn block_on<T, F>(stream: &TcpStream, decoder: &mut PacketDecoder, run: F) -> Result<T, HpkeProtoError>
+where
+    F: FnOnce(&Blocking, &mut PacketStream<tokio::net::TcpStream>) -> Result<T, HpkeProtoError>
+{
+    let read = stream.read_timeout()?;
+    let write = stream.write_timeout()?;
+    let io = stream.try_clone()?;
+    io.set_nonblocking(true)?;
+
+    let result = with_runtime(|runtime| {
+        let _guard = runtime.enter();
+        let blocking = Blocking { runtime, read, write };
+        let mut parts = FramedParts::new::<DataPacket>(tokio::net::TcpStream::from_std(io)?, DataPacketCodec);
+        parts.read_buf = BytesMut::from(&decoder.take_pending()[..]);
+        let mut stream = Framed::from_parts(parts);
+
+        let result = run(&blocking, &mut stream);
+        let flushed = blocking.send(stream.flush());
+
+        decoder.feed(&stream.into_parts().read_buf);
+        let result = result?;
+        flushed?;
+        Ok(result)
+    });
+
+    // Il clone condivide il descrittore: lo stream originale torna bloccante
+    stream.set_nonblocking(false)?;
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::data_packets_manager::{DataType, MAX_PAYLOAD_LEN, datatype_to_int, frame};
+
+    fn decode(codec: &mut DataPacketCodec, buf: &mut BytesMut) -> Option<(DataType, Vec<u8>)> {
+        codec.decode(buf).unwrap().map(|pack| (pack.data_type, pack.payload))
+    }
+
+    #[test]
+    fn partial_frame_waits_for_the_rest() {
+        let bytes = frame(DataType::Ciphertext, b"ciphertext").unwrap();
+        let mut codec = DataPacketCodec;
+        let mut buf = BytesMut::new();
+
+        // Header incompleto, poi payload incompleto: nessun pacchetto e nessun byte consumato
+        buf.extend_from_slice(&bytes[..3]);
+        assert!(decode(&mut codec, &mut buf).is_none());
+        buf.extend_from_slice(&bytes[3..8]);
+        assert!(decode(&mut codec, &mut buf).is_none());
+        assert_eq!(buf.len(), 8);
+
+        buf.extend_from_slice(&bytes[8..]);
+        assert_eq!(decode(&mut codec, &mut buf), Some((DataType::Ciphertext, b"ciphertext".to_vec())));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn several_frames_in_one_read() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&frame(DataType::Ciphertext, b"ct").unwrap());
+        buf.extend_from_slice(&frame(DataType::TagBytes, b"tag").unwrap());
+        buf.extend_from_slice(&frame(DataType::EndOfMessage, &[0]).unwrap());
+        // Inizio del pacchetto successivo, ancora incompleto
+        buf.extend_from_slice(&frame(DataType::Plaintext, b"next").unwrap()[..2]);
+
+        let mut codec = DataPacketCodec;
+        assert_eq!(decode(&mut codec, &mut buf), Some((DataType::Ciphertext, b"ct".to_vec())));
+        assert_eq!(decode(&mut codec, &mut buf), Some((DataType::TagBytes, b"tag".to_vec())));
+        assert_eq!(decode(&mut codec, &mut buf), Some((DataType::EndOfMessage, vec![0])));
+        assert!(decode(&mut codec, &mut buf).is_none());
+        assert_eq!(buf.len(), 2);
+    }
+
+    #[test]
+    fn eof_inside_a_frame_is_an_error() {
+        let bytes = frame(DataType::Ciphertext, b"ciphertext").unwrap();
+        let mut codec = DataPacketCodec;
+
+        let mut buf = BytesMut::from(&bytes[..bytes.len() - 1]);
+        match codec.decode_eof(&mut buf) {
+            Err(HpkeProtoError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+            other => panic!("atteso UnexpectedEof, ottenuto {:?}", other.map(|p| p.map(|p| p.data_type))),
+        }
+
+        // Alla chiusura tra un pacchetto e l'altro non c'è errore
+        let mut buf = BytesMut::from(&bytes[..]);
+        assert!(codec.decode_eof(&mut buf).unwrap().is_some());
+        assert!(codec.decode_eof(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn oversize_length_is_rejected_from_the_header() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[datatype_to_int(&DataType::Ciphertext)]);
+        buf.extend_from_slice(&(MAX_PAYLOAD_LEN as u32 + 1).to_be_bytes());
+
+        assert!(matches!(DataPacketCodec.decode(&mut buf), Err(HpkeProtoError::Framing(_))));
+    }
+
+    #[test]
+    fn encoded_packets_decode_back() {
+        let mut codec = DataPacketCodec;
+        let mut buf = BytesMut::new();
+        codec.encode(data_packets_manager::create_packet(DataType::AssociatedData, b"ad".to_vec()), &mut buf).unwrap();
+        assert_eq!(&buf[..], &frame(DataType::AssociatedData, b"ad").unwrap()[..]);
+        assert_eq!(decode(&mut codec, &mut buf), Some((DataType::AssociatedData, b"ad".to_vec())));
+
+        let oversize = data_packets_manager::create_packet(DataType::Ciphertext, vec![0; MAX_PAYLOAD_LEN + 1]);
+        assert!(codec.encode(oversize, &mut buf).is_err());
+    }
+}
@@ -0,0 +1,419 @@
+// Verifica delle chiavi del peer.
+// Lato client, la chiave pubblica del server prima di cifrare:
+// - Impronta: SHA-256 di KEM (u16 BE) | chiave pubblica, scritta "SHA256:<hex>".
+//   Il KEM fa parte dell'impronta: una chiave non vale per un KEM diverso.
+// - Pinned: la chiave deve avere una delle impronte fissate.
+// - KnownHosts: file di chiavi fidate, una riga per server e KEM:
+//
+//     # commento
+//     127.0.0.1:8888 0x0020 SHA256:9f86d081884c7d65...
+//
+//   Più righe per lo stesso server e KEM valgono tutte (es. durante un cambio
+//   di chiave). In modo strict un server o un KEM non presenti nel file vengono rifiutati;
+//   in modo trust-on-first-use la prima chiave vista viene aggiunta al file
+//   e da lì in poi ogni chiave diversa viene rifiutata.
+//
+// Lato server, la chiave statica del client nei modi Auth e AuthPsk: deve
+// avere una delle impronte fissate in configurazione o essere elencata nel
+// file dei client autorizzati, una riga per chiave:
+//
+//     # commento
+//     SHA256:2c26b46b68ffc68f... laptop-di-mario
+//
+// Senza client autorizzati il server non sceglie mai i modi Auth.
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::agility::AgilePublicKey;
+use crate::ciphersuite::{Algorithm, KEMtype};
+use crate::error::HpkeProtoError;
+
+const FINGERPRINT_PREFIX: &str = "SHA256:";
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    pub fn of(pk: &AgilePublicKey) -> Fingerprint {
+        let mut hasher = Sha256::new();
+        hasher.update(pk.kem.to_bytes());
+        hasher.update(&pk.bytes);
+        Fingerprint(hasher.finalize().into())
+    }
+
+    // Legge un'impronta nel formato "SHA256:<64 cifre esadecimali>"
+    pub fn parse(text: &str) -> Result<Fingerprint, HpkeProtoError> {
+        let invalid = || HpkeProtoError::trust_store(format!("impronta non valida: {}", text));
+        let hex = text.strip_prefix(FINGERPRINT_PREFIX).ok_or_else(invalid)?;
+        let mut bytes = [0u8; 32];
+        parse_hex(hex, &mut bytes).ok_or_else(invalid)?;
+        Ok(Fingerprint(bytes))
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", FINGERPRINT_PREFIX, to_hex(&self.0))
+    }
+}
+
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Riempie out con le cifre esadecimali di hex, che devono essere esattamente 2 per byte
+pub(crate) fn parse_hex(hex: &str, out: &mut [u8]) -> Option<()> {
+    if hex.len() != 2 * out.len() || !hex.is_ascii() {
+        return None;
+    }
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(())
+}
+
+
+// Come il client decide se fidarsi della chiave ricevuta dal server
+#[derive(Debug, Clone)]
+pub enum ServerKeyPolicy {
+    // Nessuna verifica: solo dove la PSK autentica già il server (flussi secondari)
+    AcceptAny,
+    // La chiave deve avere una di queste impronte
+    Pinned(Vec<Fingerprint>),
+    // Chiavi fidate lette da file; con tofu le chiavi nuove vengono aggiunte
+    KnownHosts { path: PathBuf, tofu: bool },
+}
+
+impl ServerKeyPolicy {
+    // Rende errore se la chiave del server host non è attendibile
+    pub fn verify(&self, host: &str, pk: &AgilePublicKey) -> Result<(), HpkeProtoError> {
+        let fingerprint = Fingerprint::of(pk);
+        match self {
+            ServerKeyPolicy::AcceptAny => Ok(()),
+            ServerKeyPolicy::Pinned(pins) => {
+                if pins.contains(&fingerprint) {
+                    println!("Chiave del server {} verificata: {}", host, fingerprint);
+                    Ok(())
+                } else {
+                    Err(HpkeProtoError::UntrustedServerKey(format!(
+                        "la chiave {} di {} non corrisponde a nessuna impronta fissata", fingerprint, host
+                    )))
+                }
+            }
+            ServerKeyPolicy::KnownHosts { path, tofu } => {
+                let known = load_known_hosts(path)?;
+                // Come in ssh, più righe per lo stesso server e KEM sono tutte valide
+                let entries: Vec<&KnownHost> = known.iter().filter(|entry| entry.host == host && entry.kem == pk.kem).collect();
+                match entries.first() {
+                    Some(_) if entries.iter().any(|entry| entry.fingerprint == fingerprint) => {
+                        println!("Chiave del server {} verificata: {}", host, fingerprint);
+                        Ok(())
+                    }
+                    Some(entry) => Err(HpkeProtoError::UntrustedServerKey(format!(
+                        "la chiave di {} per il KEM {} è cambiata: attesa {}, ricevuta {} (riga {} di {})",
+                        host, pk.kem, entry.fingerprint, fingerprint, entry.line, path.display()
+                    ))),
+                    None if *tofu => {
+                        append_known_host(path, host, pk.kem, &fingerprint)?;
+                        println!("Primo contatto con {}: chiave {} aggiunta a {}", host, fingerprint, path.display());
+                        Ok(())
+                    }
+                    None => Err(HpkeProtoError::UntrustedServerKey(format!(
+                        "nessuna chiave fidata per {} e il KEM {} in {} (ricevuta {})",
+                        host, pk.kem, path.display(), fingerprint
+                    ))),
+                }
+            }
+        }
+    }
+}
+
+
+// Riga del file di chiavi fidate
+struct KnownHost {
+    host: String,
+    kem: KEMtype,
+    fingerprint: Fingerprint,
+    line: usize
+}
+
+// Un file mancante equivale a un file vuoto
+fn load_known_hosts(path: &Path) -> Result<Vec<KnownHost>, HpkeProtoError> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |what: &str| HpkeProtoError::trust_store(format!("{}, riga {}: {}", path.display(), i + 1, what));
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [host, kem, fingerprint] = fields[..] else {
+            return Err(invalid("attesi server, KEM e impronta"));
+        };
+        let kem = kem.strip_prefix("0x")
+            .and_then(|id| u16::from_str_radix(id, 16).ok())
+            .and_then(|id| KEMtype::try_from(id).ok())
+            .ok_or_else(|| invalid("KEM non valido"))?;
+        let fingerprint = Fingerprint::parse(fingerprint).map_err(|_| invalid("impronta non valida"))?;
+        entries.push(KnownHost { host: host.to_string(), kem, fingerprint, line: i + 1 });
+    }
+    Ok(entries)
+}
+
+fn append_known_host(path: &Path, host: &str, kem: KEMtype, fingerprint: &Fingerprint) -> Result<(), HpkeProtoError> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{} {} {}", host, kem, fingerprint)?;
+    Ok(())
+}
+
+
+// Client autorizzati a usare i modi Auth
+#[derive(Debug, Clone, Default)]
+pub struct AuthorizedClients {
+    pub pins: Vec<Fingerprint>,
+    pub file: Option<PathBuf>,
+}
+
+impl AuthorizedClients {
+    // true se non c'è nessun client autorizzato; il file viene riletto a ogni
+    // chiamata, così i client aggiunti valgono dalla connessione successiva
+    pub fn is_empty(&self) -> Result<bool, HpkeProtoError> {
+        if !self.pins.is_empty() {
+            return Ok(false);
+        }
+        match &self.file {
+            Some(path) => Ok(load_authorized_clients(path)?.is_empty()),
+            None => Ok(true),
+        }
+    }
+
+    // Rende errore di negoziazione se la chiave statica del client non è autorizzata
+    pub fn verify(&self, pk: &AgilePublicKey) -> Result<(), HpkeProtoError> {
+        let fingerprint = Fingerprint::of(pk);
+        if self.pins.contains(&fingerprint) {
+            println!("Client autorizzato: {}", fingerprint);
+            return Ok(());
+        }
+        if let Some(path) = &self.file {
+            if let Some(entry) = load_authorized_clients(path)?.iter().find(|entry| entry.fingerprint == fingerprint) {
+                println!("Client autorizzato: {} ({})", entry.name, fingerprint);
+                return Ok(());
+            }
+        }
+        Err(HpkeProtoError::negotiation(format!("chiave del client {} non autorizzata", fingerprint)))
+    }
+}
+
+// Riga del file dei client autorizzati
+struct AuthorizedClient {
+    fingerprint: Fingerprint,
+    name: String
+}
+
+// Un file mancante equivale a un file vuoto
+fn load_authorized_clients(path: &Path) -> Result<Vec<AuthorizedClient>, HpkeProtoError> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (fingerprint, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let fingerprint = Fingerprint::parse(fingerprint).map_err(|_| HpkeProtoError::trust_store(
+            format!("{}, riga {}: impronta non valida", path.display(), i + 1)
+        ))?;
+        entries.push(AuthorizedClient { fingerprint, name: name.trim().to_string() });
+    }
+    Ok(entries)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::agility;
+
+    const HOST: &str = "127.0.0.1:8888";
+
+    // File temporaneo per il test, rimosso alla fine
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, content: &str) -> TempFile {
+            let path = std::env::temp_dir().join(format!("hpke-proto-{}-{}", std::process::id(), name));
+            fs::write(&path, content).unwrap();
+            TempFile(path)
+        }
+
+        fn missing(name: &str) -> TempFile {
+            let file = TempFile::new(name, "");
+            fs::remove_file(&file.0).unwrap();
+            file
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn public_key(kem: KEMtype) -> AgilePublicKey {
+        agility::agile_gen_keypair(kem, &mut StdRng::from_entropy()).unwrap().public_key().clone()
+    }
+
+    fn known_hosts(path: &Path, tofu: bool) -> ServerKeyPolicy {
+        ServerKeyPolicy::KnownHosts { path: path.to_path_buf(), tofu }
+    }
+
+    #[test]
+    fn fingerprint_round_trip() {
+        let pk = public_key(KEMtype::X25519HkdfSha256);
+        let fingerprint = Fingerprint::of(&pk);
+        let text = fingerprint.to_string();
+        assert!(text.starts_with(FINGERPRINT_PREFIX));
+        assert_eq!(Fingerprint::parse(&text).unwrap(), fingerprint);
+
+        assert!(Fingerprint::parse(&text[FINGERPRINT_PREFIX.len()..]).is_err());
+        assert!(Fingerprint::parse(&text[..text.len() - 1]).is_err());
+        assert!(Fingerprint::parse(&format!("{}g", &text[..text.len() - 1])).is_err());
+    }
+
+    #[test]
+    fn known_hosts_malformed_lines() {
+        let pk = public_key(KEMtype::X25519HkdfSha256);
+        let fingerprint = Fingerprint::of(&pk);
+        for (name, content) in [
+            ("missing-field", format!("{} 0x0020\n", HOST)),
+            ("extra-field", format!("{} 0x0020 {} extra\n", HOST, fingerprint)),
+            ("kem-without-prefix", format!("{} 0020 {}\n", HOST, fingerprint)),
+            ("unknown-kem", format!("{} 0x9999 {}\n", HOST, fingerprint)),
+            ("bad-fingerprint", format!("{} 0x0020 SHA256:00\n", HOST)),
+        ] {
+            let file = TempFile::new(name, &content);
+            let err = known_hosts(&file.0, false).verify(HOST, &pk).unwrap_err();
+            assert!(matches!(err, HpkeProtoError::TrustStore(_)), "{}: {}", name, err);
+        }
+
+        // Commenti e righe vuote vengono ignorati
+        let file = TempFile::new("comments", &format!("# commento\n\n  {} 0x0020 {}  \n", HOST, fingerprint));
+        assert!(known_hosts(&file.0, false).verify(HOST, &pk).is_ok());
+    }
+
+    #[test]
+    fn known_hosts_duplicate_hosts() {
+        let old = public_key(KEMtype::X25519HkdfSha256);
+        let new = public_key(KEMtype::X25519HkdfSha256);
+        let p256 = public_key(KEMtype::DhP256HkdfSha256);
+        let file = TempFile::new("duplicates", &format!(
+            "{host} 0x0020 {}\n{host} 0x0020 {}\n{host} 0x0010 {}\n",
+            Fingerprint::of(&old), Fingerprint::of(&new), Fingerprint::of(&p256), host = HOST
+        ));
+        let policy = known_hosts(&file.0, false);
+
+        // Due righe per lo stesso server e KEM: valgono entrambe
+        assert!(policy.verify(HOST, &old).is_ok());
+        assert!(policy.verify(HOST, &new).is_ok());
+        // Lo stesso server con un altro KEM ha una riga sua
+        assert!(policy.verify(HOST, &p256).is_ok());
+        // Le righe valgono solo per il loro server
+        assert!(policy.verify("127.0.0.1:9999", &old).is_err());
+    }
+
+    #[test]
+    fn tofu_appends_then_verifies() {
+        let file = TempFile::missing("tofu");
+        let policy = known_hosts(&file.0, true);
+        let pk = public_key(KEMtype::X25519HkdfSha256);
+
+        assert!(policy.verify(HOST, &pk).is_ok());
+        let text = fs::read_to_string(&file.0).unwrap();
+        assert_eq!(text, format!("{} 0x0020 {}\n", HOST, Fingerprint::of(&pk)));
+
+        // La riga aggiunta vale anche in modo strict e non viene duplicata
+        assert!(known_hosts(&file.0, false).verify(HOST, &pk).is_ok());
+        assert!(policy.verify(HOST, &pk).is_ok());
+        assert_eq!(fs::read_to_string(&file.0).unwrap(), text);
+
+        // Un KEM diverso dello stesso server è un nuovo primo contatto
+        assert!(policy.verify(HOST, &public_key(KEMtype::DhP256HkdfSha256)).is_ok());
+        assert_eq!(fs::read_to_string(&file.0).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn changed_key_is_rejected() {
+        let file = TempFile::missing("mismatch");
+        let pk = public_key(KEMtype::X25519HkdfSha256);
+        assert!(known_hosts(&file.0, true).verify(HOST, &pk).is_ok());
+
+        let other = public_key(KEMtype::X25519HkdfSha256);
+        for tofu in [true, false] {
+            let err = known_hosts(&file.0, tofu).verify(HOST, &other).unwrap_err();
+            assert!(matches!(err, HpkeProtoError::UntrustedServerKey(_)));
+        }
+        // Il rifiuto non tocca il file
+        assert_eq!(fs::read_to_string(&file.0).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn strict_rejects_unknown_host() {
+        let file = TempFile::missing("strict");
+        let pk = public_key(KEMtype::X25519HkdfSha256);
+        let err = known_hosts(&file.0, false).verify(HOST, &pk).unwrap_err();
+        assert!(matches!(err, HpkeProtoError::UntrustedServerKey(_)));
+        assert!(!file.0.exists());
+    }
+
+    #[test]
+    fn pinned_keys() {
+        let pk = public_key(KEMtype::X25519HkdfSha256);
+        let policy = ServerKeyPolicy::Pinned(vec![Fingerprint::of(&pk)]);
+        assert!(policy.verify(HOST, &pk).is_ok());
+        assert!(policy.verify(HOST, &public_key(KEMtype::X25519HkdfSha256)).is_err());
+    }
+
+    #[test]
+    fn authorized_clients_from_pins_and_file() {
+        let pinned = public_key(KEMtype::X25519HkdfSha256);
+        let listed = public_key(KEMtype::X25519HkdfSha256);
+        let file = TempFile::new("authorized", &format!("# client\n{}  laptop\n", Fingerprint::of(&listed)));
+
+        let clients = AuthorizedClients { pins: vec![Fingerprint::of(&pinned)], file: Some(file.0.clone()) };
+        assert!(!clients.is_empty().unwrap());
+        assert!(clients.verify(&pinned).is_ok());
+        assert!(clients.verify(&listed).is_ok());
+        assert!(matches!(
+            clients.verify(&public_key(KEMtype::X25519HkdfSha256)),
+            Err(HpkeProtoError::Negotiation(_))
+        ));
+
+        let missing = TempFile::missing("authorized-missing");
+        assert!(AuthorizedClients { pins: vec![], file: Some(missing.0.clone()) }.is_empty().unwrap());
+        assert!(AuthorizedClients::default().is_empty().unwrap());
+
+        let malformed = TempFile::new("authorized-malformed", "SHA256:zz laptop\n");
+        assert!(AuthorizedClients { pins: vec![], file: Some(malformed.0.clone()) }.is_empty().is_err());
+    }
+}
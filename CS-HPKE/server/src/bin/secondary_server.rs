@@ -0,0 +1,123 @@
+// Secondary Server (SS): si registra presso il PS, ne riceve la chiave del
+// flusso SC -> SS e la usa come PSK per servire direttamente il SC
+
+use std::net::{TcpListener, TcpStream};
+use std::io::Write;
+
+use rand::{rngs::StdRng, SeedableRng};
+
+use hpke_proto::config;
+use hpke_proto::agility::{self, AgileHpkeError, AgileKeypair};
+use hpke_proto::ciphersuite::SelectionPolicy;
+use hpke_proto::data_packets_manager::{self, DataType, PacketDecoder};
+use hpke_proto::error::{HpkeProtoError, exit_on_error};
+use hpke_proto::handshake;
+use hpke_proto::kri::{EpochReceiver, RekeyPolicy};
+use hpke_proto::psk::PskStore;
+use hpke_proto::replay::EncappedKeyCache;
+use hpke_proto::secondary;
+use hpke_proto::trust::AuthorizedClients;
+
+const INFO_STR: &[u8] = b"PDMv2 secondary session";
+
+
+// Serve un SC: handshake in modo Psk, apertura della sessione ed eco dei messaggi
+fn handle_sc(
+    mut stream: TcpStream,
+    keys: &[AgileKeypair],
+    policy: &SelectionPolicy,
+    psks: &PskStore,
+    rekey: RekeyPolicy,
+    ek_cache: &mut EncappedKeyCache
+) -> Result<(), HpkeProtoError> {
+    let mut decoder = PacketDecoder::new();
+
+    // Handshake col SC in modo Psk, con la chiave del flusso come PSK
+    let negotiated = match handshake::handle_client(&stream, &mut decoder, keys, policy, psks, &AuthorizedClients::default(), None)? {
+        Some(negotiated) => negotiated,
+        None => return Ok(()),
+    };
+    let keypair = keys.iter()
+        .find(|k| k.kem() == negotiated.suite.kem)
+        .ok_or_else(|| HpkeProtoError::negotiation("nessuna chiave del SS per il KEM negoziato"))?;
+
+    let session = secondary::open_receiver_session(
+        &stream,
+        &mut decoder,
+        &negotiated,
+        psks,
+        keypair,
+        INFO_STR,
+        ek_cache
+    )?;
+    println!("Sessione col SC aperta");
+    let mut receiver = EpochReceiver::new(session, negotiated.suite.aead, rekey)?;
+
+    while let Some(sealed) = secondary::read_sealed(&stream, &mut decoder)? {
+        let header = sealed.kri.ok_or_else(|| HpkeProtoError::framing("messaggio senza KRI"))?;
+        let decrypted_msg = match receiver.open(&header, &sealed.ciphertext, &sealed.associated_data, &sealed.tag) {
+            Ok(msg) => msg,
+            Err(AgileHpkeError::Replay(e)) => {
+                println!("Messaggio rifiutato: {}", e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        println!("Il SC ha inviato: {}", String::from_utf8_lossy(&decrypted_msg));
+
+        // Eco al SC per verificare che il messaggio sia corretto
+        stream.write_all(&data_packets_manager::frame(DataType::Plaintext, &decrypted_msg)?)?;
+    }
+    Ok(())
+}
+
+
+fn main() {
+
+    // Topologia e PSK di registrazione dalla sezione [registration] di server.toml,
+    // epoche accettate da [rekey]
+    let args: Vec<String> = std::env::args().collect();
+    let loaded = config::config_path(&args, config::DEFAULT_SERVER_CONFIG)
+        .and_then(|(path, required)| config::load_server(&path, required));
+    let (registration, rekey) = match loaded {
+        Ok(config) => (config.registration, config.rekey),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Registrazione presso il PS
+    let material = exit_on_error(
+        secondary::register(&registration, &registration.ss_id),
+        "registrazione presso il PS fallita"
+    );
+    let psks = exit_on_error(material.to_psk_store(), "materiale di chiave non valido");
+    println!("Chiave del flusso ricevuta (PSK_ID {})", String::from_utf8_lossy(&material.psk_id));
+
+    let policy = secondary::secondary_policy();
+    let mut csprng = StdRng::from_entropy();
+    let keys: Vec<AgileKeypair> = policy.kems.iter()
+        .filter_map(|kem| agility::agile_gen_keypair(*kem, &mut csprng).ok())
+        .collect();
+
+    let listener = exit_on_error(TcpListener::bind(registration.ss_addr), "Could not bind");
+
+    // Encapped key delle sessioni già aperte dal SC
+    let mut ek_cache = EncappedKeyCache::default();
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("failed: {}", e);
+                continue;
+            }
+        };
+
+        // Un errore del SC chiude solo la sua connessione
+        if let Err(e) = handle_sc(stream, &keys, &policy, &psks, rekey, &mut ek_cache) {
+            println!("Connessione chiusa: {}", e);
+        }
+    }
+}
@@ -1,409 +1,139 @@
-use std::net::{TcpListener, TcpStream, Shutdown};
-use std::net::SocketAddr;
-use std::io::{Read, Write, Error, BufReader};
-
-use hpke::generic_array::typenum::Len;
-use hpke::{
-    aead::{AeadTag, ChaCha20Poly1305},
-    kdf::HkdfSha384,
-    kem::X25519HkdfSha256,
-    Deserializable, Kem as KemTrait, OpModeR, OpModeS, Serializable,
-};
-
-use rand::{rngs::StdRng, SeedableRng};
-
-mod data_packets_manager;
-mod ciphersuite_server;
-
-
-// TODO: encryption context (struct?) rfc 5.1
-
-const INFO_STR: &[u8] = b"example session";
-
-// Algorithms
-type Kem = X25519HkdfSha256;
-type Aead = ChaCha20Poly1305;
-type Kdf = HkdfSha384;
-
-
-// Initializes the server with a fresh keypair
-fn server_init() -> (<Kem as KemTrait>::PrivateKey, <Kem as KemTrait>::PublicKey) {
-    let mut csprng = StdRng::from_entropy();
-    Kem::gen_keypair(&mut csprng)
-}
-
-
-fn server_decrypt_msg(
-    server_sk_bytes: &[u8],
-    encapped_key_bytes: &[u8],
-    ciphertext: &[u8],
-    associated_data: &[u8],
-    tag_bytes: &[u8],
-) -> Vec<u8> {
-    // We have to derialize the secret key, AEAD tag, and encapsulated pubkey. 
-    // These fail if the bytestrings are the wrong length.
-    let server_sk = <Kem as KemTrait>::PrivateKey::from_bytes(
-        server_sk_bytes
-    ).expect("could not deserialize server privkey!");
-    let tag = AeadTag::<Aead>::from_bytes(
-        tag_bytes
-    ).expect("could not deserialize AEAD tag!");
-    let encapped_key = <Kem as KemTrait>::EncappedKey::from_bytes(
-        encapped_key_bytes
-    ).expect("could not deserialize the encapsulated pubkey!");
-
-    // Decapsulate and derive the shared secret. This creates a shared AEAD context.
-    // Inside setup_receiver(), decap() is made
-    let mut receiver_ctx =
-        hpke::setup_receiver::<Aead, Kdf, Kem>(
-            &OpModeR::Base,
-            &server_sk,
-            &encapped_key,
-            INFO_STR
-        ).expect("failed to set up receiver!");
-
-    // On success, open_in_place_detached() will decrypt the ciphertext in place
-    let mut ciphertext_copy = ciphertext.to_vec();
-    receiver_ctx
-        .open_in_place_detached(
-            &mut ciphertext_copy,
-            associated_data,
-            &tag
-        ).expect("invalid ciphertext!");
-
-    // Rename for clarity. Cargo clippy thinks it's unnecessary, but I disagree
-    #[allow(clippy::let_and_return)]
-    let plaintext = ciphertext_copy;
-    plaintext
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use hpke_proto::{config, connections, data_packets_manager, exchange, handshake, keystore, signing};
+use hpke_proto::agility::{AgileKeypair, AgileOpModeR};
+use hpke_proto::ciphersuite::{Ciphersuite, HpkeMode, SelectionPolicy};
+use hpke_proto::config::{ServerConfig, Timeouts};
+use hpke_proto::connections::Shutdown;
+use hpke_proto::signing::SigningKey;
+use hpke_proto::data_packets_manager::PacketDecoder;
+use hpke_proto::handshake::Negotiated;
+use hpke_proto::error::{HpkeProtoError, exit_on_error};
+use hpke_proto::exchange::MessageReceiver;
+use hpke_proto::kri::RekeyPolicy;
+use hpke_proto::psk::PskStore;
+use hpke_proto::replay::EncappedKeyCache;
+use hpke_proto::secondary::{self, FlowKeyMaterial, Registration};
+use hpke_proto::trust::AuthorizedClients;
+
+
+// Costruisce il modo HPKE del destinatario a partire dalla negoziazione
+fn server_op_mode(negotiated: &Negotiated, psks: &PskStore) -> Result<AgileOpModeR, HpkeProtoError> {
+    let bundle = negotiated.psk_bundle(psks)?;
+    let client_pk = negotiated.client_pk.clone();
+    let missing = || HpkeProtoError::negotiation("negoziazione incompleta per il modo scelto");
+    Ok(match negotiated.mode {
+        HpkeMode::Base => AgileOpModeR::Base,
+        HpkeMode::Psk => AgileOpModeR::Psk(bundle.ok_or_else(missing)?),
+        HpkeMode::Auth => AgileOpModeR::Auth(client_pk.ok_or_else(missing)?),
+        HpkeMode::AuthPsk => AgileOpModeR::AuthPsk(client_pk.ok_or_else(missing)?, bundle.ok_or_else(missing)?),
+    })
 }
 
 
-// Controlla che siano stati riempiti i vettori che servono per decriptare il messaggio
-fn ready(ek: &Vec<u8>, ct: &Vec<u8>, ad: &Vec<u8>, tb: &Vec<u8>) -> bool {
-    if !ek.is_empty()
-    && !ct.is_empty()
-    && !ad.is_empty()
-    && !tb.is_empty() {
-        return true;
-    }
-    return false;
+// info è già legato al transcript della negoziazione
+#[allow(clippy::too_many_arguments)]
+fn client_exchange_mex(
+    stream: &TcpStream,
+    decoder: &mut PacketDecoder,
+    suite: &Ciphersuite,
+    mode: &AgileOpModeR,
+    keypair: &AgileKeypair,
+    info: &[u8],
+    registration: Option<(&Mutex<TcpListener>, &Registration)>,
+    rekey: RekeyPolicy,
+    ek_cache: &Mutex<EncappedKeyCache>
+) -> Result<(), HpkeProtoError> {
+    let mut receiver = MessageReceiver::new(suite, mode, keypair, info, rekey, ek_cache);
+
+    exchange::serve_messages(stream, decoder, &mut receiver, |session| {
+        // PS consegna a SS la chiave del flusso SC -> SS derivata dalla sessione
+        if let Some((listener, registration)) = registration {
+            let material = FlowKeyMaterial::derive(session, registration)?;
+            secondary::serve_registration(listener, registration, &registration.ss_id, &material)?;
+        }
+        Ok(())
+    })
 }
 
 
-// Gestisce l'arrivo del pacchetto memorizzandolo nel corretto vettore
-fn handle_data(mut stream: &TcpStream, vec: &mut Vec<u8>, buf: &[u8], mex: &[u8]) -> Result<(), Error> {
-    let id = buf[0];
-    let dtype = data_packets_manager::int_to_datatype_display(id);
-    println!("Arrivato {}", dtype);
-    display_buf(&buf);
-    let bytes_written = stream.write(mex)?;
-    if bytes_written == 0 {return Ok(());}
-    fill_vec_from_buf(vec, &buf);
-    Ok(display_vec(&vec))
+// Stato condiviso tra i thread delle connessioni
+struct ServerState {
+    keys: Vec<AgileKeypair>,
+    policy: SelectionPolicy,
+    psks: PskStore,
+    // Chiavi statiche dei client ammesse nei modi Auth
+    authorized_clients: AuthorizedClients,
+    // Listener delle registrazioni dei SS, solo con secondary
+    registration: Option<Mutex<TcpListener>>,
+    secondary: Registration,
+    info: Vec<u8>,
+    // Epoche della chiave dei messaggi accettate
+    rekey: RekeyPolicy,
+    timeouts: Timeouts,
+    // Chiave di lungo periodo con cui il server firma l'annuncio della sua chiave pubblica
+    signing_key: SigningKey,
+    // Encapped key delle sessioni già aperte, condivise tra tutte le connessioni
+    ek_cache: Mutex<EncappedKeyCache>
 }
 
 
-fn handle_data_cps(mut stream: &TcpStream, vec: &mut Vec<String>, buf: &[u8], mex: &[u8]) -> Result<(), Error> {
-    let id = buf[0];
-    let dtype = data_packets_manager::int_to_datatype_display(id);
-    println!("Arrivato {}", dtype);
-    display_buf(&buf);
-    let bytes_written = stream.write(mex)?;
-    if bytes_written == 0 {return Ok(());}
-    fill_vec_from_buf_cps(vec, &buf);
-    Ok(display_vec_cps(&vec))
-}
-
-
-// Rimpie il vettore con i dati dentro al buffer
-fn fill_vec_from_buf(vec: &mut Vec<u8>, buf: &[u8]) {
-    let mut ii:usize = 2;
-    let pack_len = buf[1];
-
-    loop {
-        while ii <= ((pack_len + 1)).into() {
-            vec.push(buf[ii]);
-            ii += 1;
-        }
-        break;
-    }
-}
-
-
-fn fill_vec_from_buf_cps(vec: &mut Vec<String>, buf: &[u8]) -> Result<(), Error> {
-    let mut ii:usize = 2;
-    let pack_len = buf[1];
-    let mut tmp_vec = vec![];
-
-    Ok(
-        loop {
-            while ii <= ((pack_len + 1)).into() {
-                tmp_vec.push(buf[ii]);
-                ii += 1;
-            }
-            let str = String::from_utf8(tmp_vec).expect("failed to convert");
-            vec.push(str);
-            break;
-        }
+// Serve un client dall'handshake alla chiusura della connessione
+fn handle_connection(stream: TcpStream, state: &ServerState) -> Result<(), HpkeProtoError> {
+    stream.set_read_timeout(state.timeouts.read)?;
+    stream.set_write_timeout(state.timeouts.write)?;
+
+    // Il decoder è unico per connessione: eventuali byte già letti
+    // durante l'handshake non vanno persi nello scambio di messaggi
+    let mut decoder = PacketDecoder::new();
+
+    let negotiated = match handshake::handle_client(
+        &stream,
+        &mut decoder,
+        &state.keys,
+        &state.policy,
+        &state.psks,
+        &state.authorized_clients,
+        Some(&state.signing_key)
+    )? {
+        Some(negotiated) => negotiated,
+        None => return Ok(()),
+    };
+    let suite = negotiated.suite;
+    println!("Ciphersuite negoziata: {}, modo {:?}", suite, negotiated.mode);
+
+    let mode = server_op_mode(&negotiated, &state.psks)?;
+
+    let keypair = state.keys.iter()
+        .find(|k| k.kem() == suite.kem)
+        .ok_or_else(|| HpkeProtoError::negotiation("nessuna chiave del server per il KEM negoziato"))?;
+
+    client_exchange_mex(
+        &stream,
+        &mut decoder,
+        &suite,
+        &mode,
+        keypair,
+        &negotiated.session_info(&state.info),
+        state.registration.as_ref().map(|listener| (listener, &state.secondary)),
+        state.rekey,
+        &state.ek_cache
     )
 }
 
 
-// Printa un vettore
-fn display_vec(vec: &Vec<u8>) {
-    print!("vettore: ");
-    for i in vec { print!("{} ", i); }
-    print!("\n\n");
-}
-
-
-fn display_vec_cps(vec: &Vec<String>) {
-    print!("vettore: ");
-    for i in vec { print!("{} ", i); }
-    print!("\n\n");
-}
-
-
-// Printa un buffer
-fn display_buf(buf: &[u8]) {
-    print!("data: ");
-    for i in buf { print!("{} ", i); }
-    print!("\n");
-}
-
-
-// Controlla che ci sia almeno un algoritmo in comune tra C e S
-// Se esiste, ritorna l'ID DELL'ULTIMO controllato, 
-//e un flag che segnala che esiste almeno un algoritmo in comune 
-fn match_available_cps(av_client: &Vec<String>, av_server: &Vec<String>) -> (String, bool) {
-    let mut id =  String::from("None");
-    let mut pass = false;
-    for i in av_client {
-        for j in av_server {
-            if i == j {
-                // esiste un algoritmo compatibile
-                pass = true;  
-                id = j.to_string(); 
-            }
-        }
-    }
-    (id, pass)
-}
-
-
-fn send_packet(mut stream: &TcpStream, pack: &[u8], received_mex: &mut [u8], what: String) {
-    stream.write(pack).unwrap();
-    println!("\n{} inviata", what);
-
-    match stream.read(received_mex) {
-
-        Ok(_) => {
-            if received_mex == [0] { println!("Il client ha ricevuto {}\n", what) }
-        },
-        
+// Configurazione da --config PATH, altrimenti da server.toml se presente.
+// Una configurazione non valida ferma il server prima di aprire la porta
+fn load_config() -> ServerConfig {
+    let args: Vec<String> = std::env::args().collect();
+    let loaded = config::config_path(&args, config::DEFAULT_SERVER_CONFIG)
+        .and_then(|(path, required)| config::load_server(&path, required));
+    match loaded {
+        Ok(config) => config,
         Err(e) => {
-            println!("Fallimento nel ricevere dati: {}", e);
-            return;
-        }
-    }
-}
-
-
-// Primary server responds to the primary client with one of the
-// available ciphersuites and shares its public key.
-fn handle_client(mut stream: &TcpStream, pubkey: &[u8], privkey: &[u8], mex: &[u8]) -> Result<(), Error> {
-
-    let mut received = [1 as u8];
-
-    // vettori dove vengono salvati gli algoritmi del C
-    let mut client_kem_cps = vec![];
-    let mut client_kdf_cps = vec![];
-    let mut client_aead_cps = vec![];
-
-    // vettori che contengono gli algoritmi disponibili del S
-    let server_av_kems = ciphersuite_server::KEMtypeR::to_vect();
-    let server_av_kdfs = ciphersuite_server::KDFtypeR::to_vect();
-    let server_av_aeads = ciphersuite_server::AEADtypeR::to_vect();
-
-    // bool che segnalano se esiste almeno un algoritmo disponibile in comune ta C e S
-    let mut kem_pass = false;
-    let mut kdf_pass = false;
-    let mut aead_pass = false;
-
-    println!("Incoming connection from: {}\n", stream.peer_addr()?);
-
-    // IL BUFFER DATA PUÒ ESSERE MOLTO PIÙ GRANDE
-    let mut data = [0 as u8; 100]; 
-
-    loop {
-
-        let mut finish_cps = false;   // segnala quando il client ha inviato tutti
-                                            // gli algoritmi che ha a disposizione     
-
-        let bytes_read = stream.read(&mut data)?;
-        if bytes_read == 0 {return Ok(());}
-
-
-        // ##### ARRIVO DELLE CIPHERSUITES DAL CLIENT #####
-
-        // => KEM
-        if data[0] == 5 && !finish_cps {
-            handle_data_cps(stream, &mut client_kem_cps, &data, mex)?;
-        }
-        // => KDF
-        if data[0] == 6 && !finish_cps {
-            handle_data_cps(stream, &mut client_kdf_cps, &data, mex)?;
-        }
-        // => AEAD
-        if data[0] == 7 && !finish_cps {
-            handle_data_cps(stream, &mut client_aead_cps, &data, mex)?;
-        }
-        // Invio di pecchetti terminato
-        if data[0] == 8 && !finish_cps {
-            finish_cps = true;
-            println!("Arrivata tutta la cps del client\n");
-        }
-        // Trovata una ciphersuite comune -> esci
-        if data[0] == 9 {
-            println!("Il client ha ricevuto tutto!");
-            break;
-        }
-
-
-        // ##### CONTROLLO SE ESEITE UNA CIPHERSUITE COMUNE COL CLIENT #####
-
-        if finish_cps {
-
-            // ID degli algoritmi scelti
-            let kem_id:String;
-            let kdf_id:String;
-            let aead_id:String; 
-        
-            println!("Controllo quali algoritmi sono disponibili...\n");
-
-            // => KEM
-            (kem_id, kem_pass) = match_available_cps(
-                &client_kem_cps,
-                &server_av_kems
-            );   
-            // => KDF
-            (kdf_id, kdf_pass) = match_available_cps(
-                &client_kdf_cps,
-                &server_av_kdfs
-            );      
-            // => AEAD
-            (aead_id, aead_pass) = match_available_cps(
-                &client_aead_cps,
-                &server_av_aeads
-            );
-            
-            println!("KEM ID: {}", kem_id);
-            println!("KDF ID: {}", kdf_id);
-            println!("AEAD ID: {}", aead_id);
-
-            
-            // Se esiste una ciphersuite completa tra C e S, segnala
-            // al client quale algoritmo usare e invia la chiave pubblica
-            if kem_pass && kdf_pass && aead_pass {
-
-                // ##### INVIO CIPHERSUITE AL CLIENT #####
-
-                // => KEM
-                let kem_id_pack = data_packets_manager::create_packet(
-                    data_packets_manager::DataType::Enc_ctx_KEM,
-                    kem_id.into_bytes()
-                );
-                let kem_id_data_pack = kem_id_pack.group();
-                let kem_id_data_pack_bytes = kem_id_data_pack.as_slice();
-                send_packet(stream, kem_id_data_pack_bytes, &mut received, String::from("Choosen KEM cps"));
-
-                // => KEM
-                let kdf_id_pack = data_packets_manager::create_packet(
-                    data_packets_manager::DataType::Enc_ctx_KDF,
-                    kdf_id.into_bytes()
-                );
-                let kdf_id_data_pack = kdf_id_pack.group();
-                let kdf_id_data_pack_bytes = kdf_id_data_pack.as_slice();
-                send_packet(stream, kdf_id_data_pack_bytes, &mut received, String::from("Choosen KDF cps"));
-
-                // => KEM
-                let aead_id_pack = data_packets_manager::create_packet(
-                    data_packets_manager::DataType::Enc_ctx_AEAD,
-                    aead_id.into_bytes()
-                );
-                let aead_id_data_pack = aead_id_pack.group();
-                let aead_id_data_pack_bytes = aead_id_data_pack.as_slice();
-                send_packet(stream, aead_id_data_pack_bytes, &mut received, String::from("Choosen AEAD cps"));
-
-                // => Puclic Key
-                let pub_key_pack = data_packets_manager::create_packet(
-                    data_packets_manager::DataType::PublicKey,
-                    pubkey.to_vec()
-                );
-                let pub_key_data_pack = pub_key_pack.group();
-                let pub_key_data_pack_bytes = pub_key_data_pack.as_slice();
-                send_packet(stream, pub_key_data_pack_bytes, &mut received, String::from("Public Key"));
-            }
-        }
-    }
-    Ok(())
-}
-
-
-fn client_exchange_mex(mut stream: &TcpStream, pubkey: &[u8], privkey: &[u8], mex: &[u8]) -> Result<(), Error> {
-    let mut ek:Vec<u8> = vec![]; 
-    let mut ct:Vec<u8> = vec![]; 
-    let mut ad:Vec<u8> = vec![];  
-    let mut tb:Vec<u8> = vec![]; 
-
-    // IL BUFFER DATA PUÒ ESSERE MOLTO PIÙ GRANDE
-    let mut data = [0 as u8; 100]; 
-
-    loop {
-
-        let bytes_read = stream.read(&mut data)?;
-
-        if bytes_read == 0 {return Ok(());}
-
-        // Richiesta della chiave pubblica => 0
-        if data[0] == 0 {
-            stream.write(pubkey)?;
-            println!("Chiave pubblica server inviata\n");
-        }
-
-        // Memorizzazione dei pacchetti arrivati
-        // Arrivo della Encapped Key => 1 
-        else if data[0] == 1 {
-            handle_data(stream, &mut ek, &data, mex)?;
-        }
-        // Arrivo del CipherText => 2 
-        else if data[0] == 2 {
-            handle_data(stream, &mut ct, &data, mex)?;
-        }
-        // Arrivo di AssociatedData => 3
-        else if data[0] == 3 {
-            handle_data(stream, &mut ad, &data, mex)?;
-        }
-        // Arrivo di Tag => 4 
-        else if data[0] == 4 {
-            handle_data(stream, &mut tb, &data, mex)?;
-        }
-
-
-        if ready(&ek, &mut ct, &mut ad, &mut tb) {
-            // Decripta il messaggio
-            let decrypted_msg = server_decrypt_msg(
-                privkey,
-                ek.as_slice(),
-                ct.as_slice(),
-                ad.as_slice(),
-                tb.as_slice()
-            );
-
-            /* Il messaggio ricevuto viene mandato indietro 
-            al client per verificare che sia corretto */
-            stream.write(&decrypted_msg)?;
-            println!("Ho riscritto al client");
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
     }
 }
@@ -411,46 +141,59 @@ fn client_exchange_mex(mut stream: &TcpStream, pubkey: &[u8], privkey: &[u8], me
 
 fn main() {
 
-    let ok_mex = [0 as u8];
-
-    let remote: SocketAddr = "0.0.0.0:8888".parse().unwrap();
-
-    //Generazione di chiave pubblica e privata del server
-    let (server_prikey, server_pubkey) = server_init();
-
-    let server_pubkey_bytes = server_pubkey.to_bytes();
-    let server_prikey_bytes = server_prikey.to_bytes();
-    
-    //let s_puk_size = server_pubkey_bytes.len();
-    //println!("dim chiave pub {}", s_puk_size);
-    
-    let listener = TcpListener::bind(remote).expect("Could not bind");
-
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                // TODO: handle client servirà per la negoziazione; lo scambio di messaggi è successivo
-                handle_client(
-                    &stream,
-                    &server_pubkey_bytes,
-                    &server_prikey_bytes, 
-                    &ok_mex
-                ).unwrap();
-
-                client_exchange_mex(
-                    &stream,
-                    &server_pubkey_bytes,
-                    &server_prikey_bytes, 
-                    &ok_mex
-                ).unwrap();
-            }
-            Err(e) => { 
-                eprintln!("failed: {}", e) 
-            }
-        }
-    }
+    let config = load_config();
+    data_packets_manager::set_packet_dump(config.packet_dump);
+
+    // Chiavi d'identità del server, una per ogni KEM abilitato, lette da disco
+    let server_keys = exit_on_error(
+        keystore::load_or_create(&config.key_file, &config.policy.kems, config.passphrase.as_deref()),
+        "archivio delle chiavi del server"
+    );
+
+    // Chiave di firma degli annunci: la chiave di verifica va distribuita ai client
+    let signing_key = exit_on_error(
+        signing::load_or_create_signing_key(&config.signing_key, config.passphrase.as_deref()),
+        "chiave di firma del server"
+    );
+    println!("Chiave di verifica del server: {}", signing::verifying_key_to_hex(&signing_key.verifying_key()));
+
+    let listener = exit_on_error(TcpListener::bind(config.listen), "Could not bind");
+
+    // Con secondary il PS accetta la registrazione di un SS per ogni sessione
+    let registration = if config.secondary {
+        Some(exit_on_error(secondary::bind_registration(&config.registration), "Could not bind"))
+    } else {
+        None
+    };
+
+    let max_connections = config.max_connections;
+    let state = Arc::new(ServerState {
+        keys: server_keys,
+        // Algoritmi disponibili del S, in ordine di preferenza
+        policy: config.policy,
+        // Archivio delle PSK condivise coi client
+        psks: config.psks,
+        authorized_clients: config.authorized_clients,
+        registration,
+        secondary: config.registration,
+        info: config.info,
+        rekey: config.rekey,
+        timeouts: config.timeouts,
+        signing_key,
+        ek_cache: Mutex::new(EncappedKeyCache::default())
+    });
+
+    // Ctrl-C ferma il server in modo ordinato
+    let shutdown = Shutdown::new();
+    let on_signal = shutdown.clone();
+    exit_on_error(ctrlc::set_handler(move || on_signal.request()), "handler di Ctrl-C");
+
+    // Un thread per connessione; un errore chiude solo la connessione che l'ha causato
+    exit_on_error(connections::serve(&listener, max_connections, &shutdown, move |stream, _peer| {
+        handle_connection(stream, &state)
+    }), "server fermato per errore");
 
     // close the socket server
     drop(listener);
 
-}
\ No newline at end of file
+}